@@ -0,0 +1,57 @@
+use std::{
+    env,
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    process,
+};
+
+/// Default control socket path.
+const RELAYD_SOCKET: &str = "/var/run/relayd.sock";
+
+/// Send a command to the control socket and print the output.
+fn request(path: &str, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    let mut output = String::new();
+    stream.read_to_string(&mut output)?;
+    Ok(output)
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let prog = args.remove(0);
+
+    let mut opts = getopts::Options::new();
+    opts.optopt(
+        "s",
+        "",
+        "Specify an alternative control socket",
+        RELAYD_SOCKET,
+    );
+
+    let matches = match opts.parse(&args) {
+        Ok(matches) if !matches.free.is_empty() => matches,
+        Ok(_) => {
+            eprintln!("{}", opts.short_usage(&prog) + " command [argument ...]");
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("{}: {}", prog, err);
+            eprintln!("{}", opts.short_usage(&prog) + " command [argument ...]");
+            process::exit(1);
+        }
+    };
+    let path = matches
+        .opt_str("s")
+        .unwrap_or_else(|| RELAYD_SOCKET.to_string());
+
+    match request(&path, &matches.free.join(" ")) {
+        Ok(output) => print!("{}", output),
+        Err(err) => {
+            eprintln!("{}: {}: {}", prog, path, err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{
+    config::{Config, Listen, Relay, TlsOption},
+    error::Error,
+    message::{Data, Type},
+    parent::{default_handler, send_to_peer},
+    Child, Privsep,
+};
+use privsep_log::{debug, info, trace, warn};
+use rustls::{
+    crypto::ring::sign::any_supported_type,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    sign::SigningKey,
+    SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Signature schemes that are offered for keys, in order of preference.
+const SCHEMES: [SignatureScheme; 9] = [
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA512,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PKCS1_SHA512,
+    SignatureScheme::RSA_PKCS1_SHA384,
+    SignatureScheme::RSA_PKCS1_SHA256,
+];
+
+/// TLS keypair of a relay listener.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Keypair {
+    /// Name of the certificate and key files.
+    pub name: String,
+    /// Listen address of the relay.
+    pub listen: SocketAddr,
+    /// Certificate chain in DER format.
+    pub certs: Vec<Vec<u8>>,
+    /// Private key in DER format, only sent to the CA process.
+    pub key: Option<Vec<u8>>,
+    /// Signature algorithm of the private key.
+    pub algorithm: u8,
+    /// Signature schemes that are supported by the private key.
+    pub schemes: Vec<u16>,
+}
+
+impl Keypair {
+    /// Returns the keypair without the private key for the relays.
+    pub fn public(&self) -> Self {
+        Self {
+            key: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// Request to sign a message with the private key of a keypair.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Sign {
+    pub id: u64,
+    /// Name of the keypair.
+    pub name: String,
+    pub scheme: u16,
+    pub message: Vec<u8>,
+}
+
+/// Signature or error of a signing request.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Signature {
+    pub id: u64,
+    pub signature: Result<Vec<u8>, String>,
+}
+
+/// Private keys by the name of the keypair.
+#[derive(Debug, Default)]
+pub struct Keys(HashMap<String, Arc<dyn SigningKey>>);
+
+impl Keys {
+    pub fn insert(&mut self, keypair: &Keypair) -> io::Result<()> {
+        let key = keypair.key.as_deref().ok_or(io::ErrorKind::InvalidInput)?;
+        let key = signing_key(key)?;
+        self.0.insert(keypair.name.clone(), key);
+        Ok(())
+    }
+
+    pub fn sign(&self, request: &Sign) -> Signature {
+        let scheme = SignatureScheme::from(request.scheme);
+        let signature = self
+            .0
+            .get(&request.name)
+            .ok_or_else(|| format!("unknown keypair {}", request.name))
+            .and_then(|key| {
+                key.choose_scheme(&[scheme])
+                    .ok_or_else(|| format!("unsupported signature scheme {:?}", scheme))
+            })
+            .and_then(|signer| signer.sign(&request.message).map_err(|err| err.to_string()));
+        Signature {
+            id: request.id,
+            signature,
+        }
+    }
+}
+
+/// Certificate Authority: holds the private keys of the relays.
+///
+/// The relays only get the certificates; the private keys never leave
+/// this process and the relays send their signing requests through the
+/// Parent.
+pub async fn main<const N: usize>(
+    child: Child<N>,
+    config: privsep::Config,
+) -> Result<(), privsep::Error> {
+    let _guard = privsep_log::async_logger(&child.to_string(), &config)
+        .await
+        .map_err(|err| privsep::Error::GeneralError(Box::new(err)))?;
+
+    let mut keys = Keys::default();
+
+    info!("Started");
+
+    loop {
+        let (message, _, data) = default_handler::<Data<'_>>(&child[Privsep::PARENT_ID]).await?;
+        match (Type::from(message.id), data) {
+            (Type::Config, _) => {
+                trace!("received config");
+                keys = Keys::default();
+            }
+            (Type::Start, _) => {
+                trace!("received start command");
+            }
+            (Type::Keypair, Data::Keypair(keypair)) => {
+                trace!("received keypair {}", keypair.name);
+                if let Err(err) = keys.insert(&keypair) {
+                    warn!("Invalid private key {}: {}", keypair.name, err);
+                }
+            }
+            (Type::Sign, Data::Sign(request)) => {
+                debug!("signing with keypair {}", request.name);
+                let data = Data::Signature(keys.sign(&request));
+                send_to_peer(&child[Privsep::PARENT_ID], Type::Signature, None, &data).await?;
+            }
+            (Type::Shutdown, _) => {
+                trace!("received shutdown command");
+                break;
+            }
+            _ => return Err(Error::InvalidMessage.into()),
+        }
+    }
+
+    info!("Terminated");
+
+    Ok(())
+}
+
+fn signing_key(key: &[u8]) -> io::Result<Arc<dyn SigningKey>> {
+    let key = PrivateKeyDer::try_from(key).map_err(io::Error::other)?;
+    any_supported_type(&key).map_err(io::Error::other)
+}
+
+/// Load the keypairs of all relay listeners that accept TLS connections.
+///
+/// The Parent loads them before the Config is sent; a missing keypair is
+/// not fatal, the listener fails the TLS handshakes.
+pub fn load(config: &Config) -> Vec<Keypair> {
+    let mut keypairs = vec![];
+    for relay in &config.relays {
+        for listen in relay.listen.iter().filter(|listen| listen.tls) {
+            let name = keypair_name(config, relay, listen);
+            match keypair(
+                &name,
+                listen,
+                Path::new(crate::TLS_CERTS),
+                Path::new(crate::TLS_KEYS),
+            ) {
+                Ok(keypair) => keypairs.push(keypair),
+                Err(err) => warn!(
+                    "relay {}: failed to load keypair {}: {}",
+                    relay.name, name, err
+                ),
+            }
+        }
+    }
+    keypairs
+}
+
+/// Returns the name of the keypair of a listener.
+///
+/// The name of the first `keypair` option of the protocol, or the listen
+/// address by default.
+fn keypair_name(config: &Config, relay: &Relay, listen: &Listen) -> String {
+    relay
+        .protocol
+        .as_ref()
+        .and_then(|protocol| config.protocol_by_id(protocol.id))
+        .and_then(|protocol| {
+            protocol.tls.iter().find_map(|option| match option {
+                TlsOption::Keypair(name) => Some(name.clone()),
+                _ => None,
+            })
+        })
+        .unwrap_or_else(|| listen.addr.ip().to_string())
+}
+
+/// Load a keypair from `<certs>/<name>:<port>.crt` and
+/// `<keys>/<name>:<port>.key`, or from the files without the port.
+pub fn keypair(name: &str, listen: &Listen, certs: &Path, keys: &Path) -> io::Result<Keypair> {
+    let path = |dir: &Path, name: &str, ext| -> PathBuf { dir.join(format!("{}.{}", name, ext)) };
+    let with_port = format!("{}:{}", name, listen.addr.port());
+    let name = if path(certs, &with_port, "crt").exists() {
+        with_port.as_str()
+    } else {
+        name
+    };
+
+    let certs = CertificateDer::pem_file_iter(path(certs, name, "crt"))
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(io::Error::other)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no certificate"));
+    }
+    let key = PrivateKeyDer::from_pem_file(path(keys, name, "key")).map_err(io::Error::other)?;
+    let signing_key = any_supported_type(&key).map_err(io::Error::other)?;
+    let schemes = SCHEMES
+        .iter()
+        .filter(|scheme| signing_key.choose_scheme(&[**scheme]).is_some())
+        .map(|scheme| u16::from(*scheme))
+        .collect();
+
+    Ok(Keypair {
+        name: name.to_string(),
+        listen: listen.addr,
+        certs: certs.iter().map(|cert| cert.to_vec()).collect(),
+        key: Some(key.secret_der().to_vec()),
+        algorithm: signing_key.algorithm().into(),
+        schemes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ca_sign() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        let listen = Listen {
+            addr: "127.0.0.1:443".parse().unwrap(),
+            tls: true,
+            interface: None,
+        };
+
+        for (name, scheme) in [
+            ("rsa", SignatureScheme::RSA_PSS_SHA256),
+            ("ecdsa", SignatureScheme::ECDSA_NISTP256_SHA256),
+        ] {
+            let keypair = keypair(name, &listen, &dir, &dir).unwrap();
+            assert_eq!(keypair.name, name);
+            assert!(keypair.schemes.contains(&u16::from(scheme)));
+            assert!(keypair.public().key.is_none());
+
+            let mut keys = Keys::default();
+            keys.insert(&keypair).unwrap();
+            let request = Sign {
+                id: 1,
+                name: name.to_string(),
+                scheme: scheme.into(),
+                message: b"message".to_vec(),
+            };
+            assert!(!keys.sign(&request).signature.unwrap().is_empty());
+
+            // The key does not support the schemes of the other algorithm.
+            let other = if name == "rsa" {
+                SignatureScheme::ECDSA_NISTP256_SHA256
+            } else {
+                SignatureScheme::RSA_PSS_SHA256
+            };
+            assert!(!keypair.schemes.contains(&u16::from(other)));
+            let unsupported = Sign {
+                scheme: other.into(),
+                ..request.clone()
+            };
+            assert!(keys.sign(&unsupported).signature.is_err());
+
+            let request = Sign {
+                name: "unknown".to_string(),
+                ..request
+            };
+            assert!(keys.sign(&request).signature.is_err());
+        }
+
+        assert!(keypair("missing", &listen, &dir, &dir).is_err());
+    }
+}
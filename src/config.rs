@@ -1,14 +1,19 @@
 mod expand;
 mod parser;
+mod printer;
+mod tables;
+mod validate;
 
 use crate::error::Error;
 use expand::config_expand;
-use nom::{error::convert_error, Finish};
+use nom::Finish;
 use parser::config_parser;
+use privsep_log::warn;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DurationMilliSeconds, DurationSeconds};
 use std::{
     collections::HashMap,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     sync::atomic::{AtomicU32, Ordering},
     time::Duration,
@@ -22,6 +27,9 @@ pub struct Config {
     /// Privsep and log configuration.
     #[serde(skip)]
     pub privsep: privsep::Config,
+    /// Name servers of the system, read by the parent before the children
+    /// lose access to the resolver configuration.
+    pub nameservers: Vec<SocketAddr>,
 
     /// The interval in seconds at which the hosts will be checked.
     #[serde_as(as = "DurationSeconds<u64>")]
@@ -31,25 +39,42 @@ pub struct Config {
     /// The global timeout in milliseconds for checks.
     #[serde_as(as = "DurationMilliSeconds<u64>")]
     pub timeout: Duration,
+    /// The timeout in seconds to drain relay sessions on shutdown.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub drain_timeout: Duration,
+    /// Restart policies of crashed child processes.
+    pub restart: Vec<Restart>,
+    /// Number of relay processes that share the listeners.
+    pub prefork: usize,
+    /// Serve the metrics over HTTP on this address.
+    pub metrics: Option<Listen>,
+    /// Logging of relay sessions and health checks.
+    pub log: Log,
 
     pub redirects: Vec<Redirect>,
+    pub routers: Vec<Router>,
     pub relays: Vec<Relay>,
     pub protocols: Vec<Protocol>,
     pub tables: Vec<Table>,
     // Currently not supported:
     //agentx: not supported
-    //log: TODO
-    //prefork: not supported
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             privsep: Default::default(),
+            nameservers: Default::default(),
             interval: crate::CHECK_INTERVAL,
             socket: PathBuf::from(crate::RELAYD_SOCKET),
             timeout: crate::CHECK_TIMEOUT,
+            drain_timeout: crate::DRAIN_TIMEOUT,
+            restart: Default::default(),
+            prefork: crate::RELAY_PREFORK,
+            metrics: Default::default(),
+            log: Default::default(),
             redirects: Default::default(),
+            routers: Default::default(),
             relays: Default::default(),
             protocols: Default::default(),
             tables: Default::default(),
@@ -57,48 +82,149 @@ impl Default for Config {
     }
 }
 
+impl PartialEq for Config {
+    /// Compare the parsed configuration, ignoring the runtime options.
+    fn eq(&self, other: &Self) -> bool {
+        self.interval == other.interval
+            && self.socket == other.socket
+            && self.timeout == other.timeout
+            && self.drain_timeout == other.drain_timeout
+            && self.restart == other.restart
+            && self.prefork == other.prefork
+            && self.metrics == other.metrics
+            && self.log == other.log
+            && self.redirects == other.redirects
+            && self.routers == other.routers
+            && self.relays == other.relays
+            && self.protocols == other.protocols
+            && self.tables == other.tables
+    }
+}
+
 impl Config {
     pub async fn load<P: AsRef<Path> + ?Sized>(
         path: &P,
         variables: Variables,
     ) -> Result<Self, Error> {
         let input = fs::read_to_string(path).await?;
-        Self::parse(input, variables)
+        Self::parse(path, input, variables)
     }
 
-    pub fn parse<S: AsRef<str>>(input: S, variables: Variables) -> Result<Self, Error> {
-        let input = input.as_ref();
-        let (_, input) = config_expand(input, variables)
-            .finish()
-            .map_err(|err| Error::ParserError(convert_error(input, err)))?;
-        let input = input.as_ref();
-        config_parser(input)
-            .finish()
-            .map_err(|err| Error::ParserError(convert_error(input, err)))
-            .map(|(_, o)| o)
+    /// Parse the configuration, `path` is the name of the source in errors.
+    pub fn parse<P: AsRef<Path> + ?Sized, S: AsRef<str>>(
+        path: &P,
+        input: S,
+        variables: Variables,
+    ) -> Result<Self, Error> {
+        let (input, map, warnings) =
+            config_expand(path.as_ref(), input.as_ref(), variables).map_err(Error::ParserError)?;
+        for warning in warnings {
+            warn!("{}: {}", warning.location, warning.message);
+        }
+        let (_, (mut config, offsets)) = config_parser(&input).finish().map_err(|err| {
+            let offset = err
+                .errors
+                .first()
+                .map(|(rest, _)| input.len() - rest.len())
+                .unwrap_or_default();
+            Error::ParserError(map.error(offset, "syntax error"))
+        })?;
+
+        let mut errors = tables::load(&mut config, &map, &offsets);
+        errors.extend(validate::validate(&config, &map, &offsets));
+        if errors.is_empty() {
+            validate::resolve(&mut config);
+            Ok(config)
+        } else {
+            Err(Error::ConfigErrors(errors))
+        }
     }
-}
 
-#[derive(Debug, Default)]
-pub struct Variable {
-    key: String,
-    value: String,
-}
+    /// Returns the table with the given name.
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|table| table.name == name)
+    }
 
-impl From<(String, String)> for Variable {
-    fn from((key, value): (String, String)) -> Self {
-        Self { key, value }
+    /// Returns the protocol with the given name.
+    pub fn protocol(&self, name: &str) -> Option<&Protocol> {
+        self.protocols.iter().find(|protocol| protocol.name == name)
     }
-}
 
-impl ToString for Variable {
-    fn to_string(&self) -> String {
-        format!("{}=\"{}\"", self.key, self.value)
+    /// Returns the table with the given Id.
+    pub fn table_by_id(&self, id: Id) -> Option<&Table> {
+        self.tables.iter().find(|table| table.id == id)
+    }
+
+    /// Returns the protocol with the given Id.
+    pub fn protocol_by_id(&self, id: Id) -> Option<&Protocol> {
+        self.protocols.iter().find(|protocol| protocol.id == id)
+    }
+
+    /// Keep the ids of the hosts that are still in the same tables.
+    ///
+    /// This preserves the host states of the children on reload.
+    pub fn keep_host_ids(&mut self, old: &Config) {
+        for table in &mut self.tables {
+            let mut hosts = match old.table(&table.name) {
+                Some(old) => old.hosts.iter().collect::<Vec<_>>(),
+                None => continue,
+            };
+            for host in &mut table.hosts {
+                if let Some(i) = hosts.iter().position(|old| old.name == host.name) {
+                    host.id = hosts.swap_remove(i).id;
+                }
+            }
+        }
     }
 }
 
 pub type Variables = HashMap<String, String>;
 
+/// Restart policy of a child process.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Restart {
+    /// Privsep process Id.
+    pub process: usize,
+    /// Maximum number of restarts within the restart window.
+    pub limit: u32,
+}
+
+/// Logging options.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Log {
+    /// Log relay sessions.
+    pub connection: Option<LogConnection>,
+    /// Log host state changes.
+    pub state_changes: bool,
+    /// Log the result of every host check.
+    pub host_checks: bool,
+    /// Format of the relay session log.
+    pub format: LogFormat,
+    /// Write the relay session log to this file instead of the daemon log.
+    pub file: Option<PathBuf>,
+    /// Keep the history of host state changes in this file.
+    pub history: Option<PathBuf>,
+}
+
+/// Relay sessions that are logged.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum LogConnection {
+    All,
+    Errors,
+}
+
+/// Format of the relay session log.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub enum LogFormat {
+    /// Common log format.
+    #[default]
+    Common,
+    /// Combined log format with referer and user agent.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
 /// General relayd object Id.
 pub type Id = u32;
 
@@ -108,9 +234,15 @@ pub static TABLE_ID: AtomicU32 = AtomicU32::new(1);
 /// Counter of hosts.
 pub static HOST_ID: AtomicU32 = AtomicU32::new(1);
 
+/// Counter of dynamic hosts, starting above the Ids of the configured hosts.
+pub static DYNAMIC_HOST_ID: AtomicU32 = AtomicU32::new(1 << 31);
+
 /// Counter of redirects.
 pub static REDIRECT_ID: AtomicU32 = AtomicU32::new(1);
 
+/// Counter of routers.
+pub static ROUTER_ID: AtomicU32 = AtomicU32::new(1);
+
 /// Counter of relays.
 pub static RELAY_ID: AtomicU32 = AtomicU32::new(1);
 
@@ -118,7 +250,7 @@ pub static RELAY_ID: AtomicU32 = AtomicU32::new(1);
 pub static PROTOCOL_ID: AtomicU32 = AtomicU32::new(1);
 
 /// Table of hosts.
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct Table {
     /// Id.
     pub id: Id,
@@ -128,6 +260,8 @@ pub struct Table {
     pub hosts: Vec<Host>,
     /// Whether to disable the table.
     pub disabled: bool,
+    /// Load the hosts from an external file or directory.
+    pub source: Option<TableSource>,
 }
 
 impl Table {
@@ -139,8 +273,19 @@ impl Table {
     }
 }
 
+/// External source of the hosts of a table, re-read on reload.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum TableSource {
+    /// File with one host per line.
+    File(PathBuf),
+    /// Directory of host files.
+    Directory(PathBuf),
+    /// DNS SRV records of a service.
+    Srv(String),
+}
+
 /// Target host pool and definitions.
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct Host {
     /// Id.
     pub id: Id,
@@ -165,12 +310,56 @@ impl Host {
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+/// Address of a host name that was resolved at runtime.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct DynamicHost {
+    /// Id.
+    pub id: Id,
+    /// Id of the configured host.
+    pub parent: Id,
+    /// Resolved address.
+    pub addr: IpAddr,
+    /// Port of an SRV record.
+    pub port: Option<u16>,
+    /// Priority of an SRV record, lower values are preferred.
+    pub priority: Option<u8>,
+    /// Weight of an SRV record among records with the same priority.
+    pub weight: u16,
+}
+
+impl DynamicHost {
+    pub fn new(parent: Id, addr: IpAddr) -> Self {
+        Self {
+            id: DYNAMIC_HOST_ID.fetch_add(1, Ordering::SeqCst),
+            parent,
+            addr,
+            port: None,
+            priority: None,
+            weight: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct Redirect {
     /// Id.
     pub id: Id,
     /// Symbolic name of the redirect.
     pub name: String,
+    /// Local addresses to redirect connections from.
+    pub listen: Vec<Listen>,
+    /// Forward connections to the specified tables.
+    pub forward: Vec<Forward>,
+    /// Tag the redirected packets for the packet filter.
+    pub tag: Option<String>,
+    /// Tag the packets with a match rule instead of passing them.
+    pub match_tag: bool,
+    /// Keep connections from a source address on the same host.
+    pub sticky: bool,
+    /// Inactivity timeout of established sessions.
+    pub session_timeout: Option<Duration>,
+    /// Keep the states of connections to hosts that went down.
+    pub keep_states: bool,
 }
 
 impl Redirect {
@@ -182,12 +371,52 @@ impl Redirect {
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+/// Network prefix of a route.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct Prefix {
+    pub addr: IpAddr,
+    /// Length of the netmask in bits.
+    pub len: u8,
+}
+
+/// Routes to gateways, depending on their health.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Router {
+    /// Id.
+    pub id: Id,
+    /// Symbolic name of the router.
+    pub name: String,
+    /// Networks that are routed to the gateways.
+    pub routes: Vec<Prefix>,
+    /// Tables with the gateways.
+    pub forward: Vec<Forward>,
+    /// Routing table of the routes.
+    pub rtable: Option<u32>,
+    /// Label of the routes.
+    pub rtlabel: Option<String>,
+}
+
+impl Router {
+    fn new() -> Self {
+        Self {
+            id: ROUTER_ID.fetch_add(1, Ordering::SeqCst),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct Relay {
     /// Id.
     pub id: Id,
     /// Symbolic name of the relay.
     pub name: String,
+    /// Local addresses to accept connections on.
+    pub listen: Vec<Listen>,
+    /// Optional protocol.
+    pub protocol: Option<Reference>,
+    /// Forward connections to the specified targets.
+    pub forward: Vec<Forward>,
 }
 
 impl Relay {
@@ -197,22 +426,102 @@ impl Relay {
             ..Default::default()
         }
     }
+
+    /// Whether the relay forwards diverted connections to their original
+    /// destination.
+    pub fn transparent(&self) -> bool {
+        self.forward
+            .iter()
+            .any(|forward| forward.target == Target::Destination)
+    }
+}
+
+/// Relay listener.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Listen {
+    /// Local address and port.
+    pub addr: SocketAddr,
+    /// Whether to accept TLS connections.
+    pub tls: bool,
+    /// Optional interface of redirects.
+    pub interface: Option<String>,
+}
+
+/// Forwarding target of a relay or redirect.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Target {
+    /// Forward to the hosts of a table.
+    Table(Reference),
+    /// Forward to a single host.
+    Host(String),
+    /// Forward to the original destination of diverted connections.
+    Destination,
+}
+
+/// Named table or protocol, the validation resolves the name to the Id.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Reference {
+    pub name: String,
+    pub id: Id,
+}
+
+impl Reference {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            id: 0,
+        }
+    }
+}
+
+/// Scheduling algorithm to select hosts from a table.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub enum Mode {
+    Hash,
+    Loadbalance,
+    LeastStates,
+    Random,
+    #[default]
+    RoundRobin,
+    SourceHash,
+}
+
+/// Health check method of a table.
+///
+/// The HTTP checks compare the status code of the response; the `digest`
+/// checks of relayd are not supported.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Check {
+    Icmp,
+    Tcp,
+    Http { path: String, code: u16 },
+    Https { path: String, code: u16 },
+}
+
+/// Forwarding options.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Forward {
+    /// The target to forward to.
+    pub target: Target,
+    /// Optional port of the target.
+    pub port: Option<u16>,
+    /// Scheduling algorithm.
+    pub mode: Mode,
+    /// Optional health check.
+    pub check: Option<Check>,
+    /// Route connections to the hosts without translating the destination.
+    pub route: bool,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub enum ProtocolType {
+    #[default]
     Tcp,
     Http,
     Dns,
 }
 
-impl Default for ProtocolType {
-    fn default() -> Self {
-        Self::Tcp
-    }
-}
-
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct Protocol {
     /// Id.
     pub id: Id,
@@ -220,6 +529,14 @@ pub struct Protocol {
     pub name: String,
     /// Protocol or application type.
     pub typ: ProtocolType,
+    /// Return error pages to the client.
+    pub return_error: bool,
+    /// TCP socket options.
+    pub tcp: Vec<TcpOption>,
+    /// TLS options.
+    pub tls: Vec<TlsOption>,
+    /// Filter rules, evaluated in order.
+    pub rules: Vec<Rule>,
 }
 
 impl Protocol {
@@ -231,26 +548,532 @@ impl Protocol {
     }
 }
 
+/// TCP socket option of a protocol.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum TcpOption {
+    Backlog(u32),
+    IpMinTtl(u8),
+    IpTtl(u8),
+    NoDelay(bool),
+    Sack(bool),
+    SocketBuffer(u32),
+    Splice(bool),
+}
+
+/// TLS option of a protocol.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum TlsOption {
+    Ciphers(String),
+    Ecdhe(String),
+    Keypair(String),
+    SessionTickets(bool),
+    /// Enable or disable a TLS version, e.g. `tlsv1.2`.
+    Version(String, bool),
+}
+
+/// Action of a filter rule.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Action {
+    Block,
+    Match,
+    Pass,
+}
+
+/// Direction of a filter rule.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+/// Protocol filter rule.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Rule {
+    /// Action when the rule matches.
+    pub action: Action,
+    /// Optional direction, both if unset.
+    pub direction: Option<Direction>,
+    /// Stop evaluating further rules.
+    pub quick: bool,
+    /// Optional label for error pages.
+    pub label: Option<String>,
+    /// Key/value filters.
+    pub filters: Vec<Filter>,
+}
+
+/// Part of the request or response a filter applies to.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum FilterType {
+    Cookie,
+    Header,
+    Method,
+    Path,
+    Query,
+    Url,
+}
+
+/// Modification of a matched key.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Operation {
+    Append,
+    Hash,
+    Log,
+    Remove,
+    Set,
+}
+
+/// Key/value filter of a rule.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Filter {
+    pub typ: FilterType,
+    pub operation: Option<Operation>,
+    pub key: String,
+    pub value: Option<String>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tests::logger;
 
     #[test]
     fn test_config_example() {
-        let _guard = privsep_log::sync_logger(
-            "config",
-            privsep_log::Config {
-                foreground: true,
-                filter: Some("trace".to_string()),
-            },
-        )
-        .unwrap();
+        logger();
         let config = include_bytes!("../examples/relayd.conf");
 
         Config::parse(
-            &String::from_utf8(config.to_vec()).unwrap(),
+            "examples/relayd.conf",
+            String::from_utf8(config.to_vec()).unwrap(),
+            Default::default(),
+        )
+        .unwrap();
+    }
+
+    /// Reset the Ids that are assigned from the global counters.
+    fn strip_ids(mut config: Config) -> Config {
+        for table in &mut config.tables {
+            table.id = 0;
+            for host in &mut table.hosts {
+                host.id = 0;
+            }
+        }
+        for redirect in &mut config.redirects {
+            redirect.id = 0;
+        }
+        for router in &mut config.routers {
+            router.id = 0;
+        }
+        for relay in &mut config.relays {
+            relay.id = 0;
+            if let Some(protocol) = &mut relay.protocol {
+                protocol.id = 0;
+            }
+        }
+        let forwards = config
+            .redirects
+            .iter_mut()
+            .flat_map(|redirect| redirect.forward.iter_mut())
+            .chain(config.routers.iter_mut().flat_map(|r| r.forward.iter_mut()))
+            .chain(config.relays.iter_mut().flat_map(|r| r.forward.iter_mut()));
+        for forward in forwards {
+            if let Target::Table(table) = &mut forward.target {
+                table.id = 0;
+            }
+        }
+        for protocol in &mut config.protocols {
+            protocol.id = 0;
+        }
+        config
+    }
+
+    fn assert_roundtrip(config: &Config) {
+        let printed = config.to_string();
+        let parsed = Config::parse("relayd.conf", &printed, Default::default()).unwrap();
+        assert_eq!(parsed.to_string(), printed);
+        assert_eq!(strip_ids(parsed), strip_ids(config.clone()));
+    }
+
+    #[test]
+    fn test_config_roundtrip_example() {
+        logger();
+        let config = include_str!("../examples/relayd.conf");
+        let config = Config::parse("examples/relayd.conf", config, Default::default()).unwrap();
+
+        assert_roundtrip(&config);
+    }
+
+    #[test]
+    fn test_config_roundtrip() {
+        logger();
+        let config = Config::parse(
+            "relayd.conf",
+            r#"
+interval 5
+timeout 300
+drain timeout 10
+socket "/tmp/relayd.sock"
+restart relay
+restart metrics limit 2
+restart ca limit 3
+prefork 4
+metrics listen on 127.0.0.1 port 9100
+log connection errors
+log state changes
+log format json
+log file "/var/log/relayd.log"
+log history "/var/db/relayd.history"
+
+table <web> disable { 10.0.0.1 retry 2, 10.0.0.2 ip ttl 2 priority 8 }
+table <srv> srv "_http._tcp.example.com."
+table <gateways> { 192.0.2.1 priority 1, 192.0.2.2 priority 2 }
+
+redirect "www" {
+	listen on 10.1.0.1 port 80 interface em0
+	listen on fd00::1 port 8080 interface em1
+	match pftag "RELAYD"
+	sticky-address
+	no flush states
+	forward to <web> port 8080 mode least-states check tcp
+	route to <web> mode source-hash check https "/health" code 204
+}
+
+redirect "smtp" {
+	listen on 10.1.0.2 port 25
+	session timeout 600
+	forward to <web> check tcp
+}
+
+router "uplinks" {
+	route 0.0.0.0/0
+	route 2001:db8::/32
+	forward to <gateways> check icmp
+	rtable 1
+	rtlabel "uplink"
+}
+
+http protocol "http" {
+	return error
+	tcp { nodelay, no sack, socket buffer 65536, ip ttl 64, ip minttl 2, no splice }
+	tls { ciphers "HIGH:!aNULL", ecdhe "X25519", keypair "www", no session tickets }
+	tls no tlsv1.0
+	match request header append "X-Forwarded-For" value "$REMOTE_ADDR"
+	match response header remove "Server"
+	match request header log "Host"
+	pass quick cookie hash "session"
+	block request label "denied" path "/admin*" query "debug" value "1"
+	match method "GET"
+}
+
+dns protocol "dns" {
+}
+
+relay "www" {
+	listen on 127.0.0.1 port 8443 tls
+	protocol "http"
+	forward to <web> port http mode hash check http "/" code 200
+	forward to <srv>
+	forward to 10.0.0.3 port 80
+}
+"#,
             Default::default(),
         )
         .unwrap();
+
+        assert_eq!(config.tables[0].hosts[1].priority, Some(8));
+        assert_eq!(
+            config.relays[0]
+                .protocol
+                .as_ref()
+                .map(|protocol| protocol.id),
+            Some(config.protocols[0].id)
+        );
+        assert_eq!(
+            config.redirects[0].forward[1].target,
+            Target::Table(Reference {
+                name: "web".to_string(),
+                id: config.tables[0].id
+            })
+        );
+        assert_eq!(config.redirects[0].listen.len(), 2);
+        assert_eq!(config.routers[0].routes[1].to_string(), "2001:db8::/32");
+        assert_eq!(config.routers[0].rtable, Some(1));
+        assert_eq!(config.redirects[0].tag.as_deref(), Some("RELAYD"));
+        assert!(config.redirects[0].match_tag && config.redirects[0].sticky);
+        assert!(config.redirects[0].keep_states);
+        assert!(!config.redirects[0].forward[0].route && config.redirects[0].forward[1].route);
+        assert_eq!(
+            config.redirects[1].session_timeout,
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(config.metrics.as_ref().unwrap().addr.port(), 9100);
+        assert_eq!(config.log.connection, Some(LogConnection::Errors));
+        assert!(config.log.state_changes && !config.log.host_checks);
+        assert_eq!(config.protocols[0].tls.len(), 5);
+        assert_eq!(config.protocols[0].rules.len(), 6);
+        assert_roundtrip(&config);
+    }
+
+    #[test]
+    fn test_config_validate() {
+        logger();
+        let errors = match Config::parse(
+            "relayd.conf",
+            r#"table <web> { 10.0.0.1 }
+table <web> { 10.0.0.2 }
+table <unused> { 10.0.0.3 }
+tcp protocol "tcp" {
+	return error
+}
+relay "www" {
+	listen on 0.0.0.0 port 80
+	protocol "http"
+	forward to <webhosts> port 80
+}
+relay "www" {
+	listen on 127.0.0.1 port 80 interface lo0
+	forward to <web> port 80
+}
+redirect "rdr" {
+	listen on 10.0.0.1 port 443 tls
+	forward to destination
+}
+prefork 0
+redirect "dsr" {
+	listen on 10.0.0.2 port 8080
+	route to <web> port 80
+	session timeout 60
+}
+"#,
+            Default::default(),
+        ) {
+            Err(Error::ConfigErrors(errors)) => errors,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        let errors = errors
+            .iter()
+            .map(|err| format!("{}:{}: {}", err.location, err.location.column, err.message))
+            .collect::<Vec<_>>();
+
+        let mut expected = vec![
+            "relayd.conf:2:1: duplicate table <web>, first defined on line 1",
+            "relayd.conf:4:1: return error requires an http protocol",
+            "relayd.conf:9:2: undefined protocol http",
+            "relayd.conf:10:2: undefined table <webhosts>",
+            "relayd.conf:12:1: duplicate relay www, first defined on line 7",
+            "relayd.conf:13:2: interface is only supported by redirects",
+            "relayd.conf:13:2: listen address 127.0.0.1:80 conflicts with line 8",
+            "relayd.conf:17:2: redirects do not support tls",
+            "relayd.conf:18:2: redirects can only forward to tables",
+            "relayd.conf:20:1: prefork must be between 1 and 32",
+        ];
+        if cfg!(target_os = "linux") {
+            expected.extend([
+                "relayd.conf:22:2: route to requires a listen interface",
+                "relayd.conf:23:2: session timeout is not supported with route to",
+            ]);
+        }
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn test_config_syntax_error() {
+        logger();
+        let error = match Config::parse(
+            "relayd.conf",
+            "ext_addr=\"10.0.0.1\"\ntable <web> { 10.0.0.2 }\n\nrelay www {\n\tlisten on $ext_addr \\\n\t    port 80\n\tforward to <web> port 80 mode bogus\n}\n",
+            Default::default(),
+        ) {
+            Err(Error::ParserError(error)) => error,
+            result => panic!("unexpected result: {:?}", result),
+        };
+
+        assert_eq!(error.location.file, PathBuf::from("relayd.conf"));
+        assert_eq!(error.location.line, 7);
+        assert_eq!(error.location.column, 2);
+        assert_eq!(error.snippet, "\tforward to <web> port 80 mode bogus");
+        assert_eq!(
+            error.to_string(),
+            "relayd.conf:7: syntax error\n\tforward to <web> port 80 mode bogus\n\t^"
+        );
+    }
+
+    #[test]
+    fn test_config_macros() {
+        logger();
+        let input = "hosts=\"{ 10.0.0.1 10.0.0.2 }\"\nall=\"{ $hosts 10.0.0.3 }\"\nport=\"80\"\nport=\"8080\"\nunused=\"1\"\ntable <a> $hosts\ntable <b> { $all, 10.0.0.4 }\nforward to \"$port $REMOTE_ADDR\"\nlabel \"#$port\" # see $undefined\n";
+        let mut variables = Variables::new();
+        variables.insert("all".to_string(), "{ 10.0.0.5 }".to_string());
+
+        let (output, _, warnings) =
+            config_expand("relayd.conf".as_ref(), input, variables).unwrap();
+        assert_eq!(
+            output,
+            "table <a> { 10.0.0.1 10.0.0.2 }\ntable <b> { 10.0.0.5, 10.0.0.4 }\nforward to \"8080 $REMOTE_ADDR\"\nlabel \"#8080\" # see $undefined\n\n"
+        );
+        let warnings = warnings
+            .iter()
+            .map(|w| format!("{}: {}", w.location, w.message))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            [
+                "relayd.conf:4: macro port redefined, previously defined at relayd.conf:3",
+                "relayd.conf:5: macro unused not used",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_undefined_macro() {
+        logger();
+        let error = match Config::parse(
+            "relayd.conf",
+            "table <web> { 10.0.0.1 }\nrelay www {\n\tlisten on $ext_addr port 80\n\tforward to <web> port 80\n}\n",
+            Default::default(),
+        ) {
+            Err(Error::ParserError(error)) => error,
+            result => panic!("unexpected result: {:?}", result),
+        };
+
+        assert_eq!(error.location.line, 3);
+        assert_eq!(error.location.column, 12);
+        assert_eq!(error.message, "undefined macro $ext_addr");
+    }
+
+    /// Create a temporary directory with configuration files.
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("relayd-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, content) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_config_include() {
+        logger();
+        let dir = config_dir(
+            "include",
+            &[
+                (
+                    "relayd.conf",
+                    "confdir=\"relayd.d\"\ninclude \"macros.conf\"\ninclude \"$confdir/*.conf\"\n",
+                ),
+                ("macros.conf", "host=\"10.0.0.1\"\n"),
+                ("relayd.d/10-table.conf", "table <web> { $host }\n"),
+                (
+                    "relayd.d/20-relay.conf",
+                    "relay www {\n\tlisten on 127.0.0.1 port 8080\n\tforward to <web> port 80\n}\n",
+                ),
+                ("relayd.d/ignored.txt", "syntax error\n"),
+            ],
+        );
+
+        let config = Config::load(&dir.join("relayd.conf"), Default::default())
+            .await
+            .unwrap();
+        assert_eq!(config.tables[0].hosts[0].name, "10.0.0.1");
+        assert_eq!(config.relays[0].name, "www");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_config_table_source() {
+        logger();
+        let dir = config_dir(
+            "tables",
+            &[
+                (
+                    "relayd.conf",
+                    "table <web> file \"web.hosts\"\ntable <api> directory \"api.d\"\nrelay www {\n\tlisten on 127.0.0.1 port 8080\n\tforward to <web> port 80\n\tforward to <api> port 80\n}\n",
+                ),
+                ("web.hosts", "# web servers\n10.0.0.1\n\n  10.0.0.2 retry 2 # backup\n"),
+                ("api.d/b", "10.0.1.2\n"),
+                ("api.d/a", "10.0.1.1\n"),
+                ("api.d/.a.tmp", "bogus host\n"),
+            ],
+        );
+
+        let mut config = Config::load(&dir.join("relayd.conf"), Default::default())
+            .await
+            .unwrap();
+        let hosts = |config: &Config, table| {
+            config
+                .table(table)
+                .unwrap()
+                .hosts
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(hosts(&config, "web"), ["10.0.0.1", "10.0.0.2 retry 2"]);
+        assert_eq!(hosts(&config, "api"), ["10.0.1.1", "10.0.1.2"]);
+        assert_eq!(
+            config.tables[0].source,
+            Some(TableSource::File(dir.join("web.hosts")))
+        );
+        assert_roundtrip(&config);
+
+        // Reloading keeps the ids of the remaining hosts.
+        std::fs::write(dir.join("api.d/b"), "10.0.1.3\n").unwrap();
+        let mut reloaded = Config::load(&dir.join("relayd.conf"), Default::default())
+            .await
+            .unwrap();
+        reloaded.keep_host_ids(&config);
+        let old = &config.tables[1].hosts;
+        let new = &reloaded.tables[1].hosts;
+        assert_eq!(new[0].id, old[0].id);
+        assert_ne!(new[1].id, old[1].id);
+        assert_eq!(new[1].name, "10.0.1.3");
+
+        std::fs::write(dir.join("web.hosts"), "10.0.0.1\n10.0.0.2 bogus\n").unwrap();
+        config = match Config::load(&dir.join("relayd.conf"), Default::default()).await {
+            Err(Error::ConfigErrors(errors)) => {
+                assert_eq!(errors[0].location.file, dir.join("web.hosts"));
+                assert_eq!(errors[0].location.line, 2);
+                assert_eq!(errors[0].location.column, 10);
+                assert_eq!(errors[0].message, "invalid host");
+                config
+            }
+            result => panic!("unexpected result: {:?}", result),
+        };
+        assert_eq!(config.tables.len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_config_include_cycle() {
+        logger();
+        let dir = config_dir(
+            "cycle",
+            &[
+                ("relayd.conf", "include \"a.conf\"\n"),
+                ("a.conf", "interval 10\ninclude \"b.conf\"\n"),
+                ("b.conf", "include \"a.conf\"\n"),
+            ],
+        );
+
+        let error = match Config::load(&dir.join("relayd.conf"), Default::default()).await {
+            Err(Error::ParserError(error)) => error,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        assert_eq!(error.location.file, dir.join("b.conf"));
+        assert_eq!(error.location.line, 1);
+        let canonical = dir.canonicalize().unwrap();
+        assert_eq!(
+            error.message,
+            format!(
+                "include cycle: {a} -> {b} -> {a}",
+                a = canonical.join("a.conf").display(),
+                b = canonical.join("b.conf").display()
+            )
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
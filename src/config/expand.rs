@@ -1,165 +1,500 @@
-use crate::config::{
-    parser::{comment, line, quoted, string, CResult},
-    Variable, Variables,
+use crate::{
+    config::Variables,
+    error::{ConfigError, Location},
 };
-use nom::{
-    branch::alt,
-    bytes::complete::{is_not, tag},
-    character::complete::{char, multispace1},
-    combinator::{map, opt},
-    error::{VerboseError, VerboseErrorKind},
-    multi::many0,
-    sequence::{pair, separated_pair},
-    Err, IResult,
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
 };
 
+/// Characters that turn an include path into a glob pattern.
+const GLOB_CHARS: &[char] = &['*', '?', '['];
+
+/// Macros that are expanded by the relays at runtime.
+const RUNTIME_MACROS: &[&str] = &[
+    "HOST",
+    "REMOTE_ADDR",
+    "REMOTE_PORT",
+    "SERVER_ADDR",
+    "SERVER_NAME",
+    "SERVER_PORT",
+    "TIMEOUT",
+];
+
+/// Source file of the configuration.
 #[derive(Debug)]
-enum Token {
-    Variable(Variable),
-    UseVariable(String),
-    Escaped(String),
-    Next(String),
-    Nested(Vec<Self>),
-    None,
-}
-
-impl Token {
-    pub fn resolve(&self, output: &mut String, variables: &mut Variables) {
-        match self {
-            Token::None => {}
-            Token::Variable(variable) => {
-                if !variables.contains_key(&variable.key) {
-                    variables.insert(variable.key.clone(), variable.value.clone());
-                }
-            }
-            Token::UseVariable(name) if variables.contains_key(name) => {
-                if let Some(value) = variables.get(name) {
-                    output.push_str(value);
+struct Source {
+    path: PathBuf,
+    content: String,
+}
+
+/// Start of a segment of the expanded output.
+#[derive(Debug)]
+struct Segment {
+    /// Offset in the expanded output.
+    offset: usize,
+    /// Index of the source file.
+    source: usize,
+    line: usize,
+    column: usize,
+    /// Whether the segment was copied verbatim or expanded from a macro.
+    verbatim: bool,
+}
+
+/// Maps offsets of the expanded configuration back to the source files.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    sources: Vec<Source>,
+    segments: Vec<Segment>,
+}
+
+impl SourceMap {
+    fn segment(&self, offset: usize) -> Option<&Segment> {
+        match self.segments.partition_point(|s| s.offset <= offset) {
+            0 => None,
+            i => Some(&self.segments[i - 1]),
+        }
+    }
+
+    /// Returns the source location of an offset in the expanded output.
+    pub fn locate(&self, offset: usize) -> Location {
+        match self.segment(offset) {
+            Some(segment) => Location {
+                file: self.sources[segment.source].path.clone(),
+                line: segment.line,
+                column: if segment.verbatim {
+                    segment.column + offset - segment.offset
+                } else {
+                    segment.column
+                },
+            },
+            None => Default::default(),
+        }
+    }
+
+    /// Returns an error at an offset in the expanded output.
+    pub fn error<S: Into<String>>(&self, offset: usize, message: S) -> ConfigError {
+        ConfigError {
+            location: self.locate(offset),
+            snippet: self
+                .segment(offset)
+                .map(|segment| self.snippet(segment.source, segment.line))
+                .unwrap_or_default(),
+            message: message.into(),
+        }
+    }
+
+    /// Returns a line of a source file.
+    fn snippet(&self, source: usize, line: usize) -> String {
+        self.sources[source]
+            .content
+            .lines()
+            .nth(line - 1)
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+/// Macro definition.
+#[derive(Debug)]
+struct Macro {
+    value: String,
+    /// Source, line and column of the definition, `None` for the command line.
+    position: Option<(usize, usize, usize)>,
+    used: bool,
+}
+
+/// Part of a line with macros.
+enum Piece<'a> {
+    Text(&'a str),
+    Macro(&'a str),
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    warnings: Vec<ConfigError>,
+    output: String,
+    map: SourceMap,
+    /// The files that are currently being expanded, to detect cycles.
+    stack: Vec<PathBuf>,
+}
+
+impl Expander {
+    /// Start a new segment at the current end of the output.
+    fn segment(&mut self, source: usize, line: usize, column: usize, verbatim: bool) {
+        let segment = Segment {
+            offset: self.output.len(),
+            source,
+            line,
+            column,
+            verbatim,
+        };
+        match self.map.segments.last_mut() {
+            Some(last) if last.offset == segment.offset => *last = segment,
+            _ => self.map.segments.push(segment),
+        }
+    }
+
+    /// Returns an error at a position of a source file.
+    fn error(&self, source: usize, line: usize, column: usize, message: String) -> ConfigError {
+        ConfigError {
+            location: Location {
+                file: self.map.sources[source].path.clone(),
+                line,
+                column,
+            },
+            snippet: self.map.snippet(source, line),
+            message,
+        }
+    }
+
+    /// Expand a source file and all of its includes.
+    fn source(&mut self, path: PathBuf, content: String) -> Result<(), ConfigError> {
+        self.stack
+            .push(fs::canonicalize(&path).unwrap_or_else(|_| path.clone()));
+        let result = self.expand(path, content);
+        self.stack.pop();
+        result
+    }
+
+    fn expand(&mut self, path: PathBuf, content: String) -> Result<(), ConfigError> {
+        let source = self.map.sources.len();
+        self.map.sources.push(Source {
+            path,
+            content: content.clone(),
+        });
+
+        let mut continued = false;
+        let mut comment = false;
+
+        for (i, line) in content.split('\n').enumerate() {
+            let number = i + 1;
+            let (text, next) = match line.strip_suffix('\\') {
+                Some(text) => (text, true),
+                None => (line, false),
+            };
+
+            if !continued {
+                let trimmed = text.trim_start();
+                let column = text.len() - trimmed.len() + 1;
+                comment = trimmed.starts_with('#');
+
+                if !comment {
+                    if let Some((key, value)) = definition(trimmed) {
+                        let value = self
+                            .substitute(value)
+                            .map_err(|message| self.error(source, number, column, message))?;
+                        self.define(key, value, (source, number, column));
+                        continue;
+                    }
+                    if let Some(file) = include(trimmed) {
+                        self.include(file, source, number, column)?;
+                        continue;
+                    }
                 }
             }
-            Token::Nested(values) => {
-                for token in values {
-                    token.resolve(output, variables);
+            continued = next;
+
+            if !comment {
+                self.line(source, number, text)?;
+                if !next {
+                    self.output.push('\n');
                 }
             }
-            _ => output.push_str(&self.to_string()),
         }
+
+        Ok(())
     }
-}
 
-impl Default for Token {
-    fn default() -> Self {
-        Self::None
+    /// Expand all files matching an include path.
+    fn include(
+        &mut self,
+        file: &str,
+        source: usize,
+        line: usize,
+        column: usize,
+    ) -> Result<(), ConfigError> {
+        let paths = self
+            .include_paths(file)
+            .map_err(|message| self.error(source, line, column, message))?;
+
+        for path in paths {
+            let content = self
+                .include_file(&path)
+                .map_err(|message| self.error(source, line, column, message))?;
+            self.source(path, content)?;
+        }
+
+        Ok(())
     }
-}
 
-impl ToString for Token {
-    fn to_string(&self) -> String {
-        let mut result = String::new();
-        match self {
-            Token::Variable(variable) => {
-                result = variable.to_string();
-            }
-            Token::UseVariable(name) => {
-                result.push('$');
-                result.push_str(name);
+    /// Returns the sorted list of files matching an include path.
+    fn include_paths(&mut self, file: &str) -> Result<Vec<PathBuf>, String> {
+        let file = self.substitute(file)?;
+
+        // Relative paths are resolved from the directory of the including file.
+        let mut path = PathBuf::from(&file);
+        if path.is_relative() {
+            if let Some(dir) = self.stack.last().and_then(|path| path.parent()) {
+                path = dir.join(path);
             }
-            Token::Escaped(value) => {
-                result.push_str(value);
+        }
+
+        if !file.contains(GLOB_CHARS) {
+            return Ok(vec![path]);
+        }
+
+        let mut paths = glob::glob(&path.to_string_lossy())
+            .map_err(|err| format!("invalid include pattern {}: {}", file, err))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("failed to include {}: {}", file, err))?;
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    /// Read an included file unless it would include itself.
+    fn include_file(&self, path: &Path) -> Result<String, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("failed to include {}: {}", path.display(), err))?;
+
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(pos) = self.stack.iter().position(|p| p == &canonical) {
+            let cycle = self.stack[pos..]
+                .iter()
+                .chain([&canonical])
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>();
+            return Err(format!("include cycle: {}", cycle.join(" -> ")));
+        }
+
+        Ok(content)
+    }
+
+    /// Define a macro; macros from the command line cannot be redefined.
+    fn define(&mut self, key: &str, value: String, position: (usize, usize, usize)) {
+        let (source, line, column) = position;
+
+        match self.macros.get(key).map(|m| m.position) {
+            Some(None) => return,
+            Some(Some((previous, previous_line, _))) => {
+                let message = format!(
+                    "macro {} redefined, previously defined at {}:{}",
+                    key,
+                    self.map.sources[previous].path.display(),
+                    previous_line
+                );
+                self.warnings
+                    .push(self.error(source, line, column, message));
             }
-            Token::Nested(values) => {
-                result = values
-                    .iter()
-                    .map(ToString::to_string)
-                    .collect::<Vec<_>>()
-                    .join("\n");
+            None => {}
+        }
+
+        self.macros.insert(
+            key.to_string(),
+            Macro {
+                value,
+                position: Some(position),
+                used: false,
+            },
+        );
+    }
+
+    /// Returns the value of a macro or `None` for runtime macros.
+    fn lookup(&mut self, name: &str) -> Result<Option<String>, String> {
+        if let Some(m) = self.macros.get_mut(name) {
+            m.used = true;
+            Ok(Some(m.value.clone()))
+        } else if RUNTIME_MACROS.contains(&name) {
+            Ok(None)
+        } else {
+            Err(format!("undefined macro ${}", name))
+        }
+    }
+
+    /// Expand the macros of a string.
+    fn substitute(&mut self, text: &str) -> Result<String, String> {
+        let mut output = String::new();
+
+        for (_, piece) in pieces(text) {
+            match piece {
+                Piece::Text(text) => output.push_str(text),
+                Piece::Macro(name) => match self.lookup(name)? {
+                    Some(value) => output.push_str(list(&value, &output)),
+                    None => {
+                        output.push('$');
+                        output.push_str(name);
+                    }
+                },
             }
-            Token::Next(value) => {
-                result.push_str(value);
+        }
+
+        Ok(output)
+    }
+
+    /// Copy a line to the output and expand the macros.
+    fn line(&mut self, source: usize, line: usize, text: &str) -> Result<(), ConfigError> {
+        for (pos, piece) in pieces(text) {
+            let column = pos + 1;
+            match piece {
+                Piece::Text(text) => {
+                    self.segment(source, line, column, true);
+                    self.output.push_str(text);
+                }
+                Piece::Macro(name) => {
+                    let value = self
+                        .lookup(name)
+                        .map_err(|message| self.error(source, line, column, message))?;
+                    let start = self.output.rfind('\n').map(|pos| pos + 1).unwrap_or(0);
+                    match value {
+                        Some(value) => {
+                            let value = list(&value, &self.output[start..]).to_string();
+                            self.segment(source, line, column, false);
+                            self.output.push_str(&value);
+                        }
+                        None => {
+                            self.segment(source, line, column, true);
+                            self.output.push('$');
+                            self.output.push_str(name);
+                        }
+                    }
+                }
             }
-            Token::None => {}
         }
-        result
+
+        Ok(())
     }
 }
 
-fn escaped_line(s: &str) -> CResult<'_, Token> {
-    map(
-        pair(is_not("\\\n"), tag("\\\n")),
-        |(a, _b): (&str, &str)| Token::Escaped(a.to_string()),
-    )(s)
-}
+/// Split a line into text and macro references with their offsets.
+///
+/// Macros in a trailing comment are kept as text.
+fn pieces(text: &str) -> Vec<(usize, Piece<'_>)> {
+    let mut pieces = vec![];
+    let mut start = 0;
+    let mut pos = 0;
+    let end = comment(text).unwrap_or(text.len());
 
-fn variable(s: &str) -> CResult<'_, Variable> {
-    map(separated_pair(string, char('='), quoted), |(key, value)| {
-        Variable {
-            key: key.to_string(),
-            value: value.to_string(),
+    while let Some(found) = text[pos..end].find('$').map(|found| pos + found) {
+        let name = macro_name(&text[found + 1..]);
+        pos = found + 1;
+        if name.is_empty() || name.starts_with(|ch: char| ch.is_ascii_digit()) {
+            continue;
+        }
+        if start < found {
+            pieces.push((start, Piece::Text(&text[start..found])));
         }
-    })(s)
+        pieces.push((found, Piece::Macro(name)));
+        start = found + 1 + name.len();
+        pos = start;
+    }
+    if start < text.len() {
+        pieces.push((start, Piece::Text(&text[start..])));
+    }
+
+    pieces
 }
 
-fn use_variable(s: &str) -> CResult<'_, Token> {
-    map(
-        pair(
-            opt(is_not("$\n")),
-            map(pair(char('$'), string), |(_, value): (_, &str)| {
-                Token::UseVariable(value.to_string())
-            }),
-        ),
-        |(line, variable)| {
-            Token::Nested(vec![
-                line.map(|line| Token::Next(line.to_string()))
-                    .unwrap_or_default(),
-                variable,
-            ])
-        },
-    )(s)
+/// Returns the offset of a comment that is not within a quoted string.
+fn comment(text: &str) -> Option<usize> {
+    let mut quoted = false;
+    for (pos, ch) in text.char_indices() {
+        match ch {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return Some(pos),
+            _ => (),
+        }
+    }
+    None
 }
 
-fn include(s: &str) -> CResult<'_, Token> {
-    let (input, (_, file)) = separated_pair(tag("include"), multispace1, quoted)(s)?;
+/// Returns the items of a list macro `{ a b }` if it is used within a list.
+fn list<'a>(value: &'a str, before: &str) -> &'a str {
+    let depth = before.matches('{').count() as isize - before.matches('}').count() as isize;
+    let trimmed = value.trim();
+    match trimmed.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+        Some(items) if depth > 0 => items.trim(),
+        _ => value,
+    }
+}
 
-    let output = std::fs::read_to_string(file).map_err(|_err| {
-        Err::<VerboseError<&str>>::Error(VerboseError::<&str> {
-            errors: vec![(input, VerboseErrorKind::Context("include file not found"))],
-        })
-    })?;
-    let (_, tokens) = many0(ast)(&output).map_err(|_err| {
-        Err::<VerboseError<&str>>::Error(VerboseError::<&str> {
-            errors: vec![(input, VerboseErrorKind::Context("invalid include"))],
-        })
-    })?;
+/// Returns the name of a macro at the start of the input.
+fn macro_name(s: &str) -> &str {
+    let end = s
+        .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_'))
+        .unwrap_or(s.len());
+    &s[..end]
+}
 
-    Ok((input, Token::Nested(tokens)))
+/// Parse a macro definition `name="value"`.
+fn definition(line: &str) -> Option<(&str, &str)> {
+    let name = macro_name(line);
+    let value = line[name.len()..].strip_prefix('=')?.trim();
+    if name.is_empty() || name.starts_with(|ch: char| ch.is_ascii_digit()) {
+        return None;
+    }
+    Some((name, unquote(value)))
 }
 
-fn read_line(s: &str) -> CResult<'_, Token> {
-    alt((
-        map(comment, |_| Token::None),
-        map(line, |s| Token::Next(format!("{}\n", s))),
-    ))(s)
+/// Parse an include statement `include "path"`.
+fn include(line: &str) -> Option<&str> {
+    let path = line.strip_prefix("include")?;
+    if !path.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(unquote(path.trim()))
 }
 
-fn ast(s: &str) -> CResult<'_, Token> {
-    alt((
-        map(variable, Token::Variable),
-        escaped_line,
-        use_variable,
-        include,
-        read_line,
-    ))(s)
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
 }
 
+/// Expand macros and includes of the configuration.
+///
+/// The `variables` from the command line take precedence over macros that
+/// are defined in the configuration.  Returns the expanded configuration,
+/// a map to locate the parsed objects in the original source files, and
+/// warnings about redefined or unused macros.
 pub fn config_expand(
-    s: &str,
-    mut variables: Variables,
-) -> IResult<&str, String, VerboseError<&str>> {
-    map(many0(ast), |ast: Vec<Token>| {
-        let mut result = String::new();
-        for token in ast {
-            token.resolve(&mut result, &mut variables);
-        }
-        result
-    })(s)
+    path: &Path,
+    input: &str,
+    variables: Variables,
+) -> Result<(String, SourceMap, Vec<ConfigError>), ConfigError> {
+    let macros = variables
+        .into_iter()
+        .map(|(key, value)| {
+            let m = Macro {
+                value,
+                position: None,
+                used: false,
+            };
+            (key, m)
+        })
+        .collect();
+    let mut expander = Expander {
+        macros,
+        warnings: vec![],
+        output: String::new(),
+        map: SourceMap::default(),
+        stack: vec![],
+    };
+    expander.source(path.to_path_buf(), input.to_string())?;
+
+    let mut unused = expander
+        .macros
+        .iter()
+        .filter(|(_, m)| !m.used)
+        .filter_map(|(key, m)| Some((m.position?, key)))
+        .collect::<Vec<_>>();
+    unused.sort();
+    for ((source, line, column), key) in unused {
+        let warning = expander.error(source, line, column, format!("macro {} not used", key));
+        expander.warnings.push(warning);
+    }
+
+    Ok((expander.output, expander.map, expander.warnings))
 }
@@ -1,34 +1,55 @@
-use crate::config::{Config, Host, Protocol, ProtocolType, Redirect, Relay, Table};
+use crate::{
+    config::{
+        validate::{Object, Offsets},
+        Action, Check, Config, Direction, Filter, FilterType, Forward, Host, Id, Listen,
+        LogConnection, LogFormat, Mode, Operation, Prefix, Protocol, ProtocolType, Redirect,
+        Reference, Relay, Restart, Router, Rule, Table, TableSource, Target, TcpOption, TlsOption,
+    },
+    Privsep,
+};
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_until, take_while1},
-    character::complete::{char, digit1, multispace0},
-    combinator::{all_consuming, eof, map, map_res, not, opt, peek, recognize},
+    character::complete::{char, digit1, multispace0, multispace1, space0},
+    combinator::{all_consuming, cut, eof, map, map_res, not, opt, peek, recognize},
     error::VerboseError,
-    multi::{many0, many_till},
-    sequence::{delimited, pair, preceded, separated_pair, tuple},
+    multi::{many0, many_till, separated_list1},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
     IResult,
 };
 use privsep_log::debug;
-use std::{path::PathBuf, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 pub(super) type CResult<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
 
-enum Section {
+/// Parsed objects and the remaining input where they start.
+type Spans<'a> = Vec<(Object, &'a str)>;
+
+enum Section<'a> {
     // Global configuration.
     Interval(Duration),
     Socket(PathBuf),
     Timeout(Duration),
+    DrainTimeout(Duration),
+    Restart(Restart),
+    Prefork(usize, Spans<'a>),
+    Metrics(Listen, Spans<'a>),
+    Log(LogOption),
 
     // Other sections.
-    Table(Table),
-    Redirect(Redirect),
-    Relay(Relay),
-    Protocol(Protocol),
+    Table(Table, Spans<'a>),
+    Redirect(Redirect, Spans<'a>),
+    Router(Router, Spans<'a>),
+    Relay(Relay, Spans<'a>),
+    Protocol(Protocol, Spans<'a>),
     Ignore,
 }
 
-fn section(s: &str) -> CResult<'_, Section> {
+fn section(s: &str) -> CResult<'_, Section<'_>> {
     preceded(
         not(eof),
         alt((
@@ -44,21 +65,45 @@ fn section(s: &str) -> CResult<'_, Section> {
                 debug!("timeout {:?}", d);
                 Section::Timeout(d)
             }),
-            map(table, |t| {
+            map(drain_timeout, |d| {
+                debug!("drain timeout {:?}", d);
+                Section::DrainTimeout(d)
+            }),
+            map(restart, |r| {
+                debug!("{:?}", r);
+                Section::Restart(r)
+            }),
+            map(prefork, |(n, spans)| {
+                debug!("prefork {}", n);
+                Section::Prefork(n, spans)
+            }),
+            map(metrics, |(l, spans)| {
+                debug!("metrics {}", l);
+                Section::Metrics(l, spans)
+            }),
+            map(log, |l| {
+                debug!("{:?}", l);
+                Section::Log(l)
+            }),
+            map(table, |(t, spans)| {
                 debug!("{:?}", t);
-                Section::Table(t)
+                Section::Table(t, spans)
             }),
-            map(redirect, |r| {
+            map(redirect, |(r, spans)| {
                 debug!("{:?}", r);
-                Section::Redirect(r)
+                Section::Redirect(r, spans)
             }),
-            map(relay, |r| {
+            map(router, |(r, spans)| {
                 debug!("{:?}", r);
-                Section::Relay(r)
+                Section::Router(r, spans)
             }),
-            map(protocol, |p| {
+            map(relay, |(r, spans)| {
+                debug!("{:?}", r);
+                Section::Relay(r, spans)
+            }),
+            map(protocol, |(p, spans)| {
                 debug!("{:?}", p);
-                Section::Protocol(p)
+                Section::Protocol(p, spans)
             }),
             map(comment, |c| {
                 debug!("#{}", c);
@@ -69,14 +114,15 @@ fn section(s: &str) -> CResult<'_, Section> {
     )(s)
 }
 
-fn section_options<T>(s: &str, c: fn(&str) -> CResult<'_, T>) -> CResult<'_, Vec<T>> {
-    delimited(
+/// Parse the options of a section; errors are fatal after the section header.
+fn section_options<'a, T>(s: &'a str, c: fn(&'a str) -> CResult<'a, T>) -> CResult<'a, Vec<T>> {
+    cut(delimited(
         char('{'),
         map(many_till(c, peek(char('}'))), |options: (Vec<T>, _)| {
             options.0
         }),
         char('}'),
-    )(s)
+    ))(s)
 }
 
 fn interval(s: &str) -> CResult<'_, Duration> {
@@ -87,7 +133,7 @@ fn interval(s: &str) -> CResult<'_, Duration> {
 }
 
 fn socket(s: &str) -> CResult<'_, PathBuf> {
-    map(separated_pair(tag("socket<"), nl, string), |(_, path)| {
+    map(separated_pair(tag("socket"), nl, quoted), |(_, path)| {
         PathBuf::from(path)
     })(s)
 }
@@ -99,100 +145,568 @@ fn timeout(s: &str) -> CResult<'_, Duration> {
     )(s)
 }
 
-fn host(s: &str) -> CResult<'_, Host> {
-    map(tuple((sep, string, sep)), |(_, name, _)| Host {
-        name: name.to_string(),
-        ..Host::new()
-    })(s)
+fn drain_timeout(s: &str) -> CResult<'_, Duration> {
+    map(
+        tuple((tag("drain"), nl, tag("timeout"), nl, integer)),
+        |(_, _, _, _, seconds)| Duration::from_secs(seconds),
+    )(s)
 }
 
-fn table_options(s: &str) -> CResult<'_, Vec<Host>> {
-    delimited(
+fn restart(s: &str) -> CResult<'_, Restart> {
+    map(
+        tuple((
+            tag("restart"),
+            nl,
+            alt((
+                map(tag("health"), |_| Privsep::HEALTH_ID),
+                map(tag("relay"), |_| Privsep::RELAY_ID),
+                map(tag("ca"), |_| Privsep::CA_ID),
+                map(tag("metrics"), |_| Privsep::METRICS_ID),
+            )),
+            opt(preceded(tuple((nl, tag("limit"), nl)), integer)),
+        )),
+        |(_, _, process, limit)| Restart {
+            process,
+            limit: limit
+                .map(|limit| limit as u32)
+                .unwrap_or(crate::RESTART_LIMIT),
+        },
+    )(s)
+}
+
+fn prefork(s: &str) -> CResult<'_, (usize, Spans<'_>)> {
+    map(
+        tuple((span, tag("prefork"), nl, map_res(integer, usize::try_from))),
+        |(span, _, _, n)| (n, vec![(Object::Prefork, span)]),
+    )(s)
+}
+
+fn metrics(s: &str) -> CResult<'_, (Listen, Spans<'_>)> {
+    map(
+        tuple((span, tag("metrics"), nl, listen)),
+        |(span, _, _, listen)| (listen, vec![(Object::Metrics, span)]),
+    )(s)
+}
+
+#[derive(Debug)]
+enum LogOption {
+    Connection(LogConnection),
+    StateChanges,
+    HostChecks,
+    Format(LogFormat),
+    File(PathBuf),
+    History(PathBuf),
+}
+
+fn log(s: &str) -> CResult<'_, LogOption> {
+    preceded(
+        pair(tag("log"), nl),
+        alt((
+            map(
+                preceded(tag("connection"), opt(preceded(nl, tag("errors")))),
+                |errors| {
+                    LogOption::Connection(match errors {
+                        Some(_) => LogConnection::Errors,
+                        None => LogConnection::All,
+                    })
+                },
+            ),
+            map(tuple((tag("state"), nl, tag("changes"))), |_| {
+                LogOption::StateChanges
+            }),
+            map(tuple((tag("host"), nl, tag("checks"))), |_| {
+                LogOption::HostChecks
+            }),
+            map(
+                preceded(
+                    pair(tag("format"), nl),
+                    alt((
+                        map(tag("common"), |_| LogFormat::Common),
+                        map(tag("combined"), |_| LogFormat::Combined),
+                        map(tag("json"), |_| LogFormat::Json),
+                    )),
+                ),
+                LogOption::Format,
+            ),
+            map(preceded(pair(tag("file"), nl), quoted), |path| {
+                LogOption::File(PathBuf::from(path))
+            }),
+            map(preceded(pair(tag("history"), nl), quoted), |path| {
+                LogOption::History(PathBuf::from(path))
+            }),
+        )),
+    )(s)
+}
+
+enum HostOption {
+    IpTtl(u8),
+    Parent(Id),
+    Priority(u8),
+    Retry(usize),
+}
+
+fn host_option(s: &str) -> CResult<'_, HostOption> {
+    preceded(
+        nl,
+        alt((
+            map(
+                preceded(
+                    tuple((tag("ip"), nl, tag("ttl"), nl)),
+                    map_res(integer, u8::try_from),
+                ),
+                HostOption::IpTtl,
+            ),
+            map(
+                preceded(pair(tag("parent"), nl), map_res(integer, Id::try_from)),
+                HostOption::Parent,
+            ),
+            map(
+                preceded(pair(tag("priority"), nl), map_res(integer, u8::try_from)),
+                HostOption::Priority,
+            ),
+            map(
+                preceded(pair(tag("retry"), nl), map_res(integer, usize::try_from)),
+                HostOption::Retry,
+            ),
+        )),
+    )(s)
+}
+
+/// Returns the remaining input without consuming it.
+fn span(s: &str) -> CResult<'_, &str> {
+    Ok((s, s))
+}
+
+fn host(s: &str) -> CResult<'_, (Host, &str)> {
+    map(
+        tuple((sep, span, string, many0(host_option), sep)),
+        |(_, span, name, options, _)| {
+            let mut host = Host {
+                name: name.to_string(),
+                ..Host::new()
+            };
+            for option in options {
+                match option {
+                    HostOption::IpTtl(ttl) => host.ip_ttl = Some(ttl),
+                    HostOption::Parent(id) => host.parent = Some(id),
+                    HostOption::Priority(priority) => host.priority = Some(priority),
+                    HostOption::Retry(retry) => host.retry = retry,
+                }
+            }
+            (host, span)
+        },
+    )(s)
+}
+
+fn table_options(s: &str) -> CResult<'_, Vec<(Host, &str)>> {
+    cut(delimited(
         char('{'),
         map(many_till(host, peek(char('}'))), |(hosts, _)| hosts),
         char('}'),
-    )(s)
+    ))(s)
+}
+
+/// Parse a host entry of a table file.
+pub(super) fn table_host(s: &str) -> CResult<'_, Host> {
+    all_consuming(map(host, |(host, _)| host))(s)
+}
+
+fn table_source(s: &str) -> CResult<'_, TableSource> {
+    alt((
+        map(preceded(pair(tag("file"), nl), quoted), |path| {
+            TableSource::File(PathBuf::from(path))
+        }),
+        map(preceded(pair(tag("directory"), nl), quoted), |path| {
+            TableSource::Directory(PathBuf::from(path))
+        }),
+        map(preceded(pair(tag("srv"), nl), quoted), |name| {
+            TableSource::Srv(name.to_string())
+        }),
+    ))(s)
 }
 
 fn table_name(s: &str) -> CResult<'_, &str> {
     delimited(char('<'), string, char('>'))(s)
 }
 
-fn table(s: &str) -> CResult<'_, Table> {
+fn table(s: &str) -> CResult<'_, (Table, Spans<'_>)> {
     map(
         tuple((
+            span,
             tag("table"),
             nl,
             table_name,
             nl,
             opt(pair(tag("disable"), nl)),
-            table_options,
-            line,
+            alt((
+                map(table_source, |source| (vec![], Some(source))),
+                map(table_options, |hosts| (hosts, None)),
+            )),
+            eol,
         )),
-        |(_, _, name, _n, disable, hosts, _)| Table {
-            name: name.to_string(),
-            disabled: disable.is_some(),
-            hosts,
-            ..Table::new()
+        |(span, _, _, name, _n, disable, (hosts, source), _)| {
+            let mut table = Table {
+                name: name.to_string(),
+                disabled: disable.is_some(),
+                source,
+                ..Table::new()
+            };
+            let mut spans = vec![(Object::Table(table.id), span)];
+
+            // The SRV records are resolved at runtime like a host name.
+            if let Some(TableSource::Srv(name)) = &table.source {
+                let host = Host {
+                    name: name.to_string(),
+                    ..Host::new()
+                };
+                spans.push((Object::Host(host.id), span));
+                table.hosts.push(host);
+            }
+            for (host, span) in hosts {
+                spans.push((Object::Host(host.id), span));
+                table.hosts.push(host);
+            }
+            (table, spans)
         },
     )(s)
 }
 
 enum RedirectOption {
-    PfTag(String),
+    Listen(Listen),
+    PfTag(String, bool),
+    SessionTimeout(Duration),
+    Sticky,
+    KeepStates,
+    Forward(Forward),
     Ignore,
 }
 
-fn redirect_option(s: &str) -> CResult<'_, RedirectOption> {
-    alt((
-        map(preceded(tag("listen"), line), |_| {
-            debug!("return");
-            RedirectOption::Ignore
-        }),
-        map(preceded(tag("pftag"), line), |line| {
-            debug!("pftag");
-            RedirectOption::PfTag(line.to_string())
-        }),
-        map(
-            tuple((tag("forward"), nl, tag("to"), nl, table_name, line)),
-            |_line| {
-                debug!("forward");
-                RedirectOption::Ignore
-            },
-        ),
-        map(comment, |_| RedirectOption::Ignore),
-        map(nl, |_| RedirectOption::Ignore),
-    ))(s)
+fn redirect_option(s: &str) -> CResult<'_, (&str, RedirectOption)> {
+    pair(
+        span,
+        alt((
+            map(listen, |listen| {
+                debug!("listen on {}", listen.addr);
+                RedirectOption::Listen(listen)
+            }),
+            map(
+                tuple((opt(pair(tag("match"), nl)), tag("pftag"), nl, quoted, eol)),
+                |(matching, _, _, name, _)| {
+                    debug!("pftag {}", name);
+                    RedirectOption::PfTag(name.to_string(), matching.is_some())
+                },
+            ),
+            map(
+                tuple((tag("session"), nl, tag("timeout"), nl, integer, eol)),
+                |(_, _, _, _, seconds, _)| {
+                    RedirectOption::SessionTimeout(Duration::from_secs(seconds))
+                },
+            ),
+            map(pair(tag("sticky-address"), eol), |_| RedirectOption::Sticky),
+            map(
+                tuple((tag("no"), nl, tag("flush"), nl, tag("states"), eol)),
+                |_| RedirectOption::KeepStates,
+            ),
+            map(forward, |forward| {
+                debug!("forward to {:?}", forward.target);
+                RedirectOption::Forward(forward)
+            }),
+            map(preceded(pair(tag("route"), nl), forward_to), |forward| {
+                debug!("route to {:?}", forward.target);
+                RedirectOption::Forward(Forward {
+                    route: true,
+                    ..forward
+                })
+            }),
+            map(comment, |_| RedirectOption::Ignore),
+            map(nl, |_| RedirectOption::Ignore),
+        )),
+    )(s)
 }
 
-fn redirect_options(s: &str) -> CResult<'_, Vec<RedirectOption>> {
+fn redirect_options(s: &str) -> CResult<'_, Vec<(&str, RedirectOption)>> {
     section_options(s, redirect_option)
 }
 
-fn redirect(s: &str) -> CResult<'_, Redirect> {
+fn redirect(s: &str) -> CResult<'_, (Redirect, Spans<'_>)> {
+    map(
+        tuple((span, tag("redirect"), nl, quoted, nl, redirect_options, eol)),
+        |(span, _, _, name, _, options, _)| {
+            let mut redirect = Redirect {
+                name: name.to_string(),
+                ..Redirect::new()
+            };
+            let id = redirect.id;
+            let mut spans = vec![(Object::Redirect(id), span)];
+            for (span, option) in options {
+                match option {
+                    RedirectOption::Listen(listen) => {
+                        spans.push((Object::RedirectListen(id, redirect.listen.len()), span));
+                        redirect.listen.push(listen);
+                    }
+                    RedirectOption::Forward(forward) => {
+                        spans.push((Object::RedirectForward(id, redirect.forward.len()), span));
+                        redirect.forward.push(forward);
+                    }
+                    RedirectOption::PfTag(name, matching) => {
+                        redirect.tag = Some(name);
+                        redirect.match_tag = matching;
+                    }
+                    RedirectOption::SessionTimeout(timeout) => {
+                        redirect.session_timeout = Some(timeout)
+                    }
+                    RedirectOption::Sticky => redirect.sticky = true,
+                    RedirectOption::KeepStates => redirect.keep_states = true,
+                    RedirectOption::Ignore => (),
+                }
+            }
+            (redirect, spans)
+        },
+    )(s)
+}
+
+enum RouterOption {
+    Route(Prefix),
+    Forward(Forward),
+    Rtable(u32),
+    Rtlabel(String),
+    Ignore,
+}
+
+fn router_option(s: &str) -> CResult<'_, (&str, RouterOption)> {
+    pair(
+        span,
+        alt((
+            map(delimited(pair(tag("route"), nl), prefix, eol), |prefix| {
+                debug!("route {}/{}", prefix.addr, prefix.len);
+                RouterOption::Route(prefix)
+            }),
+            map(forward, |forward| {
+                debug!("forward to {:?}", forward.target);
+                RouterOption::Forward(forward)
+            }),
+            map(
+                delimited(
+                    pair(tag("rtable"), nl),
+                    map_res(integer, u32::try_from),
+                    eol,
+                ),
+                RouterOption::Rtable,
+            ),
+            map(delimited(pair(tag("rtlabel"), nl), quoted, eol), |label| {
+                RouterOption::Rtlabel(label.to_string())
+            }),
+            map(comment, |_| RouterOption::Ignore),
+            map(nl, |_| RouterOption::Ignore),
+        )),
+    )(s)
+}
+
+fn router_options(s: &str) -> CResult<'_, Vec<(&str, RouterOption)>> {
+    section_options(s, router_option)
+}
+
+fn router(s: &str) -> CResult<'_, (Router, Spans<'_>)> {
     map(
-        tuple((tag("redirect"), nl, quoted, nl, redirect_options, line)),
-        |(name, _, _, _, _, _)| Redirect {
-            name: name.to_string(),
-            ..Redirect::new()
+        tuple((span, tag("router"), nl, quoted, nl, router_options, eol)),
+        |(span, _, _, name, _, options, _)| {
+            let mut router = Router {
+                name: name.to_string(),
+                ..Router::new()
+            };
+            let id = router.id;
+            let mut spans = vec![(Object::Router(id), span)];
+            for (span, option) in options {
+                match option {
+                    RouterOption::Route(prefix) => router.routes.push(prefix),
+                    RouterOption::Forward(forward) => {
+                        spans.push((Object::RouterForward(id, router.forward.len()), span));
+                        router.forward.push(forward);
+                    }
+                    RouterOption::Rtable(rtable) => router.rtable = Some(rtable),
+                    RouterOption::Rtlabel(label) => router.rtlabel = Some(label),
+                    RouterOption::Ignore => (),
+                }
+            }
+            (router, spans)
+        },
+    )(s)
+}
+
+/// Network prefix, the length defaults to a host route.
+fn prefix(s: &str) -> CResult<'_, Prefix> {
+    map_res(
+        pair(address, opt(preceded(char('/'), integer))),
+        |(addr, len)| {
+            let max = if addr.is_ipv4() { 32 } else { 128 };
+            match len.unwrap_or(max) {
+                len if len <= max => Ok(Prefix {
+                    addr,
+                    len: len as u8,
+                }),
+                _ => Err("invalid prefix length"),
+            }
         },
     )(s)
 }
 
-fn relay(s: &str) -> CResult<'_, Relay> {
+fn listen(s: &str) -> CResult<'_, Listen> {
     map(
         tuple((
-            tag("relay"),
+            tag("listen"),
             nl,
-            quoted,
+            tag("on"),
             nl,
-            char('{'),
-            take_until("}"),
-            line,
+            address,
+            nl,
+            tag("port"),
+            nl,
+            port,
+            opt(pair(nl, tag("tls"))),
+            opt(preceded(tuple((nl, tag("interface"), nl)), string)),
+            eol,
         )),
-        |(_, _, name, _, _, _, _)| Relay {
-            name: name.to_string(),
-            ..Relay::new()
+        |(_, _, _, _, addr, _, _, _, port, tls, interface, _)| Listen {
+            addr: SocketAddr::new(addr, port),
+            tls: tls.is_some(),
+            interface: interface.map(str::to_string),
+        },
+    )(s)
+}
+
+fn target(s: &str) -> CResult<'_, Target> {
+    alt((
+        map(table_name, |name| Target::Table(Reference::new(name))),
+        map(tag("destination"), |_| Target::Destination),
+        map(string, |name| Target::Host(name.to_string())),
+    ))(s)
+}
+
+fn mode(s: &str) -> CResult<'_, Mode> {
+    alt((
+        map(tag("hash"), |_| Mode::Hash),
+        map(tag("loadbalance"), |_| Mode::Loadbalance),
+        map(tag("least-states"), |_| Mode::LeastStates),
+        map(tag("random"), |_| Mode::Random),
+        map(tag("roundrobin"), |_| Mode::RoundRobin),
+        map(tag("source-hash"), |_| Mode::SourceHash),
+    ))(s)
+}
+
+fn check(s: &str) -> CResult<'_, Check> {
+    preceded(
+        pair(tag("check"), nl),
+        alt((
+            map(tag("icmp"), |_| Check::Icmp),
+            map(
+                tuple((tag("https"), nl, quoted, nl, tag("code"), nl, code)),
+                |(_, _, path, _, _, _, code)| Check::Https {
+                    path: path.to_string(),
+                    code,
+                },
+            ),
+            map(
+                tuple((tag("http"), nl, quoted, nl, tag("code"), nl, code)),
+                |(_, _, path, _, _, _, code)| Check::Http {
+                    path: path.to_string(),
+                    code,
+                },
+            ),
+            map(tag("tcp"), |_| Check::Tcp),
+        )),
+    )(s)
+}
+
+fn forward(s: &str) -> CResult<'_, Forward> {
+    preceded(pair(tag("forward"), nl), forward_to)(s)
+}
+
+/// Target and options of `forward to` or `route to`.
+fn forward_to(s: &str) -> CResult<'_, Forward> {
+    map(
+        tuple((
+            tag("to"),
+            nl,
+            target,
+            opt(preceded(tuple((nl, tag("port"), nl)), port)),
+            opt(preceded(tuple((nl, tag("mode"), nl)), mode)),
+            opt(preceded(nl, check)),
+            eol,
+        )),
+        |(_, _, target, port, mode, check, _)| Forward {
+            target,
+            port,
+            mode: mode.unwrap_or_default(),
+            check,
+            route: false,
+        },
+    )(s)
+}
+
+enum RelayOption {
+    Listen(Listen),
+    Protocol(String),
+    Forward(Forward),
+    Ignore,
+}
+
+fn relay_option(s: &str) -> CResult<'_, (&str, RelayOption)> {
+    pair(
+        span,
+        alt((
+            map(listen, |listen| {
+                debug!("listen on {}", listen.addr);
+                RelayOption::Listen(listen)
+            }),
+            map(
+                tuple((tag("protocol"), nl, quoted, eol)),
+                |(_, _, name, _)| {
+                    debug!("protocol {}", name);
+                    RelayOption::Protocol(name.to_string())
+                },
+            ),
+            map(forward, |forward| {
+                debug!("forward to {:?}", forward.target);
+                RelayOption::Forward(forward)
+            }),
+            map(comment, |_| RelayOption::Ignore),
+            map(nl, |_| RelayOption::Ignore),
+        )),
+    )(s)
+}
+
+fn relay_options(s: &str) -> CResult<'_, Vec<(&str, RelayOption)>> {
+    section_options(s, relay_option)
+}
+
+fn relay(s: &str) -> CResult<'_, (Relay, Spans<'_>)> {
+    map(
+        tuple((span, tag("relay"), nl, quoted, nl, relay_options, eol)),
+        |(span, _, _, name, _, options, _)| {
+            let mut relay = Relay {
+                name: name.to_string(),
+                ..Relay::new()
+            };
+            let id = relay.id;
+            let mut spans = vec![(Object::Relay(id), span)];
+            for (span, option) in options {
+                match option {
+                    RelayOption::Listen(listen) => {
+                        spans.push((Object::RelayListen(id, relay.listen.len()), span));
+                        relay.listen.push(listen);
+                    }
+                    RelayOption::Protocol(name) => {
+                        spans.push((Object::RelayProtocol(id), span));
+                        relay.protocol = Some(Reference::new(&name));
+                    }
+                    RelayOption::Forward(forward) => {
+                        spans.push((Object::RelayForward(id, relay.forward.len()), span));
+                        relay.forward.push(forward);
+                    }
+                    RelayOption::Ignore => (),
+                }
+            }
+            (relay, spans)
         },
     )(s)
 }
@@ -205,30 +719,222 @@ fn protocol_type(s: &str) -> CResult<'_, ProtocolType> {
     ))(s)
 }
 
-fn protocol_option(s: &str) -> CResult<'_, ()> {
+fn tcp_option(s: &str) -> CResult<'_, TcpOption> {
+    alt((
+        map(
+            preceded(pair(tag("backlog"), nl), map_res(integer, u32::try_from)),
+            TcpOption::Backlog,
+        ),
+        map(
+            preceded(
+                tuple((tag("ip"), nl, tag("minttl"), nl)),
+                map_res(integer, u8::try_from),
+            ),
+            TcpOption::IpMinTtl,
+        ),
+        map(
+            preceded(
+                tuple((tag("ip"), nl, tag("ttl"), nl)),
+                map_res(integer, u8::try_from),
+            ),
+            TcpOption::IpTtl,
+        ),
+        map(
+            preceded(
+                tuple((tag("socket"), nl, tag("buffer"), nl)),
+                map_res(integer, u32::try_from),
+            ),
+            TcpOption::SocketBuffer,
+        ),
+        map(
+            pair(opt(no), alt((tag("nodelay"), tag("sack"), tag("splice")))),
+            |(no, name)| match name {
+                "nodelay" => TcpOption::NoDelay(no.is_none()),
+                "sack" => TcpOption::Sack(no.is_none()),
+                _ => TcpOption::Splice(no.is_none()),
+            },
+        ),
+    ))(s)
+}
+
+fn tls_option(s: &str) -> CResult<'_, TlsOption> {
+    alt((
+        map(preceded(pair(tag("ciphers"), nl), quoted), |ciphers| {
+            TlsOption::Ciphers(ciphers.to_string())
+        }),
+        map(preceded(pair(tag("ecdhe"), nl), quoted), |curves| {
+            TlsOption::Ecdhe(curves.to_string())
+        }),
+        map(preceded(pair(tag("keypair"), nl), quoted), |name| {
+            TlsOption::Keypair(name.to_string())
+        }),
+        map(
+            tuple((opt(no), tag("session"), nl, tag("tickets"))),
+            |(no, _, _, _)| TlsOption::SessionTickets(no.is_none()),
+        ),
+        map(
+            pair(
+                opt(no),
+                recognize(pair(tag("tlsv1"), opt(pair(char('.'), digit1)))),
+            ),
+            |(no, version)| TlsOption::Version(version.to_string(), no.is_none()),
+        ),
+    ))(s)
+}
+
+/// Parse a single option or a list of options in braces.
+fn option_list<T>(s: &str, c: fn(&str) -> CResult<'_, T>) -> CResult<'_, Vec<T>> {
+    alt((
+        delimited(
+            pair(char('{'), nl),
+            separated_list1(tuple((nl, char(','), nl)), c),
+            pair(nl, char('}')),
+        ),
+        map(c, |option| vec![option]),
+    ))(s)
+}
+
+fn action(s: &str) -> CResult<'_, Action> {
+    alt((
+        map(tag("block"), |_| Action::Block),
+        map(tag("match"), |_| Action::Match),
+        map(tag("pass"), |_| Action::Pass),
+    ))(s)
+}
+
+fn direction(s: &str) -> CResult<'_, Direction> {
+    alt((
+        map(tag("request"), |_| Direction::Request),
+        map(tag("response"), |_| Direction::Response),
+    ))(s)
+}
+
+fn filter_type(s: &str) -> CResult<'_, FilterType> {
+    alt((
+        map(tag("cookie"), |_| FilterType::Cookie),
+        map(tag("header"), |_| FilterType::Header),
+        map(tag("method"), |_| FilterType::Method),
+        map(tag("path"), |_| FilterType::Path),
+        map(tag("query"), |_| FilterType::Query),
+        map(tag("url"), |_| FilterType::Url),
+    ))(s)
+}
+
+fn operation(s: &str) -> CResult<'_, Operation> {
+    alt((
+        map(tag("append"), |_| Operation::Append),
+        map(tag("hash"), |_| Operation::Hash),
+        map(tag("log"), |_| Operation::Log),
+        map(tag("remove"), |_| Operation::Remove),
+        map(tag("set"), |_| Operation::Set),
+    ))(s)
+}
+
+fn filter(s: &str) -> CResult<'_, Filter> {
+    map(
+        tuple((
+            filter_type,
+            opt(preceded(nl, operation)),
+            nl,
+            quoted,
+            opt(preceded(tuple((nl, tag("value"), nl)), quoted)),
+        )),
+        |(typ, operation, _, key, value)| Filter {
+            typ,
+            operation,
+            key: key.to_string(),
+            value: value.map(str::to_string),
+        },
+    )(s)
+}
+
+enum RuleOption {
+    Label(String),
+    Filter(Filter),
+}
+
+fn rule_option(s: &str) -> CResult<'_, RuleOption> {
     alt((
-        map(preceded(tag("return"), line), |_| {
-            debug!("return");
+        map(preceded(pair(tag("label"), nl), quoted), |label| {
+            RuleOption::Label(label.to_string())
         }),
-        map(pair(alt((tag("block"), tag("match"))), line), |(t, _)| {
-            debug!("{}", t);
+        map(filter, RuleOption::Filter),
+    ))(s)
+}
+
+fn rule(s: &str) -> CResult<'_, Rule> {
+    map(
+        tuple((
+            action,
+            opt(preceded(nl, direction)),
+            opt(pair(nl, tag("quick"))),
+            many0(preceded(nl, rule_option)),
+            eol,
+        )),
+        |(action, direction, quick, options, _)| {
+            let mut rule = Rule {
+                action,
+                direction,
+                quick: quick.is_some(),
+                label: None,
+                filters: vec![],
+            };
+            for option in options {
+                match option {
+                    RuleOption::Label(label) => rule.label = Some(label),
+                    RuleOption::Filter(filter) => rule.filters.push(filter),
+                }
+            }
+            rule
+        },
+    )(s)
+}
+
+enum ProtocolOption {
+    ReturnError,
+    Tcp(Vec<TcpOption>),
+    Tls(Vec<TlsOption>),
+    Rule(Rule),
+    Ignore,
+}
+
+fn protocol_option(s: &str) -> CResult<'_, ProtocolOption> {
+    alt((
+        map(tuple((tag("return"), nl, tag("error"), eol)), |_| {
+            debug!("return error");
+            ProtocolOption::ReturnError
         }),
-        map(preceded(tag("tcp"), line), |_| {
-            debug!("tcp");
+        map(rule, |rule| {
+            debug!("{:?}", rule);
+            ProtocolOption::Rule(rule)
         }),
-        map(preceded(tag("tls"), line), |_| ()),
-        map(comment, |_| ()),
-        map(nl, |_| ()),
+        map(
+            delimited(pair(tag("tcp"), nl), |s| option_list(s, tcp_option), eol),
+            |options| {
+                debug!("tcp {:?}", options);
+                ProtocolOption::Tcp(options)
+            },
+        ),
+        map(
+            delimited(pair(tag("tls"), nl), |s| option_list(s, tls_option), eol),
+            |options| {
+                debug!("tls {:?}", options);
+                ProtocolOption::Tls(options)
+            },
+        ),
+        map(comment, |_| ProtocolOption::Ignore),
+        map(nl, |_| ProtocolOption::Ignore),
     ))(s)
 }
 
-fn protocol_options(s: &str) -> CResult<'_, Vec<()>> {
+fn protocol_options(s: &str) -> CResult<'_, Vec<ProtocolOption>> {
     section_options(s, protocol_option)
 }
 
-fn protocol(s: &str) -> CResult<'_, Protocol> {
+fn protocol(s: &str) -> CResult<'_, (Protocol, Spans<'_>)> {
     map(
         tuple((
+            span,
             opt(protocol_type),
             nl,
             tag("protocol"),
@@ -236,12 +942,25 @@ fn protocol(s: &str) -> CResult<'_, Protocol> {
             quoted,
             nl,
             protocol_options,
-            line,
+            eol,
         )),
-        |(typ, _, _, _, name, _, _, _)| Protocol {
-            name: name.to_string(),
-            typ: typ.unwrap_or_default(),
-            ..Protocol::new()
+        |(span, typ, _, _, _, name, _, options, _)| {
+            let mut protocol = Protocol {
+                name: name.to_string(),
+                typ: typ.unwrap_or_default(),
+                ..Protocol::new()
+            };
+            for option in options {
+                match option {
+                    ProtocolOption::ReturnError => protocol.return_error = true,
+                    ProtocolOption::Tcp(options) => protocol.tcp.extend(options),
+                    ProtocolOption::Tls(options) => protocol.tls.extend(options),
+                    ProtocolOption::Rule(rule) => protocol.rules.push(rule),
+                    ProtocolOption::Ignore => (),
+                }
+            }
+            let spans = vec![(Object::Protocol(protocol.id), span)];
+            (protocol, spans)
         },
     )(s)
 }
@@ -266,6 +985,19 @@ pub(super) fn string(s: &str) -> CResult<'_, &str> {
     take_while1(allowed_in_string)(s)
 }
 
+/// End of an option, allowing a trailing comment.
+fn eol(s: &str) -> CResult<'_, ()> {
+    map(
+        tuple((
+            space0,
+            opt(pair(char('#'), take_until("\n"))),
+            alt((tag("\n"), eof)),
+            nl,
+        )),
+        |_| (),
+    )(s)
+}
+
 pub(super) fn line(s: &str) -> CResult<'_, &str> {
     take_until("\n")(s).and_then(|(s, value)| nl(s).map(|(s, _)| (s, value)))
 }
@@ -278,6 +1010,11 @@ pub(super) fn nl(s: &str) -> CResult<'_, Option<&str>> {
     alt((map(multispace0, |_| None), map(comment, Some)))(s)
 }
 
+/// Prefix of disabled boolean options.
+fn no(s: &str) -> CResult<'_, &str> {
+    terminated(tag("no"), multispace1)(s)
+}
+
 fn sep(s: &str) -> CResult<'_, ()> {
     map(tuple((nl, opt(char(',')), nl)), |_| ())(s)
 }
@@ -290,21 +1027,93 @@ fn integer(s: &str) -> CResult<'_, u64> {
     map_res(recognize(digit1), str::parse)(s)
 }
 
-pub fn config_parser(s: &str) -> CResult<'_, Config> {
-    all_consuming(map(many0(section), |sections: Vec<Section>| {
+fn code(s: &str) -> CResult<'_, u16> {
+    map_res(recognize(digit1), str::parse)(s)
+}
+
+fn address(s: &str) -> CResult<'_, IpAddr> {
+    map_res(
+        take_while1(|ch: char| ch.is_ascii_hexdigit() || ch == '.' || ch == ':'),
+        str::parse,
+    )(s)
+}
+
+/// Well-known service names that can be used instead of port numbers.
+fn service(name: &str) -> Result<u16, &'static str> {
+    match name {
+        "ftp" => Ok(21),
+        "ssh" => Ok(22),
+        "smtp" => Ok(25),
+        "domain" => Ok(53),
+        "http" | "www" => Ok(80),
+        "pop3" => Ok(110),
+        "imap" => Ok(143),
+        "https" => Ok(443),
+        "imaps" => Ok(993),
+        "pop3s" => Ok(995),
+        _ => Err("unknown service"),
+    }
+}
+
+fn port(s: &str) -> CResult<'_, u16> {
+    alt((code, map_res(string, service)))(s)
+}
+
+pub fn config_parser(s: &str) -> CResult<'_, (Config, Offsets)> {
+    all_consuming(map(many0(section), |sections: Vec<Section<'_>>| {
         let mut config = Config::default();
+        let mut offsets = Offsets::new();
+        let mut locate = |spans: Spans<'_>| {
+            for (object, span) in spans {
+                offsets.insert(object, s.len() - span.len());
+            }
+        };
         for section in sections {
             match section {
                 Section::Interval(d) => config.interval = d,
                 Section::Socket(p) => config.socket = p,
                 Section::Timeout(d) => config.timeout = d,
-                Section::Table(t) => config.tables.push(t),
-                Section::Redirect(r) => config.redirects.push(r),
-                Section::Relay(r) => config.relays.push(r),
-                Section::Protocol(p) => config.protocols.push(p),
+                Section::DrainTimeout(d) => config.drain_timeout = d,
+                Section::Restart(r) => config.restart.push(r),
+                Section::Prefork(n, spans) => {
+                    locate(spans);
+                    config.prefork = n;
+                }
+                Section::Metrics(l, spans) => {
+                    locate(spans);
+                    config.metrics = Some(l);
+                }
+                Section::Log(l) => match l {
+                    LogOption::Connection(c) => config.log.connection = Some(c),
+                    LogOption::StateChanges => config.log.state_changes = true,
+                    LogOption::HostChecks => config.log.host_checks = true,
+                    LogOption::Format(f) => config.log.format = f,
+                    LogOption::File(p) => config.log.file = Some(p),
+                    LogOption::History(p) => config.log.history = Some(p),
+                },
+                Section::Table(t, spans) => {
+                    locate(spans);
+                    config.tables.push(t);
+                }
+                Section::Redirect(r, spans) => {
+                    locate(spans);
+                    config.redirects.push(r);
+                }
+                Section::Router(r, spans) => {
+                    locate(spans);
+                    config.routers.push(r);
+                }
+                Section::Relay(r, spans) => {
+                    locate(spans);
+                    config.relays.push(r);
+                }
+                Section::Protocol(p, spans) => {
+                    locate(spans);
+                    config.protocols.push(p);
+                }
                 Section::Ignore => (),
             }
         }
-        config
+        (config, offsets)
     }))(s)
 }
@@ -0,0 +1,407 @@
+use crate::{
+    config::{
+        Action, Check, Config, Direction, Filter, FilterType, Forward, Host, Listen, Log,
+        LogConnection, LogFormat, Mode, Operation, Prefix, Protocol, ProtocolType, Redirect, Relay,
+        Restart, Router, Rule, Table, TableSource, Target, TcpOption, TlsOption,
+    },
+    Privsep,
+};
+use std::fmt;
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "interval {}", self.interval.as_secs())?;
+        writeln!(f, "timeout {}", self.timeout.as_millis())?;
+        writeln!(f, "drain timeout {}", self.drain_timeout.as_secs())?;
+        writeln!(f, "socket \"{}\"", self.socket.display())?;
+        for restart in &self.restart {
+            writeln!(f, "{}", restart)?;
+        }
+        writeln!(f, "prefork {}", self.prefork)?;
+        if let Some(metrics) = &self.metrics {
+            writeln!(f, "metrics {}", metrics)?;
+        }
+        write!(f, "{}", self.log)?;
+
+        for table in &self.tables {
+            write!(f, "\n{}", table)?;
+        }
+        for redirect in &self.redirects {
+            write!(f, "\n{}", redirect)?;
+        }
+        for router in &self.routers {
+            write!(f, "\n{}", router)?;
+        }
+        for protocol in &self.protocols {
+            write!(f, "\n{}", protocol)?;
+        }
+        for relay in &self.relays {
+            write!(f, "\n{}", relay)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Restart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "restart {} limit {}",
+            Privsep::PROCESS_NAMES[self.process],
+            self.limit
+        )
+    }
+}
+
+impl fmt::Display for Log {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.connection {
+            Some(LogConnection::All) => writeln!(f, "log connection")?,
+            Some(LogConnection::Errors) => writeln!(f, "log connection errors")?,
+            None => {}
+        }
+        if self.state_changes {
+            writeln!(f, "log state changes")?;
+        }
+        if self.host_checks {
+            writeln!(f, "log host checks")?;
+        }
+        match self.format {
+            LogFormat::Common => {}
+            LogFormat::Combined => writeln!(f, "log format combined")?,
+            LogFormat::Json => writeln!(f, "log format json")?,
+        }
+        if let Some(path) = &self.file {
+            writeln!(f, "log file \"{}\"", path.display())?;
+        }
+        if let Some(path) = &self.history {
+            writeln!(f, "log history \"{}\"", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "table <{}> ", self.name)?;
+        if self.disabled {
+            write!(f, "disable ")?;
+        }
+        // The hosts of external sources are loaded again when parsing.
+        match &self.source {
+            Some(TableSource::File(path)) => return writeln!(f, "file \"{}\"", path.display()),
+            Some(TableSource::Directory(path)) => {
+                return writeln!(f, "directory \"{}\"", path.display())
+            }
+            Some(TableSource::Srv(name)) => return writeln!(f, "srv \"{}\"", name),
+            None => {}
+        }
+        let hosts = self
+            .hosts
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        writeln!(f, "{{ {} }}", hosts.join(", "))
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some(ttl) = self.ip_ttl {
+            write!(f, " ip ttl {}", ttl)?;
+        }
+        if let Some(parent) = self.parent {
+            write!(f, " parent {}", parent)?;
+        }
+        if let Some(priority) = self.priority {
+            write!(f, " priority {}", priority)?;
+        }
+        if self.retry > 0 {
+            write!(f, " retry {}", self.retry)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "redirect \"{}\" {{", self.name)?;
+        for listen in &self.listen {
+            writeln!(f, "\t{}", listen)?;
+        }
+        if let Some(tag) = &self.tag {
+            let matching = if self.match_tag { "match " } else { "" };
+            writeln!(f, "\t{}pftag \"{}\"", matching, tag)?;
+        }
+        if let Some(timeout) = self.session_timeout {
+            writeln!(f, "\tsession timeout {}", timeout.as_secs())?;
+        }
+        if self.sticky {
+            writeln!(f, "\tsticky-address")?;
+        }
+        if self.keep_states {
+            writeln!(f, "\tno flush states")?;
+        }
+        for forward in &self.forward {
+            writeln!(f, "\t{}", forward)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+impl fmt::Display for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "router \"{}\" {{", self.name)?;
+        for route in &self.routes {
+            writeln!(f, "\troute {}", route)?;
+        }
+        for forward in &self.forward {
+            writeln!(f, "\t{}", forward)?;
+        }
+        if let Some(rtable) = self.rtable {
+            writeln!(f, "\trtable {}", rtable)?;
+        }
+        if let Some(rtlabel) = &self.rtlabel {
+            writeln!(f, "\trtlabel \"{}\"", rtlabel)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for ProtocolType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Http => "http",
+            Self::Dns => "dns",
+        })
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} protocol \"{}\" {{", self.typ, self.name)?;
+        if self.return_error {
+            writeln!(f, "\treturn error")?;
+        }
+        if !self.tcp.is_empty() {
+            writeln!(f, "\ttcp {{ {} }}", join(&self.tcp))?;
+        }
+        if !self.tls.is_empty() {
+            writeln!(f, "\ttls {{ {} }}", join(&self.tls))?;
+        }
+        for rule in &self.rules {
+            writeln!(f, "\t{}", rule)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+/// Join a list of options with commas.
+fn join<T: fmt::Display>(options: &[T]) -> String {
+    options
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Prefix for disabled boolean options.
+fn no(enabled: bool) -> &'static str {
+    if enabled {
+        ""
+    } else {
+        "no "
+    }
+}
+
+impl fmt::Display for TcpOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Backlog(backlog) => write!(f, "backlog {}", backlog),
+            Self::IpMinTtl(ttl) => write!(f, "ip minttl {}", ttl),
+            Self::IpTtl(ttl) => write!(f, "ip ttl {}", ttl),
+            Self::NoDelay(enabled) => write!(f, "{}nodelay", no(*enabled)),
+            Self::Sack(enabled) => write!(f, "{}sack", no(*enabled)),
+            Self::SocketBuffer(size) => write!(f, "socket buffer {}", size),
+            Self::Splice(enabled) => write!(f, "{}splice", no(*enabled)),
+        }
+    }
+}
+
+impl fmt::Display for TlsOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ciphers(ciphers) => write!(f, "ciphers \"{}\"", ciphers),
+            Self::Ecdhe(curves) => write!(f, "ecdhe \"{}\"", curves),
+            Self::Keypair(name) => write!(f, "keypair \"{}\"", name),
+            Self::SessionTickets(enabled) => write!(f, "{}session tickets", no(*enabled)),
+            Self::Version(version, enabled) => write!(f, "{}{}", no(*enabled), version),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Block => "block",
+            Self::Match => "match",
+            Self::Pass => "pass",
+        })
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Request => "request",
+            Self::Response => "response",
+        })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+        if let Some(direction) = self.direction {
+            write!(f, " {}", direction)?;
+        }
+        if self.quick {
+            write!(f, " quick")?;
+        }
+        if let Some(label) = &self.label {
+            write!(f, " label \"{}\"", label)?;
+        }
+        for filter in &self.filters {
+            write!(f, " {}", filter)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FilterType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Cookie => "cookie",
+            Self::Header => "header",
+            Self::Method => "method",
+            Self::Path => "path",
+            Self::Query => "query",
+            Self::Url => "url",
+        })
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Append => "append",
+            Self::Hash => "hash",
+            Self::Log => "log",
+            Self::Remove => "remove",
+            Self::Set => "set",
+        })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.typ)?;
+        if let Some(operation) = self.operation {
+            write!(f, " {}", operation)?;
+        }
+        write!(f, " \"{}\"", self.key)?;
+        if let Some(value) = &self.value {
+            write!(f, " value \"{}\"", value)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Relay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "relay \"{}\" {{", self.name)?;
+        for listen in &self.listen {
+            writeln!(f, "\t{}", listen)?;
+        }
+        if let Some(protocol) = &self.protocol {
+            writeln!(f, "\tprotocol \"{}\"", protocol.name)?;
+        }
+        for forward in &self.forward {
+            writeln!(f, "\t{}", forward)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "listen on {} port {}", self.addr.ip(), self.addr.port())?;
+        if self.tls {
+            write!(f, " tls")?;
+        }
+        if let Some(interface) = &self.interface {
+            write!(f, " interface {}", interface)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Table(table) => write!(f, "<{}>", table.name),
+            Self::Host(name) => f.write_str(name),
+            Self::Destination => f.write_str("destination"),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Hash => "hash",
+            Self::Loadbalance => "loadbalance",
+            Self::LeastStates => "least-states",
+            Self::Random => "random",
+            Self::RoundRobin => "roundrobin",
+            Self::SourceHash => "source-hash",
+        })
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Icmp => write!(f, "check icmp"),
+            Self::Tcp => write!(f, "check tcp"),
+            Self::Http { path, code } => write!(f, "check http \"{}\" code {}", path, code),
+            Self::Https { path, code } => write!(f, "check https \"{}\" code {}", path, code),
+        }
+    }
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = if self.route { "route" } else { "forward" };
+        write!(f, "{} to {}", action, self.target)?;
+        if let Some(port) = self.port {
+            write!(f, " port {}", port)?;
+        }
+        if let Target::Table(_) = self.target {
+            write!(f, " mode {}", self.mode)?;
+        }
+        if let Some(check) = &self.check {
+            write!(f, " {}", check)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{
+    config::{
+        expand::SourceMap,
+        parser::table_host,
+        validate::{Object, Offsets},
+        Config, Host, TableSource,
+    },
+    error::{ConfigError, Location},
+};
+use nom::Finish;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Returns the sorted list of host files of a table source.
+///
+/// Hidden files are skipped to allow tools to write a temporary file
+/// before renaming it in place.
+fn files(source: &TableSource) -> io::Result<Vec<PathBuf>> {
+    match source {
+        TableSource::File(path) => Ok(vec![path.clone()]),
+        TableSource::Directory(path) => {
+            let mut paths = vec![];
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                if !hidden && entry.file_type()?.is_file() {
+                    paths.push(entry.path());
+                }
+            }
+            paths.sort();
+            Ok(paths)
+        }
+        // SRV records are resolved at runtime.
+        TableSource::Srv(_) => Ok(vec![]),
+    }
+}
+
+/// Parse a host file with one host per line.
+fn read_hosts(path: &Path, hosts: &mut Vec<Host>, errors: &mut Vec<ConfigError>) -> io::Result<()> {
+    let content = fs::read_to_string(path)?;
+
+    for (i, line) in content.lines().enumerate() {
+        let text = line.split('#').next().unwrap_or_default().trim_end();
+        let entry = text.trim_start();
+        if entry.is_empty() {
+            continue;
+        }
+
+        match table_host(entry).finish() {
+            Ok((_, host)) => hosts.push(host),
+            Err(err) => {
+                let offset = err
+                    .errors
+                    .first()
+                    .map(|(rest, _)| entry.len() - rest.len())
+                    .unwrap_or_default();
+                errors.push(ConfigError {
+                    location: Location {
+                        file: path.to_path_buf(),
+                        line: i + 1,
+                        column: text.len() - entry.len() + offset + 1,
+                    },
+                    snippet: line.to_string(),
+                    message: "invalid host".to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the hosts of all tables with a file or directory source.
+///
+/// Relative paths are resolved from the directory of the file that
+/// defines the table.
+pub(super) fn load(config: &mut Config, map: &SourceMap, offsets: &Offsets) -> Vec<ConfigError> {
+    let mut errors = vec![];
+
+    for table in &mut config.tables {
+        let offset = offsets.get(&Object::Table(table.id)).copied();
+        let source = match &mut table.source {
+            Some(source @ (TableSource::File(_) | TableSource::Directory(_))) => source,
+            _ => continue,
+        };
+
+        if let TableSource::File(path) | TableSource::Directory(path) = source {
+            if path.is_relative() {
+                if let Some(dir) = offset.and_then(|offset| {
+                    let file = map.locate(offset).file;
+                    file.parent().map(Path::to_path_buf)
+                }) {
+                    *path = dir.join(&path);
+                }
+            }
+        }
+
+        let result = files(source).and_then(|paths| {
+            paths
+                .iter()
+                .try_for_each(|path| read_hosts(path, &mut table.hosts, &mut errors))
+        });
+        if let Err(err) = result {
+            let message = format!("failed to load table <{}>: {}", table.name, err);
+            errors.push(match offset {
+                Some(offset) => map.error(offset, message),
+                None => ConfigError {
+                    message,
+                    ..Default::default()
+                },
+            });
+        }
+    }
+
+    errors
+}
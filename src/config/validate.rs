@@ -0,0 +1,419 @@
+use crate::{
+    config::{expand::SourceMap, Config, Id, Listen, ProtocolType, Target},
+    error::{ConfigError, Location},
+};
+use privsep_log::warn;
+use std::collections::HashMap;
+
+/// Parsed object that can be located in the configuration file.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(super) enum Object {
+    Table(Id),
+    Host(Id),
+    Redirect(Id),
+    /// Listen option of a redirect by its index.
+    RedirectListen(Id, usize),
+    /// Forward option of a redirect by its index.
+    RedirectForward(Id, usize),
+    Router(Id),
+    /// Forward option of a router by its index.
+    RouterForward(Id, usize),
+    Relay(Id),
+    /// Listen option of a relay by its index.
+    RelayListen(Id, usize),
+    RelayProtocol(Id),
+    /// Forward option of a relay by its index.
+    RelayForward(Id, usize),
+    Protocol(Id),
+    Prefork,
+    Metrics,
+}
+
+/// Offsets of the parsed objects in the input.
+pub(super) type Offsets = HashMap<Object, usize>;
+
+struct Validator<'a> {
+    config: &'a Config,
+    map: &'a SourceMap,
+    offsets: &'a Offsets,
+    errors: Vec<ConfigError>,
+}
+
+impl Validator<'_> {
+    fn location(&self, object: Object) -> Location {
+        match self.offsets.get(&object) {
+            Some(offset) => self.map.locate(*offset),
+            None => Default::default(),
+        }
+    }
+
+    fn error<S: Into<String>>(&mut self, object: Object, message: S) {
+        let error = match self.offsets.get(&object) {
+            Some(offset) => self.map.error(*offset, message),
+            None => ConfigError {
+                message: message.into(),
+                ..Default::default()
+            },
+        };
+        self.errors.push(error);
+    }
+
+    /// Report a name that was already used by another object of the same kind.
+    fn duplicate(
+        &mut self,
+        names: &mut HashMap<String, Object>,
+        kind: &str,
+        name: &str,
+        object: Object,
+    ) {
+        if let Some(first) = names.get(name).copied() {
+            let line = self.location(first).line;
+            self.error(
+                object,
+                format!(
+                    "duplicate {} {}, first defined on line {}",
+                    kind, name, line
+                ),
+            );
+        } else {
+            names.insert(name.to_string(), object);
+        }
+    }
+
+    fn tables(&mut self) {
+        let mut names = HashMap::new();
+        let hosts = self
+            .config
+            .tables
+            .iter()
+            .flat_map(|table| table.hosts.iter())
+            .map(|host| host.id)
+            .collect::<Vec<_>>();
+
+        for table in &self.config.tables {
+            let object = Object::Table(table.id);
+            self.duplicate(&mut names, "table", &format!("<{}>", table.name), object);
+            for host in &table.hosts {
+                match host.parent {
+                    Some(parent) if parent == host.id => {
+                        self.error(Object::Host(host.id), "host cannot be its own parent")
+                    }
+                    Some(parent) if !hosts.contains(&parent) => self.error(
+                        Object::Host(host.id),
+                        format!("undefined parent host {}", parent),
+                    ),
+                    _ => {}
+                }
+            }
+
+            // Disabled tables are kept in the configuration to enable them later.
+            if !table.disabled && !self.is_used(&table.name) {
+                warn!("{}: unused table <{}>", self.location(object), table.name);
+            }
+        }
+    }
+
+    /// Returns true if the table is a forwarding target.
+    fn is_used(&self, name: &str) -> bool {
+        let used = |target: &Target| matches!(target, Target::Table(table) if table.name == name);
+        self.config
+            .redirects
+            .iter()
+            .flat_map(|redirect| redirect.forward.iter())
+            .chain(
+                self.config
+                    .routers
+                    .iter()
+                    .flat_map(|router| router.forward.iter()),
+            )
+            .chain(
+                self.config
+                    .relays
+                    .iter()
+                    .flat_map(|relay| relay.forward.iter()),
+            )
+            .any(|forward| used(&forward.target))
+    }
+
+    fn target(&mut self, object: Object, target: &Target) {
+        if let Target::Table(table) = target {
+            if self.config.table(&table.name).is_none() {
+                self.error(object, format!("undefined table <{}>", table.name));
+            }
+        }
+    }
+
+    fn protocols(&mut self) {
+        let mut names = HashMap::new();
+
+        for protocol in &self.config.protocols {
+            let object = Object::Protocol(protocol.id);
+            self.duplicate(&mut names, "protocol", &protocol.name, object);
+
+            if let ProtocolType::Http = protocol.typ {
+                continue;
+            }
+            if protocol.return_error {
+                self.error(object, "return error requires an http protocol");
+            }
+            if protocol.rules.iter().any(|rule| !rule.filters.is_empty()) {
+                self.error(object, "filter rules require an http protocol");
+            }
+        }
+    }
+
+    fn redirects(&mut self) {
+        let mut names = HashMap::new();
+
+        for redirect in &self.config.redirects {
+            let object = Object::Redirect(redirect.id);
+            self.duplicate(&mut names, "redirect", &redirect.name, object);
+
+            if redirect.listen.is_empty() {
+                self.error(
+                    object,
+                    format!("redirect {} has no listener", redirect.name),
+                );
+            }
+            let route = redirect.forward.iter().any(|forward| forward.route);
+            for (i, listen) in redirect.listen.iter().enumerate() {
+                if listen.tls {
+                    self.error(
+                        Object::RedirectListen(redirect.id, i),
+                        "redirects do not support tls",
+                    );
+                }
+                // nftables routes from the ingress hook of the interface.
+                if route && listen.interface.is_none() && cfg!(target_os = "linux") {
+                    self.error(
+                        Object::RedirectListen(redirect.id, i),
+                        "route to requires a listen interface",
+                    );
+                }
+            }
+
+            if redirect.forward.is_empty() {
+                self.error(object, format!("redirect {} has no target", redirect.name));
+            }
+            for (i, forward) in redirect.forward.iter().enumerate() {
+                let object = Object::RedirectForward(redirect.id, i);
+                match forward.target {
+                    Target::Table(_) => self.target(object, &forward.target),
+                    _ => self.error(object, "redirects can only forward to tables"),
+                }
+                // The routed packets bypass the connection tracking.
+                if forward.route && redirect.session_timeout.is_some() && cfg!(target_os = "linux")
+                {
+                    self.error(object, "session timeout is not supported with route to");
+                }
+            }
+        }
+    }
+
+    fn routers(&mut self) {
+        let mut names = HashMap::new();
+
+        for router in &self.config.routers {
+            let object = Object::Router(router.id);
+            self.duplicate(&mut names, "router", &router.name, object);
+
+            if router.routes.is_empty() {
+                self.error(object, format!("router {} has no route", router.name));
+            }
+            if router.forward.is_empty() {
+                self.error(object, format!("router {} has no target", router.name));
+            }
+            for (i, forward) in router.forward.iter().enumerate() {
+                let object = Object::RouterForward(router.id, i);
+                match forward.target {
+                    Target::Table(_) if forward.port.is_some() => {
+                        self.error(object, "routers do not support ports")
+                    }
+                    Target::Table(_) => self.target(object, &forward.target),
+                    _ => self.error(object, "routers can only forward to tables"),
+                }
+            }
+        }
+    }
+
+    fn relays(&mut self) {
+        let mut names = HashMap::new();
+
+        for relay in &self.config.relays {
+            let object = Object::Relay(relay.id);
+            self.duplicate(&mut names, "relay", &relay.name, object);
+
+            if relay.listen.is_empty() {
+                self.error(object, format!("relay {} has no listener", relay.name));
+            }
+            for (i, listen) in relay.listen.iter().enumerate() {
+                if listen.interface.is_some() {
+                    self.error(
+                        Object::RelayListen(relay.id, i),
+                        "interface is only supported by redirects",
+                    );
+                }
+            }
+
+            if let Some(protocol) = &relay.protocol {
+                if self.config.protocol(&protocol.name).is_none() {
+                    self.error(
+                        Object::RelayProtocol(relay.id),
+                        format!("undefined protocol {}", protocol.name),
+                    );
+                }
+            }
+
+            if relay.forward.is_empty() {
+                self.error(object, format!("relay {} has no target", relay.name));
+            }
+            for (i, forward) in relay.forward.iter().enumerate() {
+                let object = Object::RelayForward(relay.id, i);
+                match forward.target {
+                    Target::Table(_) => self.target(object, &forward.target),
+                    _ if forward.check.is_some() => {
+                        self.error(object, "checks are only supported for tables")
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn prefork(&mut self) {
+        if !(1..=crate::PREFORK_MAX).contains(&self.config.prefork) {
+            self.error(
+                Object::Prefork,
+                format!("prefork must be between 1 and {}", crate::PREFORK_MAX),
+            );
+        }
+    }
+
+    fn metrics(&mut self) {
+        if let Some(listen) = &self.config.metrics {
+            if listen.tls {
+                self.error(Object::Metrics, "metrics do not support tls");
+            }
+            if listen.interface.is_some() {
+                self.error(Object::Metrics, "interface is only supported by redirects");
+            }
+        }
+    }
+
+    /// Report listeners that would bind the same local address.
+    fn listeners(&mut self) {
+        let listeners = self
+            .config
+            .metrics
+            .iter()
+            .map(|listen| (Object::Metrics, listen))
+            .chain(self.config.redirects.iter().flat_map(|redirect| {
+                redirect
+                    .listen
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, listen)| (Object::RedirectListen(redirect.id, i), listen))
+            }))
+            .chain(self.config.relays.iter().flat_map(|relay| {
+                relay
+                    .listen
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, listen)| (Object::RelayListen(relay.id, i), listen))
+            }))
+            .collect::<Vec<_>>();
+        for (i, (object, listen)) in listeners.iter().enumerate() {
+            if let Some((first, _)) = listeners[..i]
+                .iter()
+                .find(|(_, other)| conflicts(listen, other))
+            {
+                let line = self.location(*first).line;
+                self.error(
+                    *object,
+                    format!(
+                        "listen address {} conflicts with line {}",
+                        listen.addr, line
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Returns true if both listeners bind the same address and port.
+fn conflicts(a: &Listen, b: &Listen) -> bool {
+    a.addr.port() == b.addr.port()
+        && a.addr.is_ipv4() == b.addr.is_ipv4()
+        && (a.addr.ip() == b.addr.ip()
+            || a.addr.ip().is_unspecified()
+            || b.addr.ip().is_unspecified())
+}
+
+/// Validate the parsed configuration and return all errors.
+pub(super) fn validate(config: &Config, map: &SourceMap, offsets: &Offsets) -> Vec<ConfigError> {
+    let mut validator = Validator {
+        config,
+        map,
+        offsets,
+        errors: vec![],
+    };
+
+    validator.tables();
+    validator.protocols();
+    validator.redirects();
+    validator.routers();
+    validator.relays();
+    validator.prefork();
+    validator.metrics();
+    validator.listeners();
+
+    let mut errors = validator.errors;
+    errors.sort_by(|a, b| {
+        (&a.location.file, a.location.line, a.location.column).cmp(&(
+            &b.location.file,
+            b.location.line,
+            b.location.column,
+        ))
+    });
+    errors
+}
+
+/// Resolve the names of the tables and protocols of a valid configuration
+/// to their Ids.
+pub(super) fn resolve(config: &mut Config) {
+    let tables = config
+        .tables
+        .iter()
+        .map(|table| (table.name.clone(), table.id))
+        .collect::<HashMap<_, _>>();
+    let protocols = config
+        .protocols
+        .iter()
+        .map(|protocol| (protocol.name.clone(), protocol.id))
+        .collect::<HashMap<_, _>>();
+
+    let forwards = config
+        .redirects
+        .iter_mut()
+        .flat_map(|redirect| redirect.forward.iter_mut())
+        .chain(
+            config
+                .routers
+                .iter_mut()
+                .flat_map(|router| router.forward.iter_mut()),
+        )
+        .chain(
+            config
+                .relays
+                .iter_mut()
+                .flat_map(|relay| relay.forward.iter_mut()),
+        );
+    for forward in forwards {
+        if let Target::Table(table) = &mut forward.target {
+            table.id = tables.get(&table.name).copied().unwrap_or_default();
+        }
+    }
+    for protocol in config.relays.iter_mut().filter_map(|r| r.protocol.as_mut()) {
+        protocol.id = protocols.get(&protocol.name).copied().unwrap_or_default();
+    }
+}
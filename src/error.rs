@@ -1,5 +1,5 @@
 use derive_more::{Display, From};
-use std::io;
+use std::{fmt, io, path::PathBuf};
 
 /// Common errors of the `privsep` crate.
 #[derive(Debug, Display, From)]
@@ -12,8 +12,10 @@ pub enum Error {
     Options(getopts::Fail),
     #[display(fmt = "Privilge separation error: {}", "_0")]
     PrivsepError(privsep::Error),
-    #[display(fmt = "Parser error: {}", "_0")]
-    ParserError(String),
+    #[display(fmt = "{}", "_0")]
+    ParserError(ConfigError),
+    #[display(fmt = "Configuration errors:{}", "ErrorList(_0)")]
+    ConfigErrors(Vec<ConfigError>),
     #[display(fmt = "Lost {}, terminated", "_0")]
     #[from(ignore)]
     Terminated(&'static str),
@@ -21,6 +23,61 @@ pub enum Error {
 
 impl std::error::Error for Error {}
 
+/// Position in the configuration file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+/// Syntax or semantic error in the configuration file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConfigError {
+    pub location: Location,
+    /// The source line of the error.
+    pub snippet: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.location.line == 0 {
+            return f.write_str(&self.message);
+        }
+        write!(f, "{}: {}", self.location, self.message)?;
+        if !self.snippet.is_empty() {
+            // Keep tabs to align the marker with the indented source line.
+            let indent = self
+                .snippet
+                .chars()
+                .take(self.location.column.saturating_sub(1))
+                .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                .collect::<String>();
+            write!(f, "\n{}\n{}^", self.snippet, indent)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print all configuration errors, one per line.
+struct ErrorList<'a>(&'a [ConfigError]);
+
+impl fmt::Display for ErrorList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for error in self.0 {
+            write!(f, "\n{}", error)?;
+        }
+        Ok(())
+    }
+}
+
 // Convert to privsep error.
 impl From<Error> for privsep::Error {
     fn from(error: Error) -> privsep::Error {
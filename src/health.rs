@@ -1,14 +1,30 @@
+mod check;
+mod resolver;
+
 use crate::{
+    config::{Check, Config, Forward, Id, Log, Target},
     error::Error,
+    history::{CheckError, Transition},
     message::{Data, Type},
+    metrics::{HostStats, Stats},
     parent::{default_handler, send_to_peer},
     Child, Context, Privsep,
 };
 use futures::{stream::FuturesUnordered, StreamExt};
-use privsep::imsg::Message;
-use privsep_log::{debug, info, trace};
-use std::{io, sync::Arc};
-use tokio::{net, time};
+use privsep_log::{debug, info, trace, warn};
+use resolver::{Change, Resolver};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net,
+    task::JoinHandle,
+    time::{self, Instant},
+};
 
 pub async fn main<const N: usize>(
     child: Child<N>,
@@ -23,33 +39,64 @@ pub async fn main<const N: usize>(
         config: Default::default(),
     };
 
+    // Last known state of every checked host.
+    let states = Arc::new(Mutex::new(HashMap::<Id, bool>::new()));
+    let mut checks = None;
+
     info!("Started");
 
     loop {
-        tokio::select! {
-            message = default_handler::<Data<'_>>(&context.child[Privsep::PARENT_ID]) => {
-                match message? {
-                    (Message { id: Type::CONFIG, .. }, _, Data::Config(new_config)) => {
-                        trace!("received config: {:?}", new_config);
-                        context.config.store(Arc::new(new_config.into_owned()));
-                    }
-                    (Message { id: Type::START, .. }, ..) => {
-                        trace!("received start command");
-                        run(context.clone()).await
-                    }
-                    _ => return Err(Error::InvalidMessage.into()),
-                }
+        let (message, _, data) =
+            default_handler::<Data<'_>>(&context.child[Privsep::PARENT_ID]).await?;
+        match (Type::from(message.id), data) {
+            (Type::Config, Data::Config(new_config)) => {
+                trace!("received config: {:?}", new_config);
+                context.config.store(Arc::new(new_config.into_owned()));
+            }
+            (Type::HostUp, Data::Host(id)) => {
+                trace!("received last known state UP: {}", id);
+                states.lock().unwrap().insert(id, true);
+            }
+            (Type::HostDown, Data::Host(id)) => {
+                trace!("received last known state DOWN: {}", id);
+                states.lock().unwrap().insert(id, false);
             }
-            message = default_handler::<()>(&context.child[Privsep::RELAY_ID]) => { message?; },
-            message = default_handler::<()>(&context.child[Privsep::REDIRECT_ID]) => { message?; },
+            (Type::Start, _) => {
+                trace!("received start command");
+                checks = Some(run(context.clone(), states.clone()).await);
+            }
+            (Type::Shutdown, _) => {
+                trace!("received shutdown command");
+                break;
+            }
+            _ => return Err(Error::InvalidMessage.into()),
         }
     }
+
+    // Stop the running health checks.
+    if let Some(checks) = checks {
+        checks.abort();
+    }
+
+    info!("Terminated");
+
+    Ok(())
 }
 
-async fn run<const N: usize>(context: Context<N>) {
+async fn run<const N: usize>(
+    context: Context<N>,
+    states: Arc<Mutex<HashMap<Id, bool>>>,
+) -> JoinHandle<()> {
     trace!("Running");
 
     tokio::spawn(async move {
+        let mut resolver = match Resolver::new(&context.config.load().nameservers) {
+            Ok(resolver) => Some(resolver),
+            Err(err) => {
+                warn!("Failed to start the resolver: {}", err);
+                None
+            }
+        };
         let mut interval = time::interval(context.config.load().interval);
         loop {
             interval.tick().await;
@@ -58,58 +105,204 @@ async fn run<const N: usize>(context: Context<N>) {
             let context = context.clone();
             let config = context.config.load();
 
+            if let Some(resolver) = &mut resolver {
+                if let Err(err) = update(&context, resolver, &config).await {
+                    warn!("Failed to send host changes: {}", err);
+                }
+            }
+
+            // Check every resolved address of a host name as a separate host.
+            let mut targets = vec![];
+            for table in &config.tables {
+                let (check, port) = table_check(&config, table.id);
+                for host in &table.hosts {
+                    let target = |id, address| HostStats {
+                        id,
+                        table: table.name.clone(),
+                        host: host.name.clone(),
+                        address,
+                        up: false,
+                        latency: Duration::ZERO,
+                    };
+                    match resolver
+                        .as_ref()
+                        .and_then(|resolver| resolver.members(host))
+                    {
+                        Some(members) if !members.is_empty() => {
+                            targets.extend(members.iter().map(|member| {
+                                let port = port.forward.or(member.port).unwrap_or(port.listen);
+                                let address = SocketAddr::new(member.addr, port).to_string();
+                                (target(member.id, address), check.clone())
+                            }));
+                        }
+                        _ => {
+                            let port = port.forward.unwrap_or(port.listen);
+                            let address = format!("{}:{}", host.name, port);
+                            targets.push((target(host.id, address), check.clone()))
+                        }
+                    }
+                }
+            }
+
+            let states = states.clone();
             tokio::spawn(async move {
-                let _ = &context;
                 let mut tasks = FuturesUnordered::new();
                 let timeout = config.timeout;
 
-                for host in config
-                    .tables
-                    .iter()
-                    .map(|table| table.hosts.iter())
-                    .flatten()
-                {
-                    let host = host.clone();
-                    let id = host.id;
+                for (mut target, check) in targets {
                     let fut = tokio::spawn(async move {
-                        let addr = host.name + ":80";
-                        let addrs = net::lookup_host(&addr).await.map_err(|_| id)?;
-                        for addr in addrs {
-                            debug!("checking host {}: {}", id, addr);
-                            if let Ok(Ok(_)) =
-                                time::timeout(timeout, net::TcpStream::connect(addr)).await
-                            {
-                                return Ok(id);
+                        let start = Instant::now();
+                        let mut reason = Some(CheckError::Resolve);
+                        if let Ok(addrs) = net::lookup_host(&target.address).await {
+                            for addr in addrs {
+                                debug!("checking host {}: {}", target.id, addr);
+                                match check::check(addr, &target.host, check.as_ref(), timeout)
+                                    .await
+                                {
+                                    Ok(()) => {
+                                        target.up = true;
+                                        reason = None;
+                                        break;
+                                    }
+                                    Err(err) => reason = Some(err),
+                                }
                             }
                         }
-                        Err(id)
+                        target.latency = start.elapsed();
+                        (target, check, reason)
                     });
                     tasks.push(fut);
                 }
 
+                let peer = &context.child[Privsep::PARENT_ID];
+                let mut stats = vec![];
                 while let Some(result) = tasks.next().await {
-                    let (typ, id) = match result? {
-                        Ok(id) => {
-                            debug!("host {} is UP", id);
-                            (Type::HostUp, id)
-                        }
-                        Err(id) => {
-                            debug!("host {} is DOWN", id);
-                            (Type::HostDown, id)
-                        }
+                    let (target, check, reason) = result?;
+                    let previous = states.lock().unwrap().insert(target.id, target.up);
+                    log_check(&config.log, &target, check.as_ref(), previous);
+
+                    if previous != Some(target.up) {
+                        let transition = Transition {
+                            time: SystemTime::now(),
+                            id: target.id,
+                            table: target.table.clone(),
+                            host: target.host.clone(),
+                            address: target.address.clone(),
+                            old: previous,
+                            new: target.up,
+                            reason,
+                        };
+                        let data = Data::Transition(transition);
+                        send_to_peer(peer, Type::HostChange, None, &data).await?;
+                    }
+                    let typ = if target.up {
+                        Type::HostUp
+                    } else {
+                        Type::HostDown
                     };
 
-                    let peer = &context.child[Privsep::REDIRECT_ID];
-                    send_to_peer(peer, typ, None, &Data::Host(id))
-                        .await
-                        .unwrap();
-                    let peer = &context.child[Privsep::RELAY_ID];
-                    send_to_peer(peer, typ, None, &Data::Host(id))
-                        .await
-                        .unwrap();
+                    // The parent forwards the host state to the other processes.
+                    send_to_peer(peer, typ, None, &Data::Host(target.id)).await?;
+                    stats.push(target);
                 }
+
+                let data = Data::Stats(Stats::Hosts(stats));
+                send_to_peer(peer, Type::Stats, None, &data).await?;
+
                 Ok::<_, io::Error>(())
             });
         }
-    });
+    })
+}
+
+/// Ports of the hosts of a table.
+struct Port {
+    /// Port of the forward statement.
+    forward: Option<u16>,
+    /// Listen port of the redirect or relay.
+    listen: u16,
+}
+
+/// Returns the check method and port of the first forward to a table.
+///
+/// Like the relays, the hosts are checked on the forward port, the port of
+/// their SRV record or the listen port, in that order.  Tables that are not
+/// used by a redirect or relay are checked on port 80.
+fn table_check(config: &Config, table: Id) -> (Option<Check>, Port) {
+    let uses = |forward: &Forward| matches!(&forward.target, Target::Table(t) if t.id == table);
+    let listens = config
+        .redirects
+        .iter()
+        .map(|redirect| (&redirect.listen, &redirect.forward))
+        .chain(
+            config
+                .relays
+                .iter()
+                .map(|relay| (&relay.listen, &relay.forward)),
+        )
+        .map(|(listen, forward)| (listen.first().map(|l| l.addr.port()), forward));
+    let routers = config.routers.iter().map(|router| (None, &router.forward));
+
+    listens
+        .chain(routers)
+        .find_map(|(listen, forwards)| {
+            let forward = forwards.iter().find(|forward| uses(forward))?;
+            let port = Port {
+                forward: forward.port,
+                listen: listen.unwrap_or(80),
+            };
+            Some((forward.check.clone(), port))
+        })
+        .unwrap_or((
+            None,
+            Port {
+                forward: None,
+                listen: 80,
+            },
+        ))
+}
+
+/// Log the result of a host check and state changes.
+fn log_check(log: &Log, target: &HostStats, check: Option<&Check>, previous: Option<bool>) {
+    let state = |up| if up { "up" } else { "down" };
+    let check = format!(
+        "host {}, {} {} ({}ms)",
+        target.host,
+        check.unwrap_or(&Check::Tcp),
+        target.address,
+        target.latency.as_millis()
+    );
+
+    if previous != Some(target.up) {
+        let old = previous.map(state).unwrap_or("unknown");
+        if log.state_changes {
+            info!("{}, state {} -> {}", check, old, state(target.up));
+        } else {
+            debug!("{}, state {} -> {}", check, old, state(target.up));
+        }
+    } else if log.host_checks {
+        info!("{}, state {}", check, state(target.up));
+    } else {
+        debug!("{}, state {}", check, state(target.up));
+    }
+}
+
+/// Resolve the host names and inform the parent about changed addresses.
+async fn update<const N: usize>(
+    context: &Context<N>,
+    resolver: &mut Resolver,
+    config: &Config,
+) -> io::Result<()> {
+    let peer = &context.child[Privsep::PARENT_ID];
+    for change in resolver.update(config).await {
+        match change {
+            Change::Add(host) => {
+                send_to_peer(peer, Type::HostAdd, None, &Data::DynamicHost(host)).await?
+            }
+            Change::Remove(id) => {
+                send_to_peer(peer, Type::HostRemove, None, &Data::Host(id)).await?
+            }
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,201 @@
+use crate::{config::Check, history::CheckError};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{
+        ring::default_provider, verify_tls12_signature, verify_tls13_signature,
+        WebPkiSupportedAlgorithms,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, Error as TlsError, SignatureScheme,
+};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+use tokio_rustls::TlsConnector;
+
+/// Maximum size of the status line of an HTTP response.
+const MAX_STATUS_LINE: usize = 1024;
+
+/// Verifier that accepts any server certificate but checks the handshake
+/// signatures; the checks test the availability of a host, not its
+/// identity.
+#[derive(Debug)]
+struct AnyCertificate(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+/// Returns a TLS connector that accepts any server certificate.
+fn connector() -> TlsConnector {
+    let provider = default_provider();
+    let client = ClientConfig::builder_with_provider(Arc::new(provider.clone()))
+        .with_safe_default_protocol_versions()
+        .expect("default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(
+            provider.signature_verification_algorithms,
+        )))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(client))
+}
+
+/// Check a host address with the check method of its table.
+///
+/// Tables without a check and ICMP checks only connect to the host.  The
+/// HTTP and HTTPS checks send a `GET` request and compare the status code;
+/// the page digest is not checked.
+pub async fn check(
+    addr: SocketAddr,
+    host: &str,
+    check: Option<&Check>,
+    timeout: Duration,
+) -> Result<(), CheckError> {
+    let result = time::timeout(timeout, async {
+        let stream = TcpStream::connect(addr).await.map_err(error)?;
+        match check {
+            Some(Check::Http { path, code }) => http(stream, host, path, *code).await,
+            Some(Check::Https { path, code }) => {
+                let name = ServerName::try_from(host.to_string())
+                    .unwrap_or_else(|_| ServerName::from(addr.ip()));
+                let stream = connector().connect(name, stream).await.map_err(error)?;
+                http(stream, host, path, *code).await
+            }
+            Some(Check::Icmp) | Some(Check::Tcp) | None => Ok(()),
+        }
+    })
+    .await;
+    result.unwrap_or(Err(CheckError::Timeout))
+}
+
+/// Send an HTTP request and compare the status code of the response.
+async fn http<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    host: &str,
+    path: &str,
+    code: u16,
+) -> Result<(), CheckError> {
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: relayd\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).await.map_err(error)?;
+
+    let mut response = vec![];
+    let mut buf = [0u8; 512];
+    while !response.contains(&b'\n') && response.len() < MAX_STATUS_LINE {
+        match stream.read(&mut buf).await.map_err(error)? {
+            0 => break,
+            len => response.extend_from_slice(&buf[..len]),
+        }
+    }
+
+    match status(&response) {
+        Some(status) if status == code => Ok(()),
+        Some(status) => Err(CheckError::BadCode(status)),
+        None => Err(CheckError::Error("invalid response".to_string())),
+    }
+}
+
+/// Parse the status code of an HTTP response.
+fn status(response: &[u8]) -> Option<u16> {
+    let line = response.split(|&ch| ch == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?;
+    let mut fields = line.split_whitespace();
+    fields
+        .next()
+        .filter(|version| version.starts_with("HTTP/"))?;
+    fields.next()?.parse().ok()
+}
+
+fn error(err: io::Error) -> CheckError {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => CheckError::Refused,
+        _ => CheckError::Error(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            for response in [
+                "HTTP/1.1 200 OK\r\n\r\n",
+                "HTTP/1.0 503 Unavailable\r\n\r\n",
+            ] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let len = stream.read(&mut buf).await.unwrap();
+                assert!(buf[..len].starts_with(b"GET /health HTTP/1.0\r\nHost: www\r\n"));
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            // The connection is accepted but never answered.
+            let _stream = listener.accept().await.unwrap();
+            time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let http = Check::Http {
+            path: "/health".to_string(),
+            code: 200,
+        };
+        let timeout = Duration::from_secs(1);
+        assert_eq!(check(addr, "www", Some(&http), timeout).await, Ok(()));
+        assert_eq!(
+            check(addr, "www", Some(&http), timeout).await,
+            Err(CheckError::BadCode(503))
+        );
+        assert_eq!(
+            check(addr, "www", Some(&http), Duration::from_millis(100)).await,
+            Err(CheckError::Timeout)
+        );
+        server.abort();
+        let _ = server.await;
+
+        assert_eq!(
+            check(addr, "www", Some(&Check::Tcp), timeout).await,
+            Err(CheckError::Refused)
+        );
+        assert_eq!(status(b"HTTP/1.1 204 No Content\r\n"), Some(204));
+        assert_eq!(status(b"SSH-2.0-OpenSSH\r\n"), None);
+    }
+}
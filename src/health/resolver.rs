@@ -0,0 +1,432 @@
+use crate::config::{Config, DynamicHost, Host, Id, TableSource};
+use futures::future::join_all;
+use privsep_log::{debug, warn};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::time::Instant;
+use trust_dns_resolver::{
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
+
+/// Minimum time before a host name is resolved again.
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+
+/// Change of the resolved addresses of a host name.
+#[derive(Debug)]
+pub enum Change {
+    Add(DynamicHost),
+    Remove(Id),
+}
+
+/// Resolved addresses of a host name.
+#[derive(Debug)]
+struct Resolved {
+    /// Name that was resolved, to detect renamed hosts on reload.
+    name: String,
+    members: Vec<DynamicHost>,
+    /// When the addresses have to be resolved again.
+    refresh: Instant,
+}
+
+/// Expands the host names of the tables into dynamic hosts.
+///
+/// Every resolved address becomes a dynamic host that keeps its Id as
+/// long as the name resolves to the address.
+pub struct Resolver {
+    resolver: TokioAsyncResolver,
+    hosts: HashMap<Id, Resolved>,
+}
+
+impl Resolver {
+    pub fn new(nameservers: &[SocketAddr]) -> io::Result<Self> {
+        let resolver = if nameservers.is_empty() {
+            TokioAsyncResolver::tokio_from_system_conf()?
+        } else {
+            let mut config = ResolverConfig::new();
+            for addr in nameservers {
+                for protocol in [Protocol::Udp, Protocol::Tcp] {
+                    config.add_name_server(NameServerConfig {
+                        socket_addr: *addr,
+                        protocol,
+                        tls_dns_name: None,
+                        trust_nx_responses: true,
+                    });
+                }
+            }
+            TokioAsyncResolver::tokio(config, ResolverOpts::default())?
+        };
+
+        Ok(Self {
+            resolver,
+            hosts: HashMap::new(),
+        })
+    }
+
+    /// Returns the dynamic hosts of a host name.
+    ///
+    /// Hosts that are configured by address don't have dynamic hosts.
+    pub fn members(&self, host: &Host) -> Option<&[DynamicHost]> {
+        self.hosts
+            .get(&host.id)
+            .map(|resolved| resolved.members.as_slice())
+    }
+
+    /// Resolve the expired host names and return the changes.
+    ///
+    /// The addresses are resolved again after their TTL expired, but not
+    /// later than the check interval.
+    pub async fn update(&mut self, config: &Config) -> Vec<Change> {
+        let mut changes = vec![];
+        let hosts = config
+            .tables
+            .iter()
+            .flat_map(|table| {
+                let srv = matches!(table.source, Some(TableSource::Srv(_)));
+                table.hosts.iter().map(move |host| (host, srv))
+            })
+            .filter(|(host, srv)| *srv || host.name.parse::<IpAddr>().is_err())
+            .collect::<Vec<_>>();
+
+        // Remove the hosts that were removed or renamed on reload.
+        self.hosts.retain(|id, resolved| {
+            let keep = hosts
+                .iter()
+                .any(|(host, _)| host.id == *id && host.name == resolved.name);
+            if !keep {
+                changes.extend(resolved.members.drain(..).map(|m| Change::Remove(m.id)));
+            }
+            keep
+        });
+
+        let now = Instant::now();
+        let expired = hosts
+            .into_iter()
+            .filter(|(host, _)| {
+                self.hosts
+                    .get(&host.id)
+                    .map(|resolved| resolved.refresh <= now)
+                    .unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+        let resolver = &self.resolver;
+        let results = join_all(
+            expired
+                .into_iter()
+                .map(|(host, srv)| async move { (host, lookup(resolver, &host.name, srv).await) }),
+        )
+        .await;
+
+        for (host, result) in results {
+            let resolved = self.hosts.entry(host.id).or_insert_with(|| Resolved {
+                name: host.name.clone(),
+                members: vec![],
+                refresh: now,
+            });
+
+            let (addrs, valid_until) = match result {
+                Ok(result) => result,
+                Err(err) if is_nxdomain(&err) => {
+                    warn!("host {} has no addresses", host.name);
+                    (vec![], now + config.interval)
+                }
+                // Keep the previous addresses if the name server is unavailable.
+                Err(err) => {
+                    warn!("failed to resolve host {}: {}", host.name, err);
+                    resolved.refresh = now + config.interval;
+                    continue;
+                }
+            };
+            resolved.refresh = refresh(now, valid_until, config.interval);
+
+            resolved.members.retain(|member| {
+                let keep = addrs.iter().any(|addr| addr.matches(member));
+                if !keep {
+                    debug!("host {}: removed address {}", host.name, member.addr);
+                    changes.push(Change::Remove(member.id));
+                }
+                keep
+            });
+            for addr in addrs {
+                if !resolved.members.iter().any(|member| addr.matches(member)) {
+                    debug!("host {}: added address {}", host.name, addr.addr);
+                    let member = DynamicHost {
+                        port: addr.port,
+                        priority: addr.priority,
+                        weight: addr.weight,
+                        ..DynamicHost::new(host.id, addr.addr)
+                    };
+                    changes.push(Change::Add(member.clone()));
+                    resolved.members.push(member);
+                }
+            }
+        }
+
+        changes
+    }
+}
+
+/// Returns when the addresses have to be resolved again.
+///
+/// This is after the TTL expired, but not sooner than `MIN_REFRESH` and not
+/// later than the check interval.
+fn refresh(now: Instant, valid_until: Instant, interval: Duration) -> Instant {
+    let ttl = valid_until.saturating_duration_since(now);
+    now + ttl.clamp(MIN_REFRESH, interval.max(MIN_REFRESH))
+}
+
+/// Resolve the addresses of a host name or the SRV records of a service.
+async fn lookup(
+    resolver: &TokioAsyncResolver,
+    name: &str,
+    srv: bool,
+) -> Result<(Vec<Address>, Instant), ResolveError> {
+    if !srv {
+        let lookup = resolver.lookup_ip(name).await?;
+        let addrs = lookup.iter().map(Address::from).collect();
+        return Ok((addrs, lookup.valid_until().into()));
+    }
+
+    let lookup = resolver.srv_lookup(name).await?;
+    let mut valid_until = lookup.as_lookup().valid_until();
+    let mut addrs = vec![];
+    for srv in lookup.iter() {
+        let ips = match resolver.lookup_ip(srv.target().clone()).await {
+            Ok(ips) => ips,
+            Err(err) if is_nxdomain(&err) => continue,
+            Err(err) => return Err(err),
+        };
+        valid_until = valid_until.min(ips.valid_until());
+        addrs.extend(ips.iter().map(|ip| Address {
+            port: Some(srv.port()),
+            priority: Some(srv.priority().min(u8::MAX.into()) as u8),
+            weight: srv.weight(),
+            ..Address::from(ip)
+        }));
+    }
+
+    Ok((addrs, valid_until.into()))
+}
+
+/// Resolved address of a host name or SRV record.
+#[derive(Debug)]
+struct Address {
+    addr: IpAddr,
+    port: Option<u16>,
+    priority: Option<u8>,
+    weight: u16,
+}
+
+impl Address {
+    /// Returns true if the dynamic host was resolved from the same record.
+    fn matches(&self, host: &DynamicHost) -> bool {
+        self.addr == host.addr
+            && self.port == host.port
+            && self.priority == host.priority
+            && self.weight == host.weight
+    }
+}
+
+impl From<IpAddr> for Address {
+    fn from(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            port: None,
+            priority: None,
+            weight: 0,
+        }
+    }
+}
+
+/// Returns true if the name does not exist or has no records.
+fn is_nxdomain(err: &ResolveError) -> bool {
+    matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::logger;
+    use std::{
+        str::FromStr,
+        sync::{Arc, Mutex},
+    };
+    use tokio::net::UdpSocket;
+    use trust_dns_resolver::proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{rdata::SRV, Name, RData, Record},
+    };
+
+    /// Answer DNS queries from a list of records on loopback.
+    async fn stub_server(records: Arc<Mutex<Vec<Record>>>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_available(true);
+                for query in request.queries() {
+                    response.add_query(query.clone());
+                    let answers = records
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|r| r.name() == query.name() && r.rr_type() == query.query_type())
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    if answers.is_empty() {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                    response.add_answers(answers);
+                }
+                let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+            }
+        });
+
+        addr
+    }
+
+    fn record(name: &str, rdata: RData) -> Record {
+        Record::from_rdata(Name::from_str(name).unwrap(), 0, rdata)
+    }
+
+    #[test]
+    fn test_resolver_refresh() {
+        let now = Instant::now();
+        let interval = Duration::from_secs(10);
+        let ttl = Duration::from_secs(5);
+
+        assert_eq!(refresh(now, now + ttl, interval), now + ttl);
+        // Expired or short TTLs don't resolve the names in a loop.
+        assert_eq!(refresh(now, now, interval), now + MIN_REFRESH);
+        // Long TTLs are limited by the check interval.
+        assert_eq!(refresh(now, now + interval * 10, interval), now + interval);
+        assert_eq!(
+            refresh(now, now + ttl, Duration::from_millis(100)),
+            now + MIN_REFRESH
+        );
+    }
+
+    fn a(name: &str, addr: &str) -> Record {
+        record(name, RData::A(addr.parse().unwrap()))
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> Record {
+        let target = Name::from_str(target).unwrap();
+        let rdata = RData::SRV(SRV::new(priority, weight, port, target));
+        record("_http._tcp.service.internal.", rdata)
+    }
+
+    #[tokio::test]
+    async fn test_resolver() {
+        logger();
+        let records = Arc::new(Mutex::new(vec![
+            srv(10, 5, 8080, "a.service.internal."),
+            srv(20, 1, 8081, "b.service.internal."),
+            a("a.service.internal.", "127.0.0.1"),
+            a("b.service.internal.", "127.0.0.2"),
+            a("www.service.internal.", "127.0.0.3"),
+            a("www.service.internal.", "127.0.0.4"),
+        ]));
+        let nameserver = stub_server(records.clone()).await;
+
+        let config = Config::parse(
+            "relayd.conf",
+            "table <web> srv \"_http._tcp.service.internal.\"\n\
+             table <app> { www.service.internal. }\n\
+             relay www {\n\
+             \tlisten on 127.0.0.1 port 8080\n\
+             \tforward to <web>\n\
+             \tforward to <app> port 80\n\
+             }\n",
+            Default::default(),
+        )
+        .unwrap();
+        let web = &config.tables[0].hosts[0];
+        let app = &config.tables[1].hosts[0];
+
+        let mut resolver = Resolver::new(&[nameserver]).unwrap();
+        let changes = resolver.update(&config).await;
+        assert_eq!(changes.len(), 4);
+
+        let mut members = resolver
+            .members(web)
+            .unwrap()
+            .iter()
+            .map(|m| (m.addr.to_string(), m.port, m.priority, m.weight))
+            .collect::<Vec<_>>();
+        members.sort();
+        assert_eq!(
+            members,
+            [
+                ("127.0.0.1".to_string(), Some(8080), Some(10), 5),
+                ("127.0.0.2".to_string(), Some(8081), Some(20), 1),
+            ]
+        );
+        let old = resolver.members(app).unwrap().to_vec();
+        assert_eq!(old.len(), 2);
+        assert!(old.iter().all(|m| m.parent == app.id && m.port.is_none()));
+
+        // The names are not resolved again before the TTL expired.
+        records
+            .lock()
+            .unwrap()
+            .push(a("www.service.internal.", "127.0.0.6"));
+        assert!(resolver.update(&config).await.is_empty());
+        assert!(resolver
+            .hosts
+            .values()
+            .all(|resolved| resolved.refresh > Instant::now()));
+        records.lock().unwrap().pop();
+
+        // Replace one address; the remaining address keeps its Id.
+        records
+            .lock()
+            .unwrap()
+            .retain(|r| r.rdata() != &RData::A("127.0.0.4".parse().unwrap()));
+        records
+            .lock()
+            .unwrap()
+            .push(a("www.service.internal.", "127.0.0.5"));
+        let expire = |resolver: &mut Resolver| {
+            for resolved in resolver.hosts.values_mut() {
+                resolved.refresh = Instant::now();
+            }
+        };
+        expire(&mut resolver);
+        let changes = resolver.update(&config).await;
+        let removed = old.iter().find(|m| m.addr.to_string() == "127.0.0.4");
+        assert!(matches!(
+            changes.as_slice(),
+            [Change::Remove(id), Change::Add(host)]
+                if Some(*id) == removed.map(|m| m.id) && host.addr.to_string() == "127.0.0.5"
+        ));
+        let kept = old
+            .iter()
+            .find(|m| m.addr.to_string() == "127.0.0.3")
+            .unwrap();
+        assert!(resolver.members(app).unwrap().contains(kept));
+
+        // Names that do not exist anymore lose all addresses.
+        records
+            .lock()
+            .unwrap()
+            .retain(|r| r.name() != &Name::from_str("www.service.internal.").unwrap());
+        expire(&mut resolver);
+        let changes = resolver.update(&config).await;
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|c| matches!(c, Change::Remove(_))));
+        assert!(resolver.members(app).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+use crate::{config::Id, Timestamp};
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt, io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+/// Reason why a host check failed.
+#[derive(Clone, Debug, Display, Deserialize, Serialize, PartialEq)]
+pub enum CheckError {
+    #[display(fmt = "timeout")]
+    Timeout,
+    #[display(fmt = "connection refused")]
+    Refused,
+    #[display(fmt = "failed to resolve")]
+    Resolve,
+    #[display(fmt = "bad code {}", "_0")]
+    BadCode(u16),
+    #[display(fmt = "{}", "_0")]
+    Error(String),
+}
+
+/// Change of the state of a host.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Transition {
+    pub time: SystemTime,
+    pub id: Id,
+    pub table: String,
+    pub host: String,
+    /// Address that was checked.
+    pub address: String,
+    /// Previous state, unknown after startup.
+    pub old: Option<bool>,
+    pub new: bool,
+    /// Why the check failed.
+    pub reason: Option<CheckError>,
+}
+
+fn state(up: Option<bool>) -> &'static str {
+    match up {
+        Some(true) => "up",
+        Some(false) => "down",
+        None => "unknown",
+    }
+}
+
+fn parse_state(s: &str) -> Option<Option<bool>> {
+    match s {
+        "up" => Some(Some(true)),
+        "down" => Some(Some(false)),
+        "unknown" => Some(None),
+        _ => None,
+    }
+}
+
+impl Transition {
+    /// Parse a line of the history file.
+    fn parse(line: &str) -> Option<Self> {
+        let fields = line.splitn(7, '\t').collect::<Vec<_>>();
+        if fields.len() < 6 {
+            return None;
+        }
+        let reason = match fields.get(6) {
+            None | Some(&"") => None,
+            Some(&"timeout") => Some(CheckError::Timeout),
+            Some(&"connection refused") => Some(CheckError::Refused),
+            Some(&"failed to resolve") => Some(CheckError::Resolve),
+            Some(reason) => Some(
+                reason
+                    .strip_prefix("bad code ")
+                    .and_then(|code| code.parse().ok())
+                    .map(CheckError::BadCode)
+                    .unwrap_or_else(|| CheckError::Error(reason.to_string())),
+            ),
+        };
+
+        Some(Self {
+            time: UNIX_EPOCH + Duration::from_secs(fields[0].parse().ok()?),
+            id: 0,
+            table: fields[1].to_string(),
+            host: fields[2].to_string(),
+            address: fields[3].to_string(),
+            old: parse_state(fields[4])?,
+            new: parse_state(fields[5])??,
+            reason,
+        })
+    }
+
+    /// Format the transition as a line of the history file.
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            self.table,
+            self.host,
+            self.address,
+            state(self.old),
+            state(Some(self.new)),
+            self.reason
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default()
+        )
+    }
+}
+
+/// The latest state transitions of all hosts.
+#[derive(Debug, Default)]
+pub struct History {
+    transitions: VecDeque<Transition>,
+}
+
+impl History {
+    /// Add a transition, forgetting the oldest ones.
+    pub fn push(&mut self, transition: Transition) {
+        if self.transitions.len() >= crate::HISTORY_SIZE {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(transition);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transition> {
+        self.transitions.iter()
+    }
+
+    /// Load the history file and write it back without the old entries.
+    pub async fn load(path: &Path) -> io::Result<Self> {
+        let mut history = Self::default();
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(history),
+            Err(err) => return Err(err),
+        };
+        for transition in content.lines().filter_map(Transition::parse) {
+            history.push(transition);
+        }
+
+        let content = history.iter().map(Transition::to_line).collect::<String>();
+        fs::write(path, content).await?;
+
+        Ok(history)
+    }
+
+    /// Append a transition to the history file.
+    pub async fn append(path: &Path, transition: &Transition) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .await?;
+        file.write_all(transition.to_line().as_bytes()).await?;
+        // Tokio writes in the background, wait until the line is written.
+        file.flush().await
+    }
+
+    /// Returns the last known state of every host by table and host name.
+    pub fn last_states(&self) -> impl Iterator<Item = (&str, &str, bool)> {
+        let mut states = Vec::<(&str, &str, bool)>::new();
+        for transition in self.iter() {
+            let key = (transition.table.as_str(), transition.host.as_str());
+            states.retain(|(table, host, _)| (*table, *host) != key);
+            states.push((key.0, key.1, transition.new));
+        }
+        states.into_iter()
+    }
+}
+
+impl fmt::Display for History {
+    /// Print the history like `relayctl show hosts history`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<22}{:<16}{:<24}{:<24}{:<18}Reason",
+            "Time", "Table", "Host", "Address", "State"
+        )?;
+        for transition in self.iter() {
+            writeln!(
+                f,
+                "{:<22}{:<16}{:<24}{:<24}{:<18}{}",
+                Timestamp(transition.time, true).to_string(),
+                transition.table,
+                transition.host,
+                transition.address,
+                format!(
+                    "{} -> {}",
+                    state(transition.old),
+                    state(Some(transition.new))
+                ),
+                transition
+                    .reason
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_history_file() {
+        let path = std::env::temp_dir().join(format!("relayd-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let transition = |secs, host: &str, old, new, reason| Transition {
+            time: UNIX_EPOCH + Duration::from_secs(secs),
+            id: 0,
+            table: "web".to_string(),
+            host: host.to_string(),
+            address: format!("{}:80", host),
+            old,
+            new,
+            reason,
+        };
+        let transitions = [
+            transition(1000, "10.0.0.1", None, true, None),
+            transition(
+                1010,
+                "10.0.0.2",
+                None,
+                false,
+                Some(CheckError::BadCode(503)),
+            ),
+            transition(1015, "10.0.0.3", None, false, Some(CheckError::Refused)),
+            transition(
+                1020,
+                "10.0.0.1",
+                Some(true),
+                false,
+                Some(CheckError::Timeout),
+            ),
+        ];
+        for transition in &transitions {
+            History::append(&path, transition).await.unwrap();
+        }
+        std::fs::write(
+            &path,
+            std::fs::read_to_string(&path).unwrap() + "invalid line\n",
+        )
+        .unwrap();
+
+        let history = History::load(&path).await.unwrap();
+        assert_eq!(history.iter().cloned().collect::<Vec<_>>(), transitions);
+        assert_eq!(
+            history.last_states().collect::<Vec<_>>(),
+            [
+                ("web", "10.0.0.2", false),
+                ("web", "10.0.0.3", false),
+                ("web", "10.0.0.1", false)
+            ]
+        );
+        assert_eq!(
+            history.to_string().lines().nth(4).unwrap(),
+            "1970-01-01T00:17:00Z  web             10.0.0.1                10.0.0.1:80             \
+             up -> down        timeout"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,7 +1,10 @@
+mod ca;
 mod config;
 mod error;
 mod health;
+mod history;
 mod message;
+mod metrics;
 mod options;
 mod parent;
 mod redirect;
@@ -10,10 +13,15 @@ mod relay;
 use crate::config::Config;
 use arc_swap::ArcSwap;
 use privsep_derive::Privsep;
-use std::{sync::Arc, time::Duration};
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 pub use {
     error::Error,
     options::Options,
+    parent::configtest,
     privsep::process::{Child, Parent},
 };
 
@@ -24,12 +32,15 @@ pub enum Privsep {
     /// Parent process.
     Parent,
     /// Health Check Engine
-    #[connect(Relay, Redirect)]
     Health,
     /// Packet Filter Engine
     Redirect,
     /// L7 Relays
     Relay,
+    /// Certificate Authority: TLS private keys
+    Ca,
+    /// Metrics exporter
+    Metrics,
 }
 
 /// Child context
@@ -39,6 +50,55 @@ struct Context<const N: usize> {
     pub child: Arc<Child<N>>,
 }
 
+/// UTC time in the common log format or RFC 3339.
+struct Timestamp(pub SystemTime, pub bool);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+
+        let secs = self
+            .0
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (days, secs) = (secs / 86400, secs % 86400);
+        let (hour, min, sec) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+        // Convert days since the epoch to a civil date.
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        if self.1 {
+            write!(
+                f,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                year, month, day, hour, min, sec
+            )
+        } else {
+            write!(
+                f,
+                "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+                day,
+                MONTHS[month as usize - 1],
+                year,
+                hour,
+                min,
+                sec
+            )
+        }
+    }
+}
+
 /// Default configuration path.
 const RELAYD_CONFIG: &str = "/etc/relayd.conf";
 /// Default control socket path.
@@ -51,10 +111,65 @@ const RELAYD_SERVERNAME: &str = "relayd-rs";
 const CHECK_TIMEOUT: Duration = Duration::from_millis(200);
 /// Default health check interval.
 const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Default timeout to drain relay sessions on shutdown.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delay before accepting again after a failed accept, eg. out of files.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Number of host state transitions that are kept in the history.
+const HISTORY_SIZE: usize = 1024;
+/// Interval to report statistics to the metrics process.
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Directory of the TLS certificates.
+const TLS_CERTS: &str = "/etc/ssl";
+/// Directory of the TLS private keys.
+const TLS_KEYS: &str = "/etc/ssl/private";
+/// Timeout of the relays waiting for a signature of the CA process.
+const SIGN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Initial delay before restarting a crashed child process.
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+/// Crashes within this interval count towards the restart limit.
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+/// Default number of restarts before the daemon gives up.
+const RESTART_LIMIT: u32 = 5;
+
+/// Default number of relay processes.
+const RELAY_PREFORK: usize = 1;
+/// Maximum number of relay processes.
+const PREFORK_MAX: usize = 32;
+
+/// Name of the relayd nftables table.
+const NFT_TABLE: &str = "relayd";
+/// Command to apply nftables rulesets.
+const NFT_COMMAND: &str = "/usr/sbin/nft";
+
+/// Command to delete tracked connections.
+const CONNTRACK_COMMAND: &str = "/usr/sbin/conntrack";
+/// Command to load PF rules.
+const PFCTL_COMMAND: &str = "/sbin/pfctl";
 
 /// Default PF socket.
 #[allow(unused)]
 const PF_SOCKET: &str = "/dev/pf";
 /// Default relayd PF anchor.
-#[allow(unused)]
 const PF_RELAYD_ANCHOR: &str = "relayd";
+
+#[cfg(test)]
+mod tests {
+    /// Set up the logger once, dropping the guard would race with other tests.
+    pub fn logger() {
+        static LOGGER: std::sync::Once = std::sync::Once::new();
+        LOGGER.call_once(|| {
+            let guard = privsep_log::sync_logger(
+                "test",
+                privsep_log::Config {
+                    foreground: true,
+                    filter: Some("trace".to_string()),
+                },
+            )
+            .unwrap();
+            std::mem::forget(guard);
+        });
+    }
+}
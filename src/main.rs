@@ -1,4 +1,4 @@
-use relayd::{Options, Privsep};
+use relayd::{configtest, Options, Privsep};
 use std::{env, process};
 
 #[tokio::main]
@@ -10,6 +10,14 @@ async fn main() {
         Err(_) => process::exit(1),
     };
 
+    if matches.opt_present("n") {
+        if let Err(err) = configtest(&matches).await {
+            eprintln!("{}: {}", opts, err);
+            process::exit(1);
+        }
+        return;
+    }
+
     let log_level = env::var("RUST_LOG")
         .unwrap_or_else(|_| privsep_log::verbose(matches.opt_count("v")))
         .into();
@@ -1,8 +1,14 @@
-use crate::config::{Config, Id};
+use crate::{
+    ca::{Keypair, Sign, Signature},
+    config::{Config, DynamicHost, Id},
+    history::Transition,
+    metrics::Stats,
+    redirect::{RedirectHosts, Route, States},
+};
 use derive_more::Display;
 use privsep::imsg::Message;
 use serde::{Deserialize, Serialize};
-use std::borrow::Cow;
+use std::{borrow::Cow, net::SocketAddr};
 
 #[derive(Clone, Copy, Debug, Display)]
 #[repr(u32)]
@@ -11,10 +17,36 @@ pub enum Type {
     Config = Message::RESERVED + 1,
     /// Start process operation
     Start,
+    /// Send the access log file
+    LogFile,
+    /// Send a listener socket
+    Listener,
+    /// Send a TLS keypair
+    Keypair,
+    /// Sign a message with a private key
+    Sign,
+    /// Signature of a signing request
+    Signature,
     /// Host is up
     HostUp,
     /// Host is down
     HostDown,
+    /// Address of a host name was added
+    HostAdd,
+    /// Address of a host name was removed
+    HostRemove,
+    /// State of a host changed
+    HostChange,
+    /// Hosts of the redirects that are up
+    RedirectHosts,
+    /// Flush the connection states of a host
+    FlushStates,
+    /// Install the routes of the routers
+    Routes,
+    /// Statistics for the metrics exporter
+    Stats,
+    /// Shut down the process
+    Shutdown,
     /// Unknown message
     Unknown,
 }
@@ -22,8 +54,21 @@ pub enum Type {
 impl Type {
     pub const CONFIG: u32 = Self::Config as u32;
     pub const START: u32 = Self::Start as u32;
+    pub const LOG_FILE: u32 = Self::LogFile as u32;
+    pub const LISTENER: u32 = Self::Listener as u32;
+    pub const KEYPAIR: u32 = Self::Keypair as u32;
+    pub const SIGN: u32 = Self::Sign as u32;
+    pub const SIGNATURE: u32 = Self::Signature as u32;
     pub const HOST_UP: u32 = Self::HostUp as u32;
     pub const HOST_DOWN: u32 = Self::HostDown as u32;
+    pub const HOST_ADD: u32 = Self::HostAdd as u32;
+    pub const HOST_REMOVE: u32 = Self::HostRemove as u32;
+    pub const HOST_CHANGE: u32 = Self::HostChange as u32;
+    pub const REDIRECT_HOSTS: u32 = Self::RedirectHosts as u32;
+    pub const FLUSH_STATES: u32 = Self::FlushStates as u32;
+    pub const ROUTES: u32 = Self::Routes as u32;
+    pub const STATS: u32 = Self::Stats as u32;
+    pub const SHUTDOWN: u32 = Self::Shutdown as u32;
 }
 
 impl From<u32> for Type {
@@ -31,8 +76,21 @@ impl From<u32> for Type {
         match id {
             Type::CONFIG => Self::Config,
             Type::START => Self::Start,
+            Type::LOG_FILE => Self::LogFile,
+            Type::LISTENER => Self::Listener,
+            Type::KEYPAIR => Self::Keypair,
+            Type::SIGN => Self::Sign,
+            Type::SIGNATURE => Self::Signature,
             Type::HOST_UP => Self::HostUp,
             Type::HOST_DOWN => Self::HostDown,
+            Type::HOST_ADD => Self::HostAdd,
+            Type::HOST_REMOVE => Self::HostRemove,
+            Type::HOST_CHANGE => Self::HostChange,
+            Type::REDIRECT_HOSTS => Self::RedirectHosts,
+            Type::FLUSH_STATES => Self::FlushStates,
+            Type::ROUTES => Self::Routes,
+            Type::STATS => Self::Stats,
+            Type::SHUTDOWN => Self::Shutdown,
             _ => Self::Unknown,
         }
     }
@@ -45,10 +103,21 @@ impl From<Type> for Message {
 }
 
 /// Internal message data
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Data<'a> {
     Config(Cow<'a, Config>),
     Host(Id),
+    DynamicHost(DynamicHost),
+    Transition(Transition),
+    Listener(SocketAddr),
+    Keypair(Keypair),
+    Sign(Sign),
+    Signature(Signature),
+    RedirectHosts(Vec<RedirectHosts>),
+    States(States),
+    Routes(Vec<Route>),
+    Stats(Stats),
     None,
 }
 
@@ -0,0 +1,625 @@
+use crate::{
+    config::{Config, Id},
+    error::Error,
+    message::{Data, Type},
+    parent::default_handler,
+    Child, Privsep,
+};
+use privsep_log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time,
+};
+
+/// Maximum size of an HTTP request.
+const MAX_REQUEST: usize = 8192;
+/// Timeout to receive an HTTP request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Statistics that are sent to the metrics process.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Stats {
+    Hosts(Vec<HostStats>),
+    Relays(Vec<RelayStats>),
+    Redirects(Vec<RedirectStats>),
+    Processes(Vec<ProcessStats>),
+}
+
+/// Result of the last health check of a host.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HostStats {
+    pub id: Id,
+    pub table: String,
+    pub host: String,
+    /// Address that was checked.
+    pub address: String,
+    pub up: bool,
+    /// Duration of the check.
+    pub latency: Duration,
+}
+
+/// Counters of a relay.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RelayStats {
+    pub id: Id,
+    pub name: String,
+    /// Accepted connections.
+    pub sessions: RollingStats,
+    /// Sessions by backend host.
+    pub hosts: Vec<HostSessions>,
+    /// Number of active sessions.
+    pub active: u64,
+    /// Bytes received from the clients.
+    pub bytes_in: u64,
+    /// Bytes sent to the clients.
+    pub bytes_out: u64,
+    /// Number of failed sessions.
+    pub errors: u64,
+}
+
+impl RelayStats {
+    /// Returns the session counters of a backend host.
+    pub fn host(&mut self, id: Id, name: &str) -> &mut RollingStats {
+        let i = match self.hosts.iter().position(|host| host.id == id) {
+            Some(i) => i,
+            None => {
+                self.hosts.push(HostSessions {
+                    id,
+                    name: name.to_string(),
+                    sessions: Default::default(),
+                });
+                self.hosts.len() - 1
+            }
+        };
+        &mut self.hosts[i].sessions
+    }
+
+    /// Finish the current interval of all session counters.
+    pub fn tick(&mut self) {
+        self.sessions.tick();
+        for host in &mut self.hosts {
+            host.sessions.tick();
+        }
+    }
+
+    /// Add the counters of the same relay in another relay process.
+    pub fn merge(&mut self, other: &Self) {
+        self.sessions.merge(&other.sessions);
+        for host in &other.hosts {
+            self.host(host.id, &host.name).merge(&host.sessions);
+        }
+        self.active += other.active;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.errors += other.errors;
+    }
+}
+
+/// Merge the statistics of all relay processes by the relay Id.
+pub fn merge_relays<'a>(stats: impl IntoIterator<Item = &'a [RelayStats]>) -> Vec<RelayStats> {
+    let mut merged = Vec::<RelayStats>::new();
+    for relay in stats.into_iter().flatten() {
+        match merged.iter_mut().find(|merged| merged.id == relay.id) {
+            Some(merged) => merged.merge(relay),
+            None => merged.push(relay.clone()),
+        }
+    }
+    merged
+}
+
+/// Sessions of a relay to a backend host.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HostSessions {
+    pub id: Id,
+    /// Address of the host.
+    pub name: String,
+    pub sessions: RollingStats,
+}
+
+/// Rolling session counters, like `struct ctl_stats` of relayd.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct RollingStats {
+    /// Total number of sessions.
+    pub total: u64,
+    /// Sessions in the current interval.
+    pub current: u64,
+    /// Sessions in the last interval.
+    pub last: u64,
+    /// Maximum sessions in an interval.
+    pub max: u64,
+    /// Number of finished intervals.
+    pub ticks: u64,
+}
+
+impl RollingStats {
+    /// Count a new session.
+    pub fn count(&mut self) {
+        self.total += 1;
+        self.current += 1;
+    }
+
+    /// Finish the current interval.
+    pub fn tick(&mut self) {
+        self.last = self.current;
+        self.max = self.max.max(self.current);
+        self.current = 0;
+        self.ticks += 1;
+    }
+
+    /// Add the counters of another relay process.
+    ///
+    /// The processes finish their intervals at the same rate; the maximum
+    /// is the sum of the maximums of the processes.
+    pub fn merge(&mut self, other: &Self) {
+        self.total += other.total;
+        self.current += other.current;
+        self.last += other.last;
+        self.max += other.max;
+        self.ticks = self.ticks.max(other.ticks);
+    }
+
+    /// Average sessions per finished interval.
+    pub fn avg(&self) -> f64 {
+        if self.ticks == 0 {
+            return 0.0;
+        }
+        (self.total - self.current) as f64 / self.ticks as f64
+    }
+
+    /// Sessions per second in the last interval.
+    pub fn rate(&self, interval: Duration) -> f64 {
+        self.last as f64 / interval.as_secs_f64()
+    }
+}
+
+/// State of a redirect.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RedirectStats {
+    pub id: Id,
+    pub name: String,
+    /// Number of hosts in the forwarding tables that are up.
+    pub hosts_up: usize,
+    /// Number of hosts in the forwarding tables.
+    pub hosts: usize,
+}
+
+/// State of a privsep process.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProcessStats {
+    pub name: String,
+    /// Instance of the relay processes, 0 for other processes.
+    pub instance: usize,
+    pub running: bool,
+    /// Number of restarts since the daemon started.
+    pub restarts: u64,
+}
+
+/// Latest statistics of all processes.
+#[derive(Debug, Default)]
+struct Metrics {
+    hosts: Vec<HostStats>,
+    relays: Vec<RelayStats>,
+    redirects: Vec<RedirectStats>,
+    processes: Vec<ProcessStats>,
+}
+
+impl Metrics {
+    fn update(&mut self, stats: Stats) {
+        match stats {
+            Stats::Hosts(hosts) => self.hosts = hosts,
+            Stats::Relays(relays) => self.relays = relays,
+            Stats::Redirects(redirects) => self.redirects = redirects,
+            Stats::Processes(processes) => self.processes = processes,
+        }
+    }
+}
+
+pub async fn main<const N: usize>(
+    child: Child<N>,
+    privsep_config: privsep::Config,
+) -> Result<(), privsep::Error> {
+    let _guard = privsep_log::async_logger(&child.to_string(), &privsep_config)
+        .await
+        .map_err(|err| privsep::Error::GeneralError(Box::new(err)))?;
+
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    let mut config = Config::default();
+    let mut listener: Option<(SocketAddr, JoinHandle<()>)> = None;
+    let mut started = false;
+
+    info!("Started");
+
+    loop {
+        let (message, _, data) = default_handler::<Data<'_>>(&child[Privsep::PARENT_ID]).await?;
+        match (Type::from(message.id), data) {
+            (Type::Config, Data::Config(new_config)) => {
+                trace!("received config: {:?}", new_config);
+                config = new_config.into_owned();
+
+                // Rebind the listener if the address changed on reload.
+                let addr = config.metrics.as_ref().map(|listen| listen.addr);
+                if started && listener.as_ref().map(|(addr, _)| *addr) != addr {
+                    if let Some((addr, listener)) = listener.take() {
+                        info!("metrics: stopped listening on {}", addr);
+                        listener.abort();
+                        let _ = listener.await;
+                    }
+                    listener = run(&config, &metrics).await;
+                }
+            }
+            (Type::Start, _) => {
+                trace!("received start command");
+                if !started {
+                    started = true;
+                    listener = run(&config, &metrics).await;
+                }
+            }
+            (Type::Stats, Data::Stats(stats)) => {
+                trace!("received stats");
+                metrics.lock().unwrap().update(stats);
+            }
+            (Type::Shutdown, _) => {
+                trace!("received shutdown command");
+                break;
+            }
+            _ => return Err(Error::InvalidMessage.into()),
+        }
+    }
+
+    if let Some((_, listener)) = listener {
+        listener.abort();
+    }
+
+    info!("Terminated");
+
+    Ok(())
+}
+
+/// Start the HTTP listener if the metrics are enabled.
+async fn run(
+    config: &Config,
+    metrics: &Arc<Mutex<Metrics>>,
+) -> Option<(SocketAddr, JoinHandle<()>)> {
+    let addr = config.metrics.as_ref()?.addr;
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("metrics: failed to listen on {}: {}", addr, err);
+            return None;
+        }
+    };
+    info!("metrics: listening on {}", addr);
+
+    let metrics = metrics.clone();
+    let handle = tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    warn!("metrics: accept failed: {}", err);
+                    time::sleep(crate::ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve(stream, &metrics).await {
+                    debug!("metrics: request from {} failed: {}", peer, err);
+                }
+            });
+        }
+    });
+    Some((addr, handle))
+}
+
+/// Answer a single HTTP request.
+async fn serve(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> std::io::Result<()> {
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let len = match time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await {
+            Ok(result) => result?,
+            Err(_) => return Ok(()),
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.lock().unwrap().to_string();
+            format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        (Some("GET"), _) => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into()
+        }
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .into(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Quote a label value.
+struct Label<'a>(&'a str);
+
+impl fmt::Display for Label<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('"')?;
+        for ch in self.0.chars() {
+            match ch {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                ch => f.write_char(ch)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// Name, type, help and value of a relay metric.
+type RelayCounter = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&RelayStats) -> u64,
+);
+
+/// Write the header of a metric family.
+fn family(f: &mut fmt::Formatter<'_>, name: &str, typ: &str, help: &str) -> fmt::Result {
+    writeln!(f, "# HELP {} {}", name, help)?;
+    writeln!(f, "# TYPE {} {}", name, typ)
+}
+
+impl fmt::Display for Metrics {
+    /// Print the metrics in the Prometheus text format.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        family(f, "relayd_info", "gauge", "Version of relayd.")?;
+        writeln!(
+            f,
+            "relayd_info{{version={}}} 1",
+            Label(env!("CARGO_PKG_VERSION"))
+        )?;
+
+        family(
+            f,
+            "relayd_process_up",
+            "gauge",
+            "Whether the process is running.",
+        )?;
+        for process in &self.processes {
+            writeln!(
+                f,
+                "relayd_process_up{{process={},instance=\"{}\"}} {}",
+                Label(&process.name),
+                process.instance,
+                u8::from(process.running)
+            )?;
+        }
+        family(
+            f,
+            "relayd_process_restarts_total",
+            "counter",
+            "Restarts of the process.",
+        )?;
+        for process in &self.processes {
+            writeln!(
+                f,
+                "relayd_process_restarts_total{{process={},instance=\"{}\"}} {}",
+                Label(&process.name),
+                process.instance,
+                process.restarts
+            )?;
+        }
+
+        family(f, "relayd_host_up", "gauge", "Whether the host is up.")?;
+        for host in &self.hosts {
+            writeln!(
+                f,
+                "relayd_host_up{{table={},host={},address={}}} {}",
+                Label(&host.table),
+                Label(&host.host),
+                Label(&host.address),
+                u8::from(host.up)
+            )?;
+        }
+        family(
+            f,
+            "relayd_host_check_duration_seconds",
+            "gauge",
+            "Duration of the last health check.",
+        )?;
+        for host in &self.hosts {
+            writeln!(
+                f,
+                "relayd_host_check_duration_seconds{{table={},host={},address={}}} {}",
+                Label(&host.table),
+                Label(&host.host),
+                Label(&host.address),
+                host.latency.as_secs_f64()
+            )?;
+        }
+
+        let relay_counters: [RelayCounter; 5] = [
+            (
+                "relayd_relay_connections_total",
+                "counter",
+                "Accepted connections.",
+                |relay| relay.sessions.total,
+            ),
+            (
+                "relayd_relay_sessions",
+                "gauge",
+                "Active sessions.",
+                |relay| relay.active,
+            ),
+            (
+                "relayd_relay_received_bytes_total",
+                "counter",
+                "Bytes received from the clients.",
+                |relay| relay.bytes_in,
+            ),
+            (
+                "relayd_relay_sent_bytes_total",
+                "counter",
+                "Bytes sent to the clients.",
+                |relay| relay.bytes_out,
+            ),
+            (
+                "relayd_relay_errors_total",
+                "counter",
+                "Failed sessions.",
+                |relay| relay.errors,
+            ),
+        ];
+        for (name, typ, help, value) in relay_counters {
+            family(f, name, typ, help)?;
+            for relay in &self.relays {
+                writeln!(
+                    f,
+                    "{}{{relay={}}} {}",
+                    name,
+                    Label(&relay.name),
+                    value(relay)
+                )?;
+            }
+        }
+
+        family(
+            f,
+            "relayd_redirect_hosts_up",
+            "gauge",
+            "Hosts of the redirect that are up.",
+        )?;
+        for redirect in &self.redirects {
+            writeln!(
+                f,
+                "relayd_redirect_hosts_up{{redirect={}}} {}",
+                Label(&redirect.name),
+                redirect.hosts_up
+            )?;
+        }
+        family(
+            f,
+            "relayd_redirect_hosts",
+            "gauge",
+            "Hosts of the redirect.",
+        )?;
+        for redirect in &self.redirects {
+            writeln!(
+                f,
+                "relayd_redirect_hosts{{redirect={}}} {}",
+                Label(&redirect.name),
+                redirect.hosts
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_format() {
+        let mut metrics = Metrics::default();
+        metrics.update(Stats::Hosts(vec![HostStats {
+            id: 1,
+            table: "web".to_string(),
+            host: "www.example.com".to_string(),
+            address: "10.0.0.1:80".to_string(),
+            up: true,
+            latency: Duration::from_millis(5),
+        }]));
+        metrics.update(Stats::Relays(vec![RelayStats {
+            id: 1,
+            name: "www \"1\"".to_string(),
+            active: 1,
+            bytes_in: 100,
+            bytes_out: 2000,
+            errors: 1,
+            ..Default::default()
+        }]));
+        metrics.update(Stats::Processes(vec![ProcessStats {
+            name: "relay".to_string(),
+            instance: 1,
+            running: true,
+            restarts: 2,
+        }]));
+
+        let text = metrics.to_string();
+        assert!(text.contains("relayd_relay_connections_total{relay=\"www \\\"1\\\"\"} 0\n"));
+        assert!(text.contains(
+            "relayd_host_up{table=\"web\",host=\"www.example.com\",address=\"10.0.0.1:80\"} 1\n"
+        ));
+        assert!(text.contains(
+            "relayd_host_check_duration_seconds{table=\"web\",host=\"www.example.com\",address=\"10.0.0.1:80\"} 0.005\n"
+        ));
+        assert!(text.contains("relayd_relay_sent_bytes_total{relay=\"www \\\"1\\\"\"} 2000\n"));
+        assert!(text.contains("# TYPE relayd_relay_errors_total counter\n"));
+        assert!(text.contains("relayd_process_up{process=\"relay\",instance=\"1\"} 1\n"));
+        assert!(
+            text.contains("relayd_process_restarts_total{process=\"relay\",instance=\"1\"} 2\n")
+        );
+    }
+
+    #[test]
+    fn test_rolling_stats() {
+        let mut stats = RelayStats::default();
+        for sessions in [3, 0, 6] {
+            for _ in 0..sessions {
+                stats.sessions.count();
+                stats.host(7, "10.0.0.1:80").count();
+            }
+            stats.tick();
+        }
+        stats.sessions.count();
+
+        let sessions = &stats.sessions;
+        assert_eq!((sessions.total, sessions.current), (10, 1));
+        assert_eq!((sessions.last, sessions.max, sessions.ticks), (6, 6, 3));
+        assert_eq!(sessions.avg(), 3.0);
+        assert_eq!(sessions.rate(Duration::from_secs(5)), 1.2);
+        assert_eq!(stats.hosts.len(), 1);
+        assert_eq!(stats.hosts[0].sessions.total, 9);
+
+        // A second relay process finished the same intervals.
+        let mut other = RelayStats {
+            active: 2,
+            ..stats.clone()
+        };
+        other.host(8, "10.0.0.2:80").count();
+        let merged = merge_relays([&[stats][..], &[other][..]]);
+        assert_eq!(merged.len(), 1);
+        let sessions = &merged[0].sessions;
+        assert_eq!((sessions.total, sessions.current), (20, 2));
+        assert_eq!((sessions.last, sessions.max, sessions.ticks), (12, 12, 3));
+        assert_eq!(merged[0].active, 2);
+        assert_eq!(merged[0].hosts[0].sessions.total, 18);
+        assert_eq!(merged[0].hosts[1].sessions.total, 1);
+    }
+}
@@ -32,6 +32,11 @@ impl Options {
             "Define macro to be set to value on the command line",
             "macro=value",
         );
+        opts.optflag(
+            "n",
+            "",
+            "Configtest mode, only check the configuration file for validity",
+        );
         opts.optflagmulti("v", "verbose", "Enable verbose logging");
 
         Self { args, opts, prog }
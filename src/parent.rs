@@ -1,11 +1,25 @@
+mod control;
+mod supervisor;
+mod systemd;
+
 use crate::{
-    config::{Config, Variables},
-    error::Error,
+    ca::{self, Keypair, Sign, Signature},
+    config::{Config, DynamicHost, Id, Variables},
+    error::{ConfigError, Error},
+    history::{History, Transition},
     message::{Data, Type},
+    metrics::{self, ProcessStats, RelayStats, Stats},
     options::Options,
+    redirect::{self, Route, RouteTable, Ruleset},
+    relay::Listeners,
     Privsep,
 };
-use nix::sys::wait::{waitpid, WaitStatus};
+use control::Control;
+use futures::future::select_all;
+use nix::sys::{
+    signal::{kill, Signal},
+    wait::{waitpid, WaitPidFlag, WaitStatus},
+};
 use privsep::{
     imsg::Message,
     net::Fd,
@@ -14,8 +28,26 @@ use privsep::{
 };
 use privsep_log::{debug, info, warn};
 use serde::de::DeserializeOwned;
-use std::{io, process, sync::Arc};
-use tokio::signal::unix::{signal, SignalKind};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::OpenOptions,
+    io,
+    net::SocketAddr,
+    os::unix::{fs::OpenOptionsExt, io::IntoRawFd},
+    time::Duration,
+};
+use supervisor::Supervisor;
+use tokio::{
+    signal::unix::{signal, Signal as SignalStream, SignalKind},
+    time::{self, Instant},
+};
+use trust_dns_resolver::system_conf::read_system_conf;
+
+/// Bits of the Id of a signing request that identify the relay process.
+const SIGN_TAG_BITS: u32 = 8;
+
+/// Message of a child process or the error of its channel.
+type Received = Result<(Message, Option<Fd>, Data<'static>), Error>;
 
 pub async fn main<const N: usize>(
     parent: Parent<N>,
@@ -25,47 +57,597 @@ pub async fn main<const N: usize>(
         .await
         .map_err(|err| PrivsepError::GeneralError(Box::new(err)))?;
 
-    let parent = Arc::new(parent);
+    // The children are replaced when they are restarted.
+    let mut parent = parent;
 
-    let config = Config {
+    let mut config = Config {
         privsep,
         ..init(&parent)
             .await
             .map_err(|err| PrivsepError::GeneralError(Box::new(err)))?
     };
+    let mut supervisor = Supervisor::default();
+    // Resolved addresses of host names, to inform restarted children.
+    let mut dynamic_hosts = HashMap::<Id, DynamicHost>::new();
+    // Last statistics of each relay process.
+    let mut relay_stats = BTreeMap::<usize, Vec<RelayStats>>::new();
     let mut sigchld = signal(SignalKind::child())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    // Sockets of the service manager, they are only meant for this process.
+    let activated = systemd::listen_fds();
 
     // Detach the parent from the foreground.
     if !config.privsep.foreground {
         daemon(true, false)?;
     }
 
+    // Rules of the redirects and whether they have to be removed on shutdown.
+    let mut ruleset = Ruleset::default();
+    ruleset.configure(&config);
+    let mut ruleset_installed = false;
+    let mut routes = RouteTable::new(redirect::routing());
+    // Listener sockets of the relays.
+    let mut listeners = Listeners::default();
+    for (name, fd) in activated {
+        match listeners.activate(name.clone(), fd) {
+            Ok(addr) => debug!("activated socket {} on {}", name, addr),
+            Err(err) => warn!("Invalid socket {} of the service manager: {}", name, err),
+        }
+    }
+    listeners.update(&config);
+    // TLS keypairs of the relays.
+    let mut keypairs = ca::load(&config);
+    let control = Control::default();
+    let control_task = match control.listen(&config.socket) {
+        Ok(task) => Some(task),
+        Err(err) => {
+            warn!(
+                "Failed to create control socket {}: {}",
+                config.socket.display(),
+                err
+            );
+            None
+        }
+    };
+
+    if let Some(path) = &config.log.history {
+        match History::load(path).await {
+            Ok(history) => control.history(history),
+            Err(err) => warn!("Failed to load host history {}: {}", path.display(), err),
+        }
+    }
+
     info!("Started");
 
+    // The additional relay processes are started like restarted ones.
+    supervisor.prefork(config.prefork);
+
     // Send the configuration to all children.
     send_to_all(&parent, Type::Config, None, &Data::from(&config)).await?;
+    let relays = supervisor.relays(&parent);
+    send_log_file(&relays, &config).await?;
+    send_listeners(&relays, listeners.iter()).await?;
+    send_keypairs(&relays, &keypairs, false).await?;
+    send_keypairs(&[&parent[Privsep::CA_ID]], &keypairs, true).await?;
+    // Use the last known host states until the first checks complete.
+    send_last_states(&parent, &supervisor, &control, &config, true).await?;
     send_to_all(&parent, Type::Start, None, &Data::None).await?;
+    report_processes(&parent, &supervisor).await?;
+    systemd::ready();
+
+    // A child that cannot be restarted terminates the daemon after cleanup.
+    let mut result = Ok(());
 
     loop {
+        let next_restart = supervisor.next_restart();
+
         tokio::select! {
             _ = sigchld.recv() => {
-                match waitpid(None, None) {
-                    Ok(WaitStatus::Exited(pid, status)) => {
-                        warn!("Child {} exited with status {}", pid, status);
-                        process::exit(0);
+                if let Err(err) = supervisor.reap(&parent, &config) {
+                    result = Err(err);
+                    break;
+                }
+                report_processes(&parent, &supervisor).await?;
+            }
+
+            _ = time::sleep_until(next_restart.unwrap_or_else(Instant::now)),
+                if next_restart.is_some() => {
+                let restarted = match supervisor.restart(&mut parent, &config) {
+                    Ok(restarted) => restarted,
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                };
+                for id in restarted {
+                    let peer = supervisor.peer(&parent, id);
+                    let privsep_id = supervisor.privsep_id(id);
+                    send_to_peer(peer, Type::Config, None, &Data::from(&config)).await?;
+                    if privsep_id == Privsep::RELAY_ID {
+                        send_log_file(&[peer], &config).await?;
+                        send_listeners(&[peer], listeners.iter()).await?;
+                        send_keypairs(&[peer], &keypairs, false).await?;
+                        send_host_states(peer, &control, &config).await?;
+                    }
+                    if privsep_id == Privsep::CA_ID {
+                        send_keypairs(&[peer], &keypairs, true).await?;
+                    }
+                    if privsep_id == Privsep::HEALTH_ID {
+                        send_last_states(&parent, &supervisor, &control, &config, false).await?;
+                    }
+                    send_to_peer(peer, Type::Start, None, &Data::None).await?;
+
+                    // A new health process resolves the host names again.
+                    if privsep_id == Privsep::HEALTH_ID {
+                        for (id, _) in dynamic_hosts.drain() {
+                            let data = Data::Host(id);
+                            forward_host_state(&parent, &supervisor, Type::HostRemove, &data)
+                                .await?;
+                        }
+                    } else {
+                        for host in dynamic_hosts.values() {
+                            let data = Data::DynamicHost(host.clone());
+                            send_to_peer(peer, Type::HostAdd, None, &data).await?;
+                        }
+                    }
+                }
+                report_processes(&parent, &supervisor).await?;
+            }
+
+            _ = sighup.recv() => {
+                systemd::reloading();
+                if let Some(new_config) = reload(&parent, &config).await {
+                    config = new_config;
+                    ruleset.configure(&config);
+                    let new_listeners = listeners.update(&config);
+                    keypairs = ca::load(&config);
+                    for peer in supervisor.prefork(config.prefork) {
+                        if let Err(err) = send_to_peer(&peer, Type::Shutdown, None, &Data::None)
+                            .await
+                        {
+                            warn!("Failed to stop {}({}): {}", peer.name, peer.pid, err);
+                        }
+                    }
+                    relay_stats.retain(|id, _| supervisor.relay_ids().any(|relay| relay == *id));
+                    for id in supervisor.running() {
+                        let peer = supervisor.peer(&parent, id);
+                        send_to_peer(peer, Type::Config, None, &Data::from(&config)).await?;
+                    }
+                    // Reopen the access log, e.g. after it was rotated, and
+                    // only send the sockets of new listeners.
+                    let relays = supervisor.relays(&parent);
+                    send_log_file(&relays, &config).await?;
+                    let new = listeners.iter().filter(|(addr, _)| new_listeners.contains(addr));
+                    send_listeners(&relays, new).await?;
+                    send_keypairs(&relays, &keypairs, false).await?;
+                    if supervisor.is_running(Privsep::CA_ID) {
+                        send_keypairs(&[&parent[Privsep::CA_ID]], &keypairs, true).await?;
+                    }
+                    report_processes(&parent, &supervisor).await?;
+                }
+                // The old configuration is kept if the reload failed.
+                systemd::ready();
+            }
+
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+
+            message = default_handler::<Data<'_>>(&parent[Privsep::HEALTH_ID]),
+                if supervisor.is_running(Privsep::HEALTH_ID) => {
+                match message {
+                    Err(Error::Terminated(_)) => supervisor.lost(Privsep::HEALTH_ID),
+                    message => match message? {
+                        (Message { id: Type::HOST_UP, .. }, _, data @ Data::Host(_)) => {
+                            forward_host_state(&parent, &supervisor, Type::HostUp, &data).await?;
+                        }
+                        (Message { id: Type::HOST_DOWN, .. }, _, data @ Data::Host(_)) => {
+                            forward_host_state(&parent, &supervisor, Type::HostDown, &data).await?;
+                        }
+                        (Message { id: Type::HOST_ADD, .. }, _, Data::DynamicHost(host)) => {
+                            dynamic_hosts.insert(host.id, host.clone());
+                            let data = Data::DynamicHost(host);
+                            forward_host_state(&parent, &supervisor, Type::HostAdd, &data).await?;
+                        }
+                        (Message { id: Type::HOST_REMOVE, .. }, _, Data::Host(id)) => {
+                            dynamic_hosts.remove(&id);
+                            let data = Data::Host(id);
+                            forward_host_state(&parent, &supervisor, Type::HostRemove, &data)
+                                .await?;
+                        }
+                        (Message { id: Type::HOST_CHANGE, .. }, _, Data::Transition(t)) => {
+                            record_transition(&config, &control, t).await;
+                        }
+                        (Message { id: Type::STATS, .. }, _, data @ Data::Stats(_)) => {
+                            forward_stats(&parent, &supervisor, &data).await?;
+                        }
+                        _ => return Err(Error::InvalidMessage.into()),
+                    }
+                }
+            }
+            (id, message) = recv_relays(&parent, &supervisor),
+                if supervisor.relay_ids().next().is_some() => {
+                match message {
+                    Err(Error::Terminated(_)) => supervisor.lost(id),
+                    message => match message? {
+                        (Message { id: Type::STATS, .. }, _, Data::Stats(Stats::Relays(stats))) => {
+                            // Report the sum of all relay processes.
+                            relay_stats.insert(id, stats);
+                            let relays = relay_stats.values().map(Vec::as_slice);
+                            let relays = metrics::merge_relays(relays);
+                            control.relays(relays.clone());
+                            let data = Data::Stats(Stats::Relays(relays));
+                            forward_stats(&parent, &supervisor, &data).await?;
+                        }
+                        (Message { id: Type::SIGN, .. }, _, Data::Sign(request)) => {
+                            forward_sign(&parent, &supervisor, id, request).await?;
+                        }
+                        _ => return Err(Error::InvalidMessage.into()),
+                    }
+                }
+            }
+            message = default_handler::<Data<'_>>(&parent[Privsep::REDIRECT_ID]),
+                if supervisor.is_running(Privsep::REDIRECT_ID) => {
+                match message {
+                    Err(Error::Terminated(_)) => supervisor.lost(Privsep::REDIRECT_ID),
+                    message => match message? {
+                        (Message { id: Type::STATS, .. }, _, data @ Data::Stats(_)) => {
+                            forward_stats(&parent, &supervisor, &data).await?;
+                        }
+                        (Message { id: Type::REDIRECT_HOSTS, .. }, _, Data::RedirectHosts(hosts)) => {
+                            match ruleset.update(&config, &dynamic_hosts, &hosts) {
+                                Ok(Some(rules)) => ruleset_installed |= install_ruleset(&rules).await,
+                                Ok(None) => (),
+                                Err(err) => warn!("Rejected the redirect hosts: {}", err),
+                            }
+                        }
+                        (Message { id: Type::ROUTES, .. }, _, Data::Routes(new_routes)) => {
+                            install_routes(&mut routes, &new_routes);
+                        }
+                        (Message { id: Type::FLUSH_STATES, .. }, _, Data::States(states)) => {
+                            if !ruleset.check(&config, &states) {
+                                warn!("Rejected the states of {}", states.host);
+                            } else if let Err(err) = redirect::flush_states(&states).await {
+                                warn!("Failed to flush states of {}: {}", states.host, err);
+                            }
+                        }
+                        _ => return Err(Error::InvalidMessage.into()),
                     }
-                    status => {
-                        warn!("Child exited with error: {:?}", status);
-                        process::exit(1);
+                }
+            }
+            message = default_handler::<Data<'_>>(&parent[Privsep::CA_ID]),
+                if supervisor.is_running(Privsep::CA_ID) => {
+                match message {
+                    Err(Error::Terminated(_)) => supervisor.lost(Privsep::CA_ID),
+                    message => match message? {
+                        (Message { id: Type::SIGNATURE, .. }, _, Data::Signature(signature)) => {
+                            forward_signature(&parent, &supervisor, signature).await?;
+                        }
+                        _ => return Err(Error::InvalidMessage.into()),
                     }
                 }
             }
+            message = default_handler::<()>(&parent[Privsep::METRICS_ID]),
+                if supervisor.is_running(Privsep::METRICS_ID) => {
+                match message {
+                    Err(Error::Terminated(_)) => supervisor.lost(Privsep::METRICS_ID),
+                    message => { message?; }
+                }
+            }
+        }
+    }
+
+    systemd::stopping();
+    if let Some(task) = control_task {
+        task.abort();
+        control.close(&config.socket);
+    }
+    shutdown(&parent, &supervisor, &mut sigchld, config.drain_timeout).await?;
+    install_routes(&mut routes, &[]);
+    if ruleset_installed {
+        if let Err(err) = redirect::remove().await {
+            warn!("Failed to remove the redirect ruleset: {}", err);
+        }
+    }
+
+    info!("Terminated");
 
-            message = default_handler::<()>(&parent[Privsep::HEALTH_ID]) => { message?; },
-            message = default_handler::<()>(&parent[Privsep::RELAY_ID]) => { message?; },
-            message = default_handler::<()>(&parent[Privsep::REDIRECT_ID]) => { message?; },
+    result.map_err(Into::into)
+}
+
+/// Load the configuration again, keeping the old one on errors.
+async fn reload<const N: usize>(parent: &Parent<N>, config: &Config) -> Option<Config> {
+    info!("Reloading configuration");
+
+    match init(parent).await {
+        Ok(new_config) => {
+            let mut new_config = Config {
+                privsep: config.privsep.clone(),
+                ..new_config
+            };
+            new_config.keep_host_ids(config);
+            Some(new_config)
         }
+        Err(err) => {
+            warn!("Failed to reload configuration: {}", err);
+            None
+        }
+    }
+}
+
+/// Forward host state and address changes from the health checks to the
+/// other processes.
+async fn forward_host_state<const N: usize>(
+    parent: &Parent<N>,
+    supervisor: &Supervisor,
+    typ: Type,
+    data: &Data<'_>,
+) -> io::Result<()> {
+    if supervisor.is_running(Privsep::REDIRECT_ID) {
+        send_to_peer(&parent[Privsep::REDIRECT_ID], typ, None, data).await?;
+    }
+    for peer in supervisor.relays(parent) {
+        send_to_peer(peer, typ, None, data).await?;
     }
+    Ok(())
+}
+
+/// Apply the ruleset of the redirects, returns true if it was installed.
+async fn install_ruleset(ruleset: &str) -> bool {
+    match redirect::apply(ruleset).await {
+        Ok(()) => {
+            debug!("installed redirect ruleset");
+            true
+        }
+        Err(err) => {
+            warn!("Failed to install the redirect ruleset: {}", err);
+            false
+        }
+    }
+}
+
+/// Install the routes of the routers, keeping the ones that failed.
+fn install_routes(routes: &mut RouteTable, new_routes: &[Route]) {
+    for err in routes.update(new_routes) {
+        warn!("Failed to update routes: {}", err);
+    }
+}
+
+/// Add a host state transition to the history and the history file.
+async fn record_transition(config: &Config, control: &Control, transition: Transition) {
+    if let Some(path) = &config.log.history {
+        if let Err(err) = History::append(path, &transition).await {
+            warn!("Failed to write host history {}: {}", path.display(), err);
+        }
+    }
+    control.transition(transition);
+}
+
+/// Send the last known host states of the history to the health checks
+/// and, on startup, to the relays and redirects.
+async fn send_last_states<const N: usize>(
+    parent: &Parent<N>,
+    supervisor: &Supervisor,
+    control: &Control,
+    config: &Config,
+    forward: bool,
+) -> io::Result<()> {
+    for (id, up) in control.last_states(config) {
+        let typ = if up { Type::HostUp } else { Type::HostDown };
+        let data = Data::Host(id);
+        if supervisor.is_running(Privsep::HEALTH_ID) {
+            send_to_peer(&parent[Privsep::HEALTH_ID], typ, None, &data).await?;
+        }
+        if forward {
+            forward_host_state(parent, supervisor, typ, &data).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Send the last known host states to a restarted relay process.
+async fn send_host_states(peer: &Peer, control: &Control, config: &Config) -> io::Result<()> {
+    for (id, up) in control.last_states(config) {
+        let typ = if up { Type::HostUp } else { Type::HostDown };
+        send_to_peer(peer, typ, None, &Data::Host(id)).await?;
+    }
+    Ok(())
+}
+
+/// Open the access log file and send it to the relays.
+///
+/// The relays cannot open it themselves after they dropped privileges.
+async fn send_log_file(relays: &[&Peer], config: &Config) -> io::Result<()> {
+    let path = match &config.log.file {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let file = match OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o640)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("Failed to open log file {}: {}", path.display(), err);
+            return Ok(());
+        }
+    };
+    let fd = Fd::from(file.into_raw_fd());
+    for peer in relays {
+        send_to_peer(peer, Type::LogFile, Some(&fd), &Data::None).await?;
+    }
+    Ok(())
+}
+
+/// Send listener sockets to the relays; they all accept connections on
+/// the same sockets.
+async fn send_listeners<'a>(
+    relays: &[&Peer],
+    listeners: impl Iterator<Item = (&'a SocketAddr, &'a Fd)>,
+) -> io::Result<()> {
+    for (addr, fd) in listeners {
+        let data = Data::Listener(*addr);
+        for peer in relays {
+            send_to_peer(peer, Type::Listener, Some(fd), &data).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Send the keypairs to the CA process or the certificates to the relays.
+async fn send_keypairs(peers: &[&Peer], keypairs: &[Keypair], private: bool) -> io::Result<()> {
+    for keypair in keypairs {
+        let data = if private {
+            Data::Keypair(keypair.clone())
+        } else {
+            Data::Keypair(keypair.public())
+        };
+        for peer in peers {
+            send_to_peer(peer, Type::Keypair, None, &data).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Receive the next message of any relay process.
+async fn recv_relays<const N: usize>(
+    parent: &Parent<N>,
+    supervisor: &Supervisor,
+) -> (usize, Received) {
+    let messages = supervisor.relay_ids().map(|id| {
+        Box::pin(async move { (id, default_handler(supervisor.peer(parent, id)).await) })
+    });
+    select_all(messages).await.0
+}
+
+/// Forward a signing request of a relay process to the CA process.
+///
+/// The Id of the request is tagged with the relay process that gets the
+/// signature.  The request fails immediately while the CA process is
+/// restarting.
+async fn forward_sign<const N: usize>(
+    parent: &Parent<N>,
+    supervisor: &Supervisor,
+    id: usize,
+    request: Sign,
+) -> io::Result<()> {
+    if supervisor.is_running(Privsep::CA_ID) {
+        let data = Data::Sign(Sign {
+            id: (request.id << SIGN_TAG_BITS) | id as u64,
+            ..request
+        });
+        return send_to_peer(&parent[Privsep::CA_ID], Type::Sign, None, &data).await;
+    }
+    let data = Data::Signature(Signature {
+        id: request.id,
+        signature: Err("CA process is not running".to_string()),
+    });
+    send_to_peer(supervisor.peer(parent, id), Type::Signature, None, &data).await
+}
+
+/// Return a signature of the CA process to the relay process that sent
+/// the request.
+async fn forward_signature<const N: usize>(
+    parent: &Parent<N>,
+    supervisor: &Supervisor,
+    signature: Signature,
+) -> io::Result<()> {
+    let id = (signature.id & ((1 << SIGN_TAG_BITS) - 1)) as usize;
+    if !supervisor.relay_ids().any(|relay| relay == id) {
+        return Ok(());
+    }
+    let data = Data::Signature(Signature {
+        id: signature.id >> SIGN_TAG_BITS,
+        ..signature
+    });
+    send_to_peer(supervisor.peer(parent, id), Type::Signature, None, &data).await
+}
+
+/// Forward statistics to the metrics process.
+async fn forward_stats<const N: usize>(
+    parent: &Parent<N>,
+    supervisor: &Supervisor,
+    data: &Data<'_>,
+) -> io::Result<()> {
+    if supervisor.is_running(Privsep::METRICS_ID) {
+        send_to_peer(&parent[Privsep::METRICS_ID], Type::Stats, None, data).await?;
+    }
+    Ok(())
+}
+
+/// Send the state of the child processes to the metrics process.
+async fn report_processes<const N: usize>(
+    parent: &Parent<N>,
+    supervisor: &Supervisor,
+) -> io::Result<()> {
+    let stats = supervisor
+        .ids()
+        .map(|id| ProcessStats {
+            name: supervisor.peer(parent, id).name.to_string(),
+            instance: supervisor.instance(id),
+            running: supervisor.is_running(id),
+            restarts: supervisor.restarts(id),
+        })
+        .collect();
+    forward_stats(parent, supervisor, &Data::Stats(Stats::Processes(stats))).await
+}
+
+/// Stop all children and wait until they exited.
+///
+/// The relays get up to `drain_timeout` to finish their active sessions
+/// before any remaining children are killed.
+async fn shutdown<const N: usize>(
+    parent: &Parent<N>,
+    supervisor: &Supervisor,
+    sigchld: &mut SignalStream,
+    drain_timeout: Duration,
+) -> io::Result<()> {
+    info!("Shutting down");
+
+    // Wait for the relay processes that were stopped after a reload.
+    let mut children = supervisor.stopping().copied().collect::<HashSet<_>>();
+    for id in supervisor.running() {
+        let peer = supervisor.peer(parent, id);
+        if let Err(err) = send_to_peer(peer, Type::Shutdown, None, &Data::None).await {
+            warn!("Failed to stop {}: {}", peer.name, err);
+        }
+        children.insert(peer.pid);
+    }
+
+    let deadline = time::sleep(drain_timeout + Duration::from_secs(1));
+    tokio::pin!(deadline);
+
+    while !children.is_empty() {
+        tokio::select! {
+            _ = sigchld.recv() => {
+                while let Ok(status) = waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                    match status {
+                        WaitStatus::Exited(pid, 0) => {
+                            debug!("Child {} exited", pid);
+                        }
+                        WaitStatus::StillAlive => break,
+                        status => {
+                            warn!("Child exited with error: {:?}", status);
+                        }
+                    }
+                    if let Some(pid) = status.pid() {
+                        children.remove(&pid);
+                    }
+                }
+            }
+            _ = &mut deadline => {
+                for pid in children.drain() {
+                    warn!("Child {} did not terminate, killing it", pid);
+                    let _ = kill(pid, Signal::SIGKILL);
+                }
+            }
+        }
+    }
+
+    Ok(())
 }
 
 async fn send_to_all<T: Into<Message> + Clone, const N: usize>(
@@ -97,6 +679,33 @@ pub async fn init<const N: usize>(_parent: &Parent<N>) -> Result<Config, Error>
     let opts = Options::new();
     let matches = opts.parse()?;
 
+    let mut config = load(&matches).await?;
+    config.nameservers = system_nameservers();
+
+    Ok(config)
+}
+
+/// Returns the name servers of the system resolver configuration.
+fn system_nameservers() -> Vec<SocketAddr> {
+    match read_system_conf() {
+        Ok((config, _)) => {
+            let mut addrs = config
+                .name_servers()
+                .iter()
+                .map(|ns| ns.socket_addr)
+                .collect::<Vec<_>>();
+            addrs.dedup();
+            addrs
+        }
+        Err(err) => {
+            warn!("Failed to read the resolver configuration: {}", err);
+            vec![]
+        }
+    }
+}
+
+/// Load the configuration file with the macros from the command line.
+async fn load(matches: &getopts::Matches) -> Result<Config, Error> {
     let path = matches
         .opt_str("f")
         .unwrap_or_else(|| crate::RELAYD_CONFIG.to_string());
@@ -105,14 +714,43 @@ pub async fn init<const N: usize>(_parent: &Parent<N>) -> Result<Config, Error>
     for variable in matches.opt_strs("D") {
         let kv = variable.split('=').collect::<Vec<_>>();
         if kv.len() != 2 {
-            return Err(Error::ParserError(variable));
+            return Err(Error::ParserError(ConfigError {
+                message: format!("invalid macro definition: {}", variable),
+                ..Default::default()
+            }));
         }
         variables.insert(kv[0].to_string(), kv[1].to_string());
     }
 
-    let config = Config::load(&path, variables).await?;
+    Config::load(&path, variables).await
+}
 
-    Ok(config)
+/// Check the configuration without starting the daemon.
+///
+/// The verbose mode prints the expanded configuration and the rules of the
+/// redirects.
+pub async fn configtest(matches: &getopts::Matches) -> Result<(), Error> {
+    // Print configuration warnings to stderr.
+    let _guard = privsep_log::sync_logger(
+        "relayd",
+        privsep_log::Config {
+            foreground: true,
+            filter: Some(privsep_log::verbose(matches.opt_count("v"))),
+        },
+    )
+    .map_err(|err| PrivsepError::GeneralError(Box::new(err)))?;
+
+    let config = load(matches).await?;
+
+    if matches.opt_present("v") {
+        print!("{}", config);
+        if !config.redirects.is_empty() {
+            print!("\n{}", redirect::dry_run(&config));
+        }
+    }
+    eprintln!("configuration OK");
+
+    Ok(())
 }
 
 pub async fn default_handler<T: DeserializeOwned>(
@@ -0,0 +1,212 @@
+use crate::{
+    config::{Config, Id},
+    history::{History, Transition},
+    metrics::{RelayStats, RollingStats},
+};
+use privsep_log::{debug, info, warn};
+use std::{
+    fmt::Write,
+    fs::{self, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+
+/// Maximum length of a control command.
+const MAX_COMMAND: u64 = 1024;
+
+/// State that is shown on the control socket.
+#[derive(Debug, Default)]
+struct State {
+    /// Latest statistics of the relays.
+    relays: Vec<RelayStats>,
+    /// Host state transitions.
+    history: History,
+}
+
+impl State {
+    /// Run a control command and return its output.
+    fn command(&self, command: &str) -> String {
+        match command.split_whitespace().collect::<Vec<_>>()[..] {
+            ["show", "relays"] => self.show_relays(crate::STATS_INTERVAL),
+            ["show", "hosts", "history"] => self.history.to_string(),
+            [] => "missing command\n".to_string(),
+            _ => format!("unknown command: {}\n", command),
+        }
+    }
+
+    /// Print the relays and their backend hosts like `relayctl show relays`.
+    fn show_relays(&self, interval: Duration) -> String {
+        let mut out = format!("{:<8}{:<16}{:<32}{}\n", "Id", "Type", "Name", "Status");
+        for relay in &self.relays {
+            let _ = writeln!(
+                out,
+                "{:<8}{:<16}{:<32}active",
+                relay.id, "relay", relay.name
+            );
+            rolling_stats(&mut out, &relay.sessions, interval);
+            for host in &relay.hosts {
+                let _ = writeln!(out, "{:<8}{:<16}{}", host.id, "host", host.name);
+                rolling_stats(&mut out, &host.sessions, interval);
+            }
+        }
+        out
+    }
+}
+
+/// Print rolling session counters.
+fn rolling_stats(out: &mut String, stats: &RollingStats, interval: Duration) {
+    let indent = " ".repeat(24);
+    let secs = interval.as_secs();
+    let _ = writeln!(out, "{}total: {} sessions", indent, stats.total);
+    let _ = writeln!(
+        out,
+        "{}last: {}/{}s {:.2}/s sessions",
+        indent,
+        stats.last,
+        secs,
+        stats.rate(interval)
+    );
+    let _ = writeln!(
+        out,
+        "{}average: {:.2}/{}s sessions",
+        indent,
+        stats.avg(),
+        secs
+    );
+    let _ = writeln!(out, "{}maximum: {}/{}s sessions", indent, stats.max, secs);
+}
+
+/// Control socket of the parent process.
+///
+/// Each connection sends a single command line and receives the output.
+#[derive(Clone, Debug, Default)]
+pub struct Control {
+    state: Arc<Mutex<State>>,
+}
+
+impl Control {
+    /// Update the relay statistics.
+    pub fn relays(&self, relays: Vec<RelayStats>) {
+        self.state.lock().unwrap().relays = relays;
+    }
+
+    /// Replace the history, e.g. after loading it from a file.
+    pub fn history(&self, history: History) {
+        self.state.lock().unwrap().history = history;
+    }
+
+    /// Add a host state transition to the history.
+    pub fn transition(&self, transition: Transition) {
+        self.state.lock().unwrap().history.push(transition);
+    }
+
+    /// Returns the last known state of the hosts in the configuration.
+    pub fn last_states(&self, config: &Config) -> Vec<(Id, bool)> {
+        let state = self.state.lock().unwrap();
+        state
+            .history
+            .last_states()
+            .filter_map(|(table, host, up)| {
+                let host = config.table(table)?.hosts.iter().find(|h| h.name == host)?;
+                Some((host.id, up))
+            })
+            .collect()
+    }
+
+    /// Listen on the control socket, replacing a stale socket file.
+    pub fn listen(&self, path: &Path) -> io::Result<JoinHandle<()>> {
+        if let Err(err) = fs::remove_file(path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(err);
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, Permissions::from_mode(0o660))?;
+        info!("control socket: listening on {}", path.display());
+
+        let state = self.state.clone();
+        Ok(tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        warn!("control socket: accept failed: {}", err);
+                        continue;
+                    }
+                };
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve(stream, &state).await {
+                        debug!("control socket: request failed: {}", err);
+                    }
+                });
+            }
+        }))
+    }
+
+    /// Remove the control socket.
+    pub fn close(&self, path: &Path) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Answer a single control command.
+async fn serve(stream: UnixStream, state: &Mutex<State>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut command = String::new();
+    BufReader::new(reader.take(MAX_COMMAND))
+        .read_line(&mut command)
+        .await?;
+
+    let output = state.lock().unwrap().command(command.trim());
+    writer.write_all(output.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_show_relays() {
+        let mut relay = RelayStats {
+            id: 1,
+            name: "www".to_string(),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            relay.sessions.count();
+            relay.host(4, "10.0.0.1:80").count();
+        }
+        relay.tick();
+        let state = State {
+            relays: vec![relay],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            state.command("show  relays\n"),
+            "Id      Type            Name                            Status
+1       relay           www                             active
+                        total: 3 sessions
+                        last: 3/5s 0.60/s sessions
+                        average: 3.00/5s sessions
+                        maximum: 3/5s sessions
+4       host            10.0.0.1:80
+                        total: 3 sessions
+                        last: 3/5s 0.60/s sessions
+                        average: 3.00/5s sessions
+                        maximum: 3/5s sessions
+"
+        );
+        assert_eq!(state.command("show hosts"), "unknown command: show hosts\n");
+    }
+}
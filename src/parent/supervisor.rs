@@ -0,0 +1,421 @@
+use crate::{config::Config, error::Error, Privsep};
+use nix::{
+    fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag},
+    libc,
+    sys::{
+        stat::Mode,
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{close, dup2, execve, fork, setsid, ForkResult, Pid},
+};
+use privsep::{
+    imsg::Handler,
+    process::{Parent, Peer, PRIVSEP_FD},
+};
+use privsep_log::{debug, info, warn};
+use std::{
+    collections::HashSet,
+    env,
+    ffi::CString,
+    io,
+    os::unix::{ffi::OsStrExt, io::AsRawFd},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Supervision state of a child process.
+#[derive(Debug)]
+struct Process {
+    /// Whether the process is running.
+    running: bool,
+    /// When the process was last started.
+    started: Instant,
+    /// Number of restarts within the restart window.
+    restarts: u32,
+    /// Number of restarts since the daemon started.
+    total_restarts: u64,
+    /// Time of the scheduled restart.
+    restart_at: Option<Instant>,
+}
+
+impl Default for Process {
+    fn default() -> Self {
+        Self {
+            running: true,
+            started: Instant::now(),
+            restarts: 0,
+            total_restarts: 0,
+            restart_at: None,
+        }
+    }
+}
+
+impl Process {
+    /// Schedule the restart of the crashed process.
+    ///
+    /// Returns the delay of the restart or `None` if the process crashed
+    /// more than `limit` times within the restart window.
+    fn crashed(&mut self, limit: u32, now: Instant) -> Option<Duration> {
+        self.running = false;
+        if now.duration_since(self.started) > crate::RESTART_WINDOW {
+            self.restarts = 0;
+        }
+        if self.restarts >= limit {
+            return None;
+        }
+
+        let backoff = crate::RESTART_BACKOFF * 2u32.pow(self.restarts.min(6));
+        self.restarts += 1;
+        self.total_restarts += 1;
+        self.restart_at = Some(now + backoff);
+        Some(backoff)
+    }
+}
+
+/// Reaps and restarts the child processes.
+///
+/// The children are identified by their privsep Id; the additional relay
+/// processes of `prefork` follow with the Ids after the last process.
+#[derive(Debug)]
+pub struct Supervisor {
+    processes: Vec<Process>,
+    /// Channels of the additional relay processes.
+    relays: Vec<Peer>,
+    /// Relay processes that were stopped after `prefork` was reduced.
+    stopped: HashSet<Pid>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self {
+            processes: Privsep::PROCESS_IDS
+                .iter()
+                .map(|_| Process::default())
+                .collect(),
+            relays: vec![],
+            stopped: HashSet::new(),
+        }
+    }
+}
+
+impl Supervisor {
+    /// Returns the privsep Id of a child process.
+    pub fn privsep_id(&self, id: usize) -> usize {
+        if id < Privsep::PROCESS_IDS.len() {
+            id
+        } else {
+            Privsep::RELAY_ID
+        }
+    }
+
+    /// Returns the instance of a relay process, 0 for other processes.
+    pub fn instance(&self, id: usize) -> usize {
+        id.saturating_sub(Privsep::PROCESS_IDS.len() - 1)
+    }
+
+    /// Returns the channel to a child process.
+    pub fn peer<'a, const N: usize>(&'a self, parent: &'a Parent<N>, id: usize) -> &'a Peer {
+        match id.checked_sub(Privsep::PROCESS_IDS.len()) {
+            Some(i) => &self.relays[i],
+            None => &parent[id],
+        }
+    }
+
+    /// Returns the Ids of all child processes.
+    pub fn ids(&self) -> impl Iterator<Item = usize> {
+        (0..self.processes.len()).filter(|id| *id != Privsep::PARENT_ID)
+    }
+
+    /// Returns the Ids of all child processes that are running.
+    pub fn running(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ids().filter(move |id| self.is_running(*id))
+    }
+
+    /// Returns the Ids of the relay processes that are running.
+    pub fn relay_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.running()
+            .filter(move |id| self.privsep_id(*id) == Privsep::RELAY_ID)
+    }
+
+    /// Returns the channels to the relay processes that are running.
+    pub fn relays<'a, const N: usize>(&'a self, parent: &'a Parent<N>) -> Vec<&'a Peer> {
+        self.relay_ids().map(|id| self.peer(parent, id)).collect()
+    }
+
+    /// Start or stop the additional relay processes.
+    ///
+    /// New relay processes are started like restarted ones; returns the
+    /// channels of the running processes that have to be stopped.
+    pub fn prefork(&mut self, prefork: usize) -> Vec<Peer> {
+        let len = Privsep::PROCESS_IDS.len() + prefork.max(1) - 1;
+        while self.processes.len() < len {
+            self.processes.push(Process {
+                running: false,
+                restart_at: Some(Instant::now()),
+                ..Default::default()
+            });
+            self.relays.push(Peer {
+                name: Privsep::as_static_str(&Privsep::Relay),
+                handler: None,
+                pid: Pid::from_raw(0),
+            });
+        }
+
+        let mut stopped = vec![];
+        while self.processes.len() > len {
+            let process = self.processes.pop().unwrap();
+            let peer = self.relays.pop().unwrap();
+            // The process may have exited without being reaped yet.
+            if process.restart_at.is_none() {
+                self.stopped.insert(peer.pid);
+            }
+            if process.running {
+                info!("Stopping {}({})", peer.name, peer.pid);
+                stopped.push(peer);
+            }
+        }
+        stopped
+    }
+
+    /// Returns the relay processes that are stopping.
+    pub fn stopping(&self) -> impl Iterator<Item = &Pid> {
+        self.stopped.iter()
+    }
+
+    /// Returns true if the child process is running.
+    pub fn is_running(&self, id: usize) -> bool {
+        self.processes[id].running
+    }
+
+    /// Returns the number of restarts since the daemon started.
+    pub fn restarts(&self, id: usize) -> u64 {
+        self.processes[id].total_restarts
+    }
+
+    /// Mark the child process as lost after its channel was closed.
+    pub fn lost(&mut self, id: usize) {
+        self.processes[id].running = false;
+    }
+
+    /// Returns the time of the next scheduled restart.
+    pub fn next_restart(&self) -> Option<Instant> {
+        self.processes.iter().filter_map(|p| p.restart_at).min()
+    }
+
+    /// Reap all exited children and schedule their restart.
+    ///
+    /// Returns an error if a child cannot be restarted and the daemon
+    /// must terminate.
+    pub fn reap<const N: usize>(
+        &mut self,
+        parent: &Parent<N>,
+        config: &Config,
+    ) -> Result<(), Error> {
+        loop {
+            let status = match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) | Err(_) => return Ok(()),
+                Ok(status) => status,
+            };
+            let pid = match status.pid() {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if self.stopped.remove(&pid) {
+                debug!("relay({}) {}", pid, describe(&status));
+                continue;
+            }
+            let id = match (1..self.processes.len()).find(|id| self.peer(parent, *id).pid == pid) {
+                Some(id) => id,
+                None => {
+                    warn!("Unknown child {} {}", pid, describe(&status));
+                    continue;
+                }
+            };
+            let name = self.peer(parent, id).name;
+            let privsep_id = self.privsep_id(id);
+            warn!("{}({}) {}", name, pid, describe(&status));
+
+            let process = &mut self.processes[id];
+            let limit = config
+                .restart
+                .iter()
+                .find(|r| r.process == privsep_id)
+                .map(|r| r.limit);
+            match process.crashed(limit.unwrap_or(0), Instant::now()) {
+                Some(backoff) => info!("Restarting {} in {:?}", name, backoff),
+                None => {
+                    if limit.is_some() {
+                        warn!("{} crashed {} times, giving up", name, process.restarts);
+                    }
+                    return Err(Error::Terminated(name));
+                }
+            }
+        }
+    }
+
+    /// Restart all children whose restart is due.
+    ///
+    /// Returns the Ids of the restarted processes; the caller has to
+    /// send them the configuration.
+    pub fn restart<const N: usize>(
+        &mut self,
+        parent: &mut Parent<N>,
+        config: &Config,
+    ) -> Result<Vec<usize>, Error> {
+        let now = Instant::now();
+        let mut restarted = vec![];
+
+        for (id, process) in self.processes.iter_mut().enumerate() {
+            match process.restart_at {
+                Some(restart_at) if restart_at <= now => {}
+                _ => continue,
+            }
+            let peer = match id.checked_sub(Privsep::PROCESS_IDS.len()) {
+                Some(i) => &mut self.relays[i],
+                None => &mut parent.children[id],
+            };
+            let (handler, pid) = spawn(peer.name, &config.privsep)?;
+            if peer.handler.is_some() {
+                info!("Restarted {}({})", peer.name, pid);
+            } else {
+                info!("Started {}({})", peer.name, pid);
+            }
+
+            peer.handler = Some(handler);
+            peer.pid = pid;
+            process.running = true;
+            process.started = now;
+            process.restart_at = None;
+            restarted.push(id);
+        }
+
+        Ok(restarted)
+    }
+}
+
+/// Describe why a child process exited.
+fn describe(status: &WaitStatus) -> String {
+    match status {
+        WaitStatus::Exited(_, code) => format!("exited with status {}", code),
+        WaitStatus::Signaled(_, signal, true) => {
+            format!("terminated by signal {} (core dumped)", signal)
+        }
+        WaitStatus::Signaled(_, signal, false) => format!("terminated by signal {}", signal),
+        status => format!("changed state: {:?}", status),
+    }
+}
+
+/// Fork and execute a single child process.
+///
+/// This follows the startup of the children in `privsep::process::Parent`
+/// but only connects the new child to the parent.
+fn spawn(name: &'static str, config: &privsep::Config) -> Result<(Handler, Pid), Error> {
+    let (handler, remote) = Handler::pair()?;
+
+    // Prepare everything that allocates before forking.
+    let program =
+        CString::new(env::current_exe()?.as_os_str().as_bytes()).map_err(io::Error::from)?;
+    let args = [
+        CString::new(name).map_err(io::Error::from)?,
+        CString::new(if config.foreground { "-d" } else { "" }).map_err(io::Error::from)?,
+    ];
+    let env = [CString::new(format!(
+        "RUST_LOG={}",
+        env::var("RUST_LOG")
+            .ok()
+            .or_else(|| config.log_level.clone())
+            .unwrap_or_default()
+    ))
+    .map_err(io::Error::from)?];
+
+    match unsafe { fork() }.map_err(privsep::Error::from)? {
+        ForkResult::Parent { child } => Ok((handler, child)),
+        ForkResult::Child => {
+            let _ = setsid();
+
+            // Daemons detach from terminal.
+            if !config.foreground {
+                if let Ok(fd) = open("/dev/null", OFlag::O_RDWR, Mode::empty()) {
+                    let _ = dup2(fd, libc::STDIN_FILENO);
+                    let _ = dup2(fd, libc::STDOUT_FILENO);
+                    let _ = dup2(fd, libc::STDERR_FILENO);
+                    if fd > libc::STDERR_FILENO {
+                        let _ = close(fd);
+                    }
+                }
+            }
+
+            if dup2(remote.as_raw_fd(), PRIVSEP_FD).is_ok() {
+                let _ = fcntl(PRIVSEP_FD, FcntlArg::F_SETFD(FdFlag::empty()));
+                unsafe {
+                    close_fds::close_open_fds(PRIVSEP_FD + 1, &[]);
+                }
+                let _ = execve(&program, &args, &env);
+            }
+
+            unsafe { libc::_exit(1) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RESTART_BACKOFF, RESTART_WINDOW};
+
+    #[test]
+    fn test_supervisor_restart() {
+        let mut process = Process::default();
+        let now = process.started;
+
+        // Without a restart policy, the first crash is fatal.
+        assert_eq!(process.crashed(0, now), None);
+        assert!(!process.running);
+        assert_eq!(process.restart_at, None);
+
+        // The backoff doubles with every crash within the restart window.
+        for i in 0..3 {
+            let backoff = RESTART_BACKOFF * 2u32.pow(i);
+            assert_eq!(process.crashed(3, now), Some(backoff));
+            assert_eq!(process.restart_at, Some(now + backoff));
+            assert_eq!(process.restarts, i + 1);
+        }
+        assert_eq!(process.crashed(3, now), None);
+
+        // Crashes after the restart window start over.
+        let later = now + RESTART_WINDOW + RESTART_BACKOFF;
+        assert_eq!(process.crashed(3, later), Some(RESTART_BACKOFF));
+        assert_eq!(process.restarts, 1);
+        assert_eq!(process.total_restarts, 4);
+
+        // The backoff is capped.
+        for _ in 1..10 {
+            process.crashed(20, now);
+        }
+        assert_eq!(process.crashed(20, now), Some(RESTART_BACKOFF * 64));
+    }
+
+    #[test]
+    fn test_supervisor_prefork() {
+        let mut supervisor = Supervisor::default();
+        let first = Privsep::PROCESS_IDS.len();
+
+        // The additional relay processes are started by the next restart.
+        assert!(supervisor.prefork(3).is_empty());
+        assert!(supervisor.next_restart().is_some());
+        assert_eq!(supervisor.ids().count(), first + 1);
+        assert_eq!(
+            supervisor.relay_ids().collect::<Vec<_>>(),
+            [Privsep::RELAY_ID]
+        );
+        assert_eq!(supervisor.privsep_id(first + 1), Privsep::RELAY_ID);
+        assert_eq!(supervisor.privsep_id(Privsep::CA_ID), Privsep::CA_ID);
+        assert_eq!(supervisor.instance(Privsep::RELAY_ID), 0);
+        assert_eq!(supervisor.instance(Privsep::METRICS_ID), 0);
+        assert_eq!(supervisor.instance(first + 1), 2);
+
+        assert!(supervisor.prefork(1).is_empty());
+        assert!(supervisor.next_restart().is_none());
+        assert_eq!(supervisor.ids().count(), first - 1);
+    }
+}
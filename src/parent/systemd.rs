@@ -0,0 +1,165 @@
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    time::{clock_gettime, ClockId},
+    unistd::Pid,
+};
+use privsep::net::Fd;
+use privsep_log::{debug, warn};
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::unix::{ffi::OsStrExt, io::RawFd, net::UnixDatagram},
+    path::Path,
+};
+
+/// First file descriptor that is passed by the service manager.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the sockets that were passed by the service manager and their
+/// names, like `sd_listen_fds_with_names(3)`.
+///
+/// This has to be called before the Parent detaches from the foreground.
+pub fn listen_fds() -> Vec<(String, Fd)> {
+    let var = |name| env::var(name).ok();
+    parse_listen_fds(
+        Pid::this(),
+        var("LISTEN_PID").as_deref(),
+        var("LISTEN_FDS").as_deref(),
+        var("LISTEN_FDNAMES").as_deref(),
+    )
+    .into_iter()
+    .map(|(name, fd)| {
+        // Don't pass the sockets to the commands of the Parent.
+        let _ = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC));
+        (name, Fd::from(fd))
+    })
+    .collect()
+}
+
+/// Parse the socket activation variables, the sockets are only meant for
+/// the process with the Id in `LISTEN_PID`.
+fn parse_listen_fds(
+    pid: Pid,
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    names: Option<&str>,
+) -> Vec<(String, RawFd)> {
+    if listen_pid.and_then(|listen_pid| listen_pid.parse().ok()) != Some(pid.as_raw()) {
+        return vec![];
+    }
+    let count = listen_fds
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or_default();
+    let mut names = names.unwrap_or_default().split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count))
+        .map(|fd| {
+            let name = names.next().filter(|name| !name.is_empty());
+            (name.unwrap_or("unknown").to_string(), fd)
+        })
+        .collect()
+}
+
+/// Tell the service manager that the daemon is running.
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Tell the service manager that the configuration is reloaded; `ready`
+/// has to be sent when it is done.
+pub fn reloading() {
+    match clock_gettime(ClockId::CLOCK_MONOTONIC) {
+        Ok(now) => {
+            let usec = now.tv_sec() as u64 * 1_000_000 + now.tv_nsec() as u64 / 1_000;
+            notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec));
+        }
+        Err(_) => notify("RELOADING=1"),
+    }
+}
+
+/// Tell the service manager that the daemon is shutting down.
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Send a state to the service manager if it started the daemon.
+fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    match send(&path, state) {
+        Ok(()) => debug!("notified {}", state.replace('\n', " ")),
+        Err(err) => warn!("Failed to notify the service manager: {}", err),
+    }
+}
+
+/// Send a datagram to the socket of the service manager; a leading `@`
+/// denotes an abstract socket.
+fn send(path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), Path::new(path))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_systemd_listen_fds() {
+        let pid = Pid::from_raw(42);
+        assert!(parse_listen_fds(pid, Some("41"), Some("2"), None).is_empty());
+        assert!(parse_listen_fds(pid, None, Some("2"), None).is_empty());
+        assert_eq!(
+            parse_listen_fds(pid, Some("42"), Some("3"), Some("www::dns")),
+            [
+                ("www".to_string(), 3),
+                ("unknown".to_string(), 4),
+                ("dns".to_string(), 5)
+            ]
+        );
+        assert_eq!(
+            parse_listen_fds(pid, Some("42"), Some("1"), None),
+            [("unknown".to_string(), 3)]
+        );
+    }
+
+    #[test]
+    fn test_systemd_notify() {
+        let path = env::temp_dir().join(format!("relayd-notify-{}.sock", Pid::this()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        // An abstract socket of the service manager.
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let name = format!("relayd-notify-{}", Pid::this());
+            let addr = SocketAddr::from_abstract_name(&name).unwrap();
+            let abstract_manager = UnixDatagram::bind_addr(&addr).unwrap();
+            send(OsStr::new(&format!("@{}", name)), "STOPPING=1").unwrap();
+            let len = abstract_manager.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"STOPPING=1");
+        }
+
+        drop(manager);
+        std::fs::remove_file(&path).unwrap();
+        assert!(send(path.as_os_str(), "READY=1").is_err());
+    }
+}
@@ -1,12 +1,28 @@
+mod filter;
+#[cfg(target_os = "linux")]
+mod netlink;
+mod nftables;
+mod pf;
+mod router;
+
 use crate::{
+    config::{Config, DynamicHost, Forward, Id, Redirect, Target},
     error::Error,
     message::{Data, Type},
-    parent::default_handler,
+    metrics::{RedirectStats, Stats},
+    parent::{default_handler, send_to_peer},
     Child, Privsep,
 };
-use privsep::imsg::Message;
+use filter::{DryRun, Filter};
 use privsep_log::{info, trace};
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+};
+
+pub use filter::{apply, flush as flush_states, remove, RedirectHosts, States};
+pub use router::{backend as routing, Route, RouteTable};
 
 pub async fn main<const N: usize>(
     child: Child<N>,
@@ -17,33 +33,505 @@ pub async fn main<const N: usize>(
         .map_err(|err| privsep::Error::GeneralError(Box::new(err)))?;
 
     let child = Arc::new(child);
+    let mut config = Config::default();
+    // Hosts that are currently up.
+    let mut hosts = HashSet::new();
+    // Resolved addresses by the Id of the configured host.
+    let mut dynamic_hosts = HashMap::<Id, Vec<DynamicHost>>::new();
+    let mut started = false;
+    let mut last_stats = vec![];
+    let mut filter = filter::backend();
+    let mut installed = false;
+    let mut last_hosts = None;
+    let mut last_routes = None;
 
     info!("Started");
 
     loop {
-        tokio::select! {
-            message = default_handler::<Data<'_>>(&child[Privsep::PARENT_ID]) => {
-                match message? {
-                    (Message { id: Type::CONFIG, .. }, _, Data::Config(config)) => {
-                        trace!("received config: {:?}", config);
-                    }
-                    (Message { id: Type::START, .. }, ..) => {
-                        trace!("received start command");
-                    }
-                    _ => return Err(Error::InvalidMessage.into()),
+        let (message, _, data) = default_handler::<Data<'_>>(&child[Privsep::PARENT_ID]).await?;
+        match (Type::from(message.id), data) {
+            (Type::Config, Data::Config(new_config)) => {
+                trace!("received config: {:?}", new_config);
+                for redirect in &config.redirects {
+                    filter.remove(redirect.id);
+                }
+                config = new_config.into_owned();
+                for redirect in &config.redirects {
+                    filter.install(redirect);
+                }
+                // The Parent regenerates the rules of the new configuration.
+                last_hosts = None;
+            }
+            (Type::Start, _) => {
+                trace!("received start command");
+                started = true;
+            }
+            (Type::HostUp, Data::Host(id)) => {
+                trace!("received host UP: {}", id);
+                hosts.insert(id);
+            }
+            (Type::HostDown, Data::Host(id)) => {
+                trace!("received host DOWN: {}", id);
+                if hosts.remove(&id) {
+                    flush(filter.as_mut(), &config, &dynamic_hosts, id);
                 }
             }
-            message = default_handler::<Data<'_>>(&child[Privsep::HEALTH_ID]) => {
-                match message? {
-                    (Message { id: Type::HOST_UP, .. }, _, Data::Host(id)) => {
-                        trace!("received host UP: {}", id);
-                    }
-                    (Message { id: Type::HOST_DOWN, .. }, _, Data::Host(id)) => {
-                        trace!("received host DOWN: {}", id);
-                    }
-                    _ => return Err(Error::InvalidMessage.into()),
+            (Type::HostAdd, Data::DynamicHost(host)) => {
+                trace!("received host ADD: {} {}", host.id, host.addr);
+                dynamic_hosts.entry(host.parent).or_default().push(host);
+            }
+            (Type::HostRemove, Data::Host(id)) => {
+                trace!("received host REMOVE: {}", id);
+                hosts.remove(&id);
+                for members in dynamic_hosts.values_mut() {
+                    members.retain(|member| member.id != id);
                 }
+                dynamic_hosts.retain(|_, members| !members.is_empty());
+            }
+            (Type::Shutdown, _) => {
+                trace!("received shutdown command");
+                break;
+            }
+            _ => return Err(Error::InvalidMessage.into()),
+        }
+
+        // Only report the redirects to the metrics process when they changed.
+        let stats = stats(&config, &hosts, &dynamic_hosts);
+        if stats != last_stats {
+            let data = Data::Stats(Stats::Redirects(stats.clone()));
+            send_to_peer(&child[Privsep::PARENT_ID], Type::Stats, None, &data).await?;
+            last_stats = stats;
+        }
+
+        // The parent installs the ruleset when the hosts changed; a host
+        // that is down is removed from the rules immediately.
+        if started && (installed || !config.redirects.is_empty()) {
+            let selected = update(filter.as_mut(), &config, &hosts, &dynamic_hosts);
+            if last_hosts.as_ref() != Some(&selected) {
+                let data = Data::RedirectHosts(selected.clone());
+                send_to_peer(&child[Privsep::PARENT_ID], Type::RedirectHosts, None, &data).await?;
+                last_hosts = Some(selected);
+                installed = true;
+            }
+
+            // Flush the states after the host was removed from the rules.
+            for states in filter.states() {
+                let data = Data::States(states);
+                send_to_peer(&child[Privsep::PARENT_ID], Type::FlushStates, None, &data).await?;
+            }
+        }
+
+        // The parent installs the routes to the gateways that are up.
+        if started && (last_routes.is_some() || !config.routers.is_empty()) {
+            let routes = router::routes(&config, &hosts, &dynamic_hosts);
+            if last_routes.as_ref() != Some(&routes) {
+                let data = Data::Routes(routes.clone());
+                send_to_peer(&child[Privsep::PARENT_ID], Type::Routes, None, &data).await?;
+                last_routes = Some(routes);
             }
         }
     }
+
+    info!("Terminated");
+
+    Ok(())
+}
+
+/// Returns the addresses of the hosts of a table that are up.
+fn table_hosts(
+    config: &Config,
+    table: Id,
+    hosts: &HashSet<Id>,
+    dynamic_hosts: &HashMap<Id, Vec<DynamicHost>>,
+) -> Vec<IpAddr> {
+    config
+        .table_by_id(table)
+        .filter(|table| !table.disabled)
+        .iter()
+        .flat_map(|table| table.hosts.iter())
+        .flat_map(|host| match dynamic_hosts.get(&host.id) {
+            Some(members) => members
+                .iter()
+                .filter(|member| hosts.contains(&member.id))
+                .map(|member| member.addr)
+                .collect(),
+            None if hosts.contains(&host.id) => host.name.parse().into_iter().collect(),
+            None => vec![],
+        })
+        .collect()
+}
+
+/// Select the first forwarding table of a redirect with hosts that are up.
+fn members(
+    config: &Config,
+    redirect: &Redirect,
+    hosts: &HashSet<Id>,
+    dynamic_hosts: &HashMap<Id, Vec<DynamicHost>>,
+) -> RedirectHosts {
+    for (index, forward) in redirect.forward.iter().enumerate() {
+        if let Target::Table(table) = &forward.target {
+            let hosts = table_hosts(config, table.id, hosts, dynamic_hosts);
+            if !hosts.is_empty() {
+                return RedirectHosts {
+                    id: redirect.id,
+                    forward: Some(index),
+                    hosts,
+                };
+            }
+        }
+    }
+    RedirectHosts {
+        id: redirect.id,
+        forward: None,
+        hosts: vec![],
+    }
+}
+
+/// Update the members of all redirects in the packet filter.
+fn update(
+    filter: &mut dyn Filter,
+    config: &Config,
+    hosts: &HashSet<Id>,
+    dynamic_hosts: &HashMap<Id, Vec<DynamicHost>>,
+) -> Vec<RedirectHosts> {
+    config
+        .redirects
+        .iter()
+        .map(|redirect| {
+            let members = members(config, redirect, hosts, dynamic_hosts);
+            let forward = members
+                .forward
+                .and_then(|index| redirect.forward.get(index));
+            filter.update(redirect.id, forward, &members.hosts);
+            members
+        })
+        .collect()
+}
+
+/// Rules of the redirects that the Parent installs.
+///
+/// The Redirect process only sends the hosts that are up, the Parent
+/// checks them against its own configuration before it generates the
+/// rules, so the unprivileged process cannot install arbitrary rules.
+pub struct Ruleset {
+    filter: Box<dyn Filter>,
+    redirects: Vec<Id>,
+    installed: Option<String>,
+}
+
+impl Default for Ruleset {
+    fn default() -> Self {
+        Self {
+            filter: filter::backend(),
+            redirects: vec![],
+            installed: None,
+        }
+    }
+}
+
+impl Ruleset {
+    /// Install the redirects of a new configuration without any hosts.
+    pub fn configure(&mut self, config: &Config) {
+        for id in self.redirects.drain(..) {
+            self.filter.remove(id);
+        }
+        for redirect in &config.redirects {
+            self.filter.install(redirect);
+            self.redirects.push(redirect.id);
+        }
+    }
+
+    /// Update the hosts of the redirects, returns the rules if they changed.
+    pub fn update(
+        &mut self,
+        config: &Config,
+        dynamic_hosts: &HashMap<Id, DynamicHost>,
+        selected: &[RedirectHosts],
+    ) -> Result<Option<String>, String> {
+        let forwards = selected
+            .iter()
+            .map(|members| selected_forward(config, dynamic_hosts, members))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (members, forward) in selected.iter().zip(forwards) {
+            self.filter.update(members.id, forward, &members.hosts);
+        }
+
+        let rules = self.filter.rules();
+        if self.installed.as_ref() == Some(&rules) {
+            return Ok(None);
+        }
+        self.installed = Some(rules.clone());
+        Ok(Some(rules))
+    }
+
+    /// Check that the states to flush belong to the listen addresses of a
+    /// redirect.
+    pub fn check(&self, config: &Config, states: &States) -> bool {
+        let listen = config
+            .redirects
+            .iter()
+            .flat_map(|redirect| redirect.listen.iter().map(|listen| listen.addr))
+            .collect::<HashSet<_>>();
+        states.listen.iter().all(|addr| listen.contains(addr))
+    }
+}
+
+/// Returns the forwarding table of the hosts that the Redirect process
+/// selected, if they are configured hosts of the table.
+fn selected_forward<'a>(
+    config: &'a Config,
+    dynamic_hosts: &HashMap<Id, DynamicHost>,
+    members: &RedirectHosts,
+) -> Result<Option<&'a Forward>, String> {
+    let redirect = config
+        .redirects
+        .iter()
+        .find(|redirect| redirect.id == members.id)
+        .ok_or_else(|| format!("unknown redirect {}", members.id))?;
+    let forward = match members.forward {
+        Some(index) => redirect.forward.get(index),
+        None => None,
+    };
+    let addrs = match forward.map(|forward| &forward.target) {
+        Some(Target::Table(table)) => config
+            .table_by_id(table.id)
+            .iter()
+            .flat_map(|table| table.hosts.iter())
+            .flat_map(|host| {
+                let resolved = dynamic_hosts
+                    .values()
+                    .filter(|member| member.parent == host.id)
+                    .map(|member| member.addr);
+                host.name.parse().into_iter().chain(resolved)
+            })
+            .collect(),
+        _ => HashSet::new(),
+    };
+    match members.hosts.iter().find(|addr| !addrs.contains(addr)) {
+        Some(addr) => Err(format!("{} is not a host of {}", addr, redirect.name)),
+        None => Ok(forward),
+    }
+}
+
+/// Flush the states of all redirects that forward to a host.
+fn flush(
+    filter: &mut dyn Filter,
+    config: &Config,
+    dynamic_hosts: &HashMap<Id, Vec<DynamicHost>>,
+    id: Id,
+) {
+    for redirect in config.redirects.iter().filter(|r| !r.keep_states) {
+        let addrs = redirect
+            .forward
+            .iter()
+            .filter_map(|forward| match &forward.target {
+                Target::Table(table) => config.table_by_id(table.id),
+                _ => None,
+            })
+            .flat_map(|table| table.hosts.iter())
+            .flat_map(|host| match dynamic_hosts.get(&host.id) {
+                Some(members) => members
+                    .iter()
+                    .filter(|member| member.id == id)
+                    .map(|member| member.addr)
+                    .collect(),
+                None if host.id == id => host.name.parse().into_iter().collect(),
+                None => vec![],
+            })
+            .collect::<HashSet<IpAddr>>();
+        for addr in addrs {
+            filter.flush(redirect.id, addr);
+        }
+    }
+}
+
+/// Returns the rules that would be installed if all hosts were up.
+pub fn dry_run(config: &Config) -> String {
+    let mut filter = DryRun::new(filter::backend());
+    let hosts = config
+        .tables
+        .iter()
+        .flat_map(|table| table.hosts.iter())
+        .map(|host| host.id)
+        .collect();
+    for redirect in &config.redirects {
+        filter.install(redirect);
+    }
+    update(&mut filter, config, &hosts, &HashMap::new());
+    filter.rules()
+}
+
+/// Count the hosts of the forwarding tables of all redirects.
+fn stats(
+    config: &Config,
+    hosts: &HashSet<Id>,
+    dynamic_hosts: &HashMap<Id, Vec<DynamicHost>>,
+) -> Vec<RedirectStats> {
+    config
+        .redirects
+        .iter()
+        .map(|redirect| {
+            let ids = redirect
+                .forward
+                .iter()
+                .filter_map(|forward| match &forward.target {
+                    Target::Table(table) => config.table_by_id(table.id),
+                    _ => None,
+                })
+                .flat_map(|table| table.hosts.iter())
+                .flat_map(|host| match dynamic_hosts.get(&host.id) {
+                    Some(members) => members.iter().map(|member| member.id).collect(),
+                    None => vec![host.id],
+                })
+                .collect::<Vec<_>>();
+            RedirectStats {
+                id: redirect.id,
+                name: redirect.name.clone(),
+                hosts_up: ids.iter().filter(|id| hosts.contains(id)).count(),
+                hosts: ids.len(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filter::Operation;
+
+    #[test]
+    fn test_redirect_dry_run() {
+        let config = Config::parse(
+            "relayd.conf",
+            r#"
+table <web> { 10.0.0.1, 10.0.0.2 }
+redirect "www" {
+	listen on 10.1.0.1 port 80
+	forward to <web> port 8080 check tcp
+}
+"#,
+            Default::default(),
+        )
+        .unwrap();
+        let www = config.redirects[0].id;
+        let web = config.table("web").unwrap();
+        let (first, second) = (web.hosts[0].id, web.hosts[1].id);
+
+        let mut filter = DryRun::new(Box::new(nftables::Nftables::default()));
+        filter.install(&config.redirects[0]);
+
+        let mut hosts = HashSet::new();
+        update(&mut filter, &config, &hosts, &HashMap::new());
+        hosts.insert(first);
+        hosts.insert(second);
+        update(&mut filter, &config, &hosts, &HashMap::new());
+        hosts.remove(&first);
+        flush(&mut filter, &config, &HashMap::new(), first);
+        update(&mut filter, &config, &hosts, &HashMap::new());
+        assert_eq!(
+            filter.states(),
+            [States {
+                listen: vec!["10.1.0.1:80".parse().unwrap()],
+                host: "10.0.0.1".parse().unwrap(),
+            }]
+        );
+        assert!(filter.states().is_empty());
+
+        let mut config = config;
+        config.redirects[0].keep_states = true;
+        flush(&mut filter, &config, &HashMap::new(), second);
+        assert!(filter.states().is_empty());
+
+        assert_eq!(
+            filter.operations,
+            [
+                Operation::Install(www),
+                Operation::Update(www, vec![]),
+                Operation::Update(
+                    www,
+                    vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()]
+                ),
+                Operation::Flush(www, "10.0.0.1".parse().unwrap()),
+                Operation::Update(www, vec!["10.0.0.2".parse().unwrap()]),
+            ]
+        );
+        let rules = filter.rules();
+        assert!(rules.contains("elements = { 0 : 10.0.0.2 . 8080 }"));
+        assert!(rules.contains(&format!(
+            "ip daddr 10.1.0.1 tcp dport 80 dnat ip addr . port to numgen inc mod 1 \
+             map @rdr_{}_ip",
+            www
+        )));
+        let rules = dry_run(&config);
+        assert!(rules.contains("elements = { 0 : 10.0.0.1 . 8080, 1 : 10.0.0.2 . 8080 }"));
+    }
+
+    #[test]
+    fn test_redirect_ruleset() {
+        let config = Config::parse(
+            "relayd.conf",
+            r#"
+table <web> { 10.0.0.1, www.example.com }
+redirect "www" {
+	listen on 10.1.0.1 port 80
+	forward to <web> port 8080 check tcp
+}
+"#,
+            Default::default(),
+        )
+        .unwrap();
+        let www = config.redirects[0].id;
+        let resolved = DynamicHost::new(config.tables[0].hosts[1].id, "10.0.0.2".parse().unwrap());
+        let dynamic_hosts = HashMap::from([(resolved.id, resolved)]);
+        let selected = |hosts: &[&str]| {
+            vec![RedirectHosts {
+                id: www,
+                forward: Some(0),
+                hosts: hosts.iter().map(|host| host.parse().unwrap()).collect(),
+            }]
+        };
+
+        let mut ruleset = Ruleset::default();
+        ruleset.configure(&config);
+        let rules = ruleset
+            .update(
+                &config,
+                &dynamic_hosts,
+                &selected(&["10.0.0.1", "10.0.0.2"]),
+            )
+            .unwrap()
+            .unwrap();
+        assert!(rules.contains("10.0.0.1"));
+        assert!(rules.contains("10.0.0.2"));
+        assert_eq!(
+            ruleset.update(
+                &config,
+                &dynamic_hosts,
+                &selected(&["10.0.0.1", "10.0.0.2"])
+            ),
+            Ok(None)
+        );
+
+        // Hosts and redirects that are not configured are rejected.
+        assert!(ruleset
+            .update(&config, &dynamic_hosts, &selected(&["192.0.2.1"]))
+            .is_err());
+        let mut unknown = selected(&[]);
+        unknown[0].id = 42;
+        assert!(ruleset.update(&config, &dynamic_hosts, &unknown).is_err());
+        let mut unknown = selected(&["10.0.0.1"]);
+        unknown[0].forward = Some(1);
+        assert!(ruleset.update(&config, &dynamic_hosts, &unknown).is_err());
+        assert_eq!(
+            ruleset.update(&config, &HashMap::new(), &selected(&["10.0.0.1"])),
+            Ok(Some(ruleset.filter.rules()))
+        );
+
+        let states = |listen: &str| States {
+            listen: vec![listen.parse().unwrap()],
+            host: "10.0.0.1".parse().unwrap(),
+        };
+        assert!(ruleset.check(&config, &states("10.1.0.1:80")));
+        assert!(!ruleset.check(&config, &states("10.1.0.1:22")));
+    }
 }
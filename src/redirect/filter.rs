@@ -0,0 +1,250 @@
+use super::{nftables::Nftables, pf::Pf};
+use crate::config::{Forward, Id, Redirect};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    process::Stdio,
+};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Hosts of a redirect that are up.
+#[derive(Clone, Debug)]
+pub struct Members {
+    pub redirect: Redirect,
+    /// The first forwarding table with available hosts.
+    pub forward: Option<Forward>,
+    pub hosts: Vec<IpAddr>,
+}
+
+/// States of the connections that a redirect forwarded to a host.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct States {
+    /// Listen addresses of the redirect in the family of the host.
+    pub listen: Vec<SocketAddr>,
+    pub host: IpAddr,
+}
+
+/// Hosts of a redirect that are up, the Redirect process sends them to the
+/// Parent that generates and installs the rules.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RedirectHosts {
+    pub id: Id,
+    /// Index of the selected forwarding table of the redirect.
+    pub forward: Option<usize>,
+    pub hosts: Vec<IpAddr>,
+}
+
+/// Installed redirects, ordered by their Id.
+#[derive(Debug, Default)]
+pub struct Redirects {
+    members: BTreeMap<Id, Members>,
+    /// States that have to be flushed.
+    states: Vec<States>,
+}
+
+impl Redirects {
+    pub fn install(&mut self, redirect: &Redirect) {
+        self.members.insert(
+            redirect.id,
+            Members {
+                redirect: redirect.clone(),
+                forward: None,
+                hosts: vec![],
+            },
+        );
+    }
+
+    pub fn update(&mut self, id: Id, forward: Option<&Forward>, hosts: &[IpAddr]) {
+        if let Some(members) = self.members.get_mut(&id) {
+            members.forward = forward.cloned();
+            members.hosts = hosts.to_vec();
+        }
+    }
+
+    pub fn remove(&mut self, id: Id) {
+        self.members.remove(&id);
+    }
+
+    pub fn flush(&mut self, id: Id, host: IpAddr) {
+        if let Some(members) = self.members.get(&id) {
+            let listen = members
+                .redirect
+                .listen
+                .iter()
+                .map(|listen| listen.addr)
+                .filter(|addr| addr.is_ipv4() == host.is_ipv4())
+                .collect::<Vec<_>>();
+            if !listen.is_empty() {
+                self.states.push(States { listen, host });
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Members> {
+        self.members.values()
+    }
+}
+
+/// Packet filter backend of the redirects.
+///
+/// The backends only generate the rules; the Parent generates them from the
+/// hosts that the Redirect process selected and installs them with the
+/// privileged `apply`.
+pub trait Filter: Send {
+    /// The redirects with their current members.
+    fn redirects(&mut self) -> &mut Redirects;
+
+    /// Install the rules of a redirect without any hosts.
+    fn install(&mut self, redirect: &Redirect) {
+        self.redirects().install(redirect)
+    }
+
+    /// Update the forwarding table and the hosts that are up.
+    fn update(&mut self, id: Id, forward: Option<&Forward>, hosts: &[IpAddr]) {
+        self.redirects().update(id, forward, hosts)
+    }
+
+    /// Remove the rules of a redirect.
+    fn remove(&mut self, id: Id) {
+        self.redirects().remove(id)
+    }
+
+    /// Flush the states of connections that a redirect forwarded to a host.
+    fn flush(&mut self, id: Id, host: IpAddr) {
+        self.redirects().flush(id, host)
+    }
+
+    /// Returns the states that have to be flushed after installing the rules.
+    fn states(&mut self) -> Vec<States> {
+        std::mem::take(&mut self.redirects().states)
+    }
+
+    /// Generate the complete ruleset of all redirects.
+    fn rules(&self) -> String;
+}
+
+/// Operation that was recorded by the dry-run backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    Install(Id),
+    Update(Id, Vec<IpAddr>),
+    Remove(Id),
+    Flush(Id, IpAddr),
+}
+
+/// Backend that records the operations instead of installing rules.
+///
+/// The rules are generated by the wrapped backend, e.g. to show them with
+/// `relayd -n`.
+pub struct DryRun {
+    filter: Box<dyn Filter>,
+    pub operations: Vec<Operation>,
+}
+
+impl DryRun {
+    pub fn new(filter: Box<dyn Filter>) -> Self {
+        Self {
+            filter,
+            operations: vec![],
+        }
+    }
+}
+
+impl Filter for DryRun {
+    fn redirects(&mut self) -> &mut Redirects {
+        self.filter.redirects()
+    }
+
+    fn install(&mut self, redirect: &Redirect) {
+        self.operations.push(Operation::Install(redirect.id));
+        self.filter.install(redirect)
+    }
+
+    fn update(&mut self, id: Id, forward: Option<&Forward>, hosts: &[IpAddr]) {
+        self.operations.push(Operation::Update(id, hosts.to_vec()));
+        self.filter.update(id, forward, hosts)
+    }
+
+    fn remove(&mut self, id: Id) {
+        self.operations.push(Operation::Remove(id));
+        self.filter.remove(id)
+    }
+
+    fn flush(&mut self, id: Id, host: IpAddr) {
+        self.operations.push(Operation::Flush(id, host));
+        self.filter.flush(id, host)
+    }
+
+    fn rules(&self) -> String {
+        self.filter.rules()
+    }
+}
+
+/// Returns the packet filter backend of the platform.
+pub fn backend() -> Box<dyn Filter> {
+    if cfg!(target_os = "linux") {
+        Box::new(Nftables::default())
+    } else {
+        Box::new(Pf::default())
+    }
+}
+
+/// Run a privileged command of the packet filter.
+pub async fn run(command: &str, args: &[&str], input: &str) -> io::Result<()> {
+    execute(command, args, input, Stdio::null()).await.map(drop)
+}
+
+/// Run a privileged command of the packet filter and return its output.
+pub async fn output(command: &str, args: &[&str]) -> io::Result<String> {
+    let stdout = execute(command, args, "", Stdio::piped()).await?;
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// Run a command and return its standard output if it succeeded.
+async fn execute(command: &str, args: &[&str], input: &str, stdout: Stdio) -> io::Result<Vec<u8>> {
+    let mut child = Command::new(command)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(stdout)
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).await?;
+    }
+
+    let output = child.wait_with_output().await?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(io::Error::other(format!(
+            "{} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Install the rules with the packet filter of the platform.
+pub async fn apply(rules: &str) -> io::Result<()> {
+    if cfg!(target_os = "linux") {
+        super::nftables::apply(rules).await
+    } else {
+        super::pf::apply(rules).await
+    }
+}
+
+/// Flush the states with the packet filter of the platform.
+pub async fn flush(states: &States) -> io::Result<()> {
+    if cfg!(target_os = "linux") {
+        super::nftables::flush(states).await
+    } else {
+        super::pf::flush(states).await
+    }
+}
+
+/// Remove all rules of the redirects.
+pub async fn remove() -> io::Result<()> {
+    apply(&backend().rules()).await
+}
@@ -0,0 +1,169 @@
+use super::router::{Route, Routing};
+use nix::{
+    libc,
+    sys::socket::{
+        bind, recv, sendto, socket, AddressFamily, MsgFlags, NetlinkAddr, SockAddr, SockFlag,
+        SockProtocol, SockType,
+    },
+    unistd::close,
+};
+use std::{io, net::IpAddr};
+
+/// Size of the netlink message header.
+const NLMSG_HDRLEN: usize = 16;
+/// Size of the route message header.
+const RTMSG_LEN: usize = 12;
+
+/// Linux routing backend using rtnetlink.
+///
+/// Linux has no route labels, the `rtlabel` of a router is ignored.
+pub struct Netlink;
+
+impl Routing for Netlink {
+    fn add(&mut self, route: &Route) -> io::Result<()> {
+        // Linux identifies a route by its prefix, table and metric, append
+        // the routes of gateways with the same priority instead of
+        // replacing each other's.
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_APPEND;
+        request(&message(libc::RTM_NEWROUTE, flags as u16, route))
+    }
+
+    fn delete(&mut self, route: &Route) -> io::Result<()> {
+        request(&message(libc::RTM_DELROUTE, 0, route))
+    }
+}
+
+/// Append a route attribute, padded to 4 bytes.
+fn attribute(buf: &mut Vec<u8>, typ: u16, data: &[u8]) {
+    buf.extend_from_slice(&(4 + data.len() as u16).to_ne_bytes());
+    buf.extend_from_slice(&typ.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize((buf.len() + 3) & !3, 0);
+}
+
+fn octets(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/// Encode a route request.
+fn message(typ: u16, flags: u16, route: &Route) -> Vec<u8> {
+    let family = match route.prefix.addr {
+        IpAddr::V4(_) => libc::AF_INET,
+        IpAddr::V6(_) => libc::AF_INET6,
+    };
+    let flags = flags | (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16;
+
+    // The header length is set when the message is complete.
+    let mut buf = vec![0; 4];
+    buf.extend_from_slice(&typ.to_ne_bytes());
+    buf.extend_from_slice(&flags.to_ne_bytes());
+    buf.extend_from_slice(&1u32.to_ne_bytes());
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+
+    buf.extend_from_slice(&[
+        family as u8,
+        route.prefix.len,
+        0,
+        0,
+        libc::RT_TABLE_UNSPEC,
+        libc::RTPROT_STATIC,
+        libc::RT_SCOPE_UNIVERSE,
+        libc::RTN_UNICAST,
+    ]);
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    debug_assert_eq!(buf.len(), NLMSG_HDRLEN + RTMSG_LEN);
+
+    let table = route.rtable.unwrap_or(libc::RT_TABLE_MAIN.into());
+    attribute(&mut buf, libc::RTA_TABLE, &table.to_ne_bytes());
+    attribute(&mut buf, libc::RTA_DST, &octets(&route.prefix.addr));
+    attribute(&mut buf, libc::RTA_GATEWAY, &octets(&route.gateway));
+    attribute(&mut buf, libc::RTA_PRIORITY, &route.metric.to_ne_bytes());
+
+    let len = buf.len() as u32;
+    buf[..4].copy_from_slice(&len.to_ne_bytes());
+    buf
+}
+
+/// Send a request to the kernel and wait for the acknowledgement.
+fn request(message: &[u8]) -> io::Result<()> {
+    let fd = socket(
+        AddressFamily::Netlink,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkRoute,
+    )?;
+    let result = (|| {
+        let kernel = SockAddr::Netlink(NetlinkAddr::new(0, 0));
+        bind(fd, &SockAddr::Netlink(NetlinkAddr::new(0, 0)))?;
+        sendto(fd, message, &kernel, MsgFlags::empty())?;
+
+        let mut buf = [0; 1024];
+        let len = recv(fd, &mut buf, MsgFlags::empty())?;
+        acknowledgement(&buf[..len])
+    })();
+    let _ = close(fd);
+    result
+}
+
+/// Parse the error code of the kernel's acknowledgement.
+fn acknowledgement(buf: &[u8]) -> io::Result<()> {
+    let typ = buf
+        .get(4..6)
+        .map(|typ| u16::from_ne_bytes([typ[0], typ[1]]));
+    let error = buf
+        .get(NLMSG_HDRLEN..NLMSG_HDRLEN + 4)
+        .map(|error| i32::from_ne_bytes([error[0], error[1], error[2], error[3]]));
+    match (typ, error) {
+        (Some(typ), Some(0)) if typ == libc::NLMSG_ERROR as u16 => Ok(()),
+        (Some(typ), Some(error)) if typ == libc::NLMSG_ERROR as u16 => {
+            Err(io::Error::from_raw_os_error(-error))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid netlink response",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Prefix;
+
+    #[test]
+    fn test_netlink_message() {
+        let route = Route {
+            prefix: Prefix {
+                addr: "10.0.0.0".parse().unwrap(),
+                len: 8,
+            },
+            gateway: "192.0.2.1".parse().unwrap(),
+            metric: 10,
+            rtable: None,
+            label: None,
+        };
+        let message = message(libc::RTM_NEWROUTE, 0, &route);
+
+        assert_eq!(message.len(), 60);
+        assert_eq!(message[..4], 60u32.to_ne_bytes());
+        assert_eq!(message[16..20], [libc::AF_INET as u8, 8, 0, 0]);
+        assert_eq!(message[28..30], 8u16.to_ne_bytes());
+        assert_eq!(message[30..32], libc::RTA_TABLE.to_ne_bytes());
+        assert_eq!(message[32..36], 254u32.to_ne_bytes());
+        assert_eq!(message[40..44], [10, 0, 0, 0]);
+        assert_eq!(message[48..52], [192, 0, 2, 1]);
+        assert_eq!(message[56..60], 10u32.to_ne_bytes());
+
+        let mut ack = vec![0; NLMSG_HDRLEN + 4];
+        ack[4..6].copy_from_slice(&(libc::NLMSG_ERROR as u16).to_ne_bytes());
+        assert!(acknowledgement(&ack).is_ok());
+        ack[16..20].copy_from_slice(&(-libc::EEXIST).to_ne_bytes());
+        assert_eq!(
+            acknowledgement(&ack).unwrap_err().raw_os_error(),
+            Some(libc::EEXIST)
+        );
+    }
+}
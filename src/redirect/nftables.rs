@@ -0,0 +1,566 @@
+use super::filter::{run, Filter, Members, Redirects, States};
+use crate::config::{Forward, Listen, Mode, Redirect};
+use std::{collections::BTreeSet, fmt::Write as _, io, net::IpAddr};
+
+/// Linux nftables backend.
+#[derive(Debug, Default)]
+pub struct Nftables {
+    redirects: Redirects,
+}
+
+impl Filter for Nftables {
+    fn redirects(&mut self) -> &mut Redirects {
+        &mut self.redirects
+    }
+
+    fn rules(&self) -> String {
+        ruleset(&self.redirects.iter().collect::<Vec<_>>())
+    }
+}
+
+/// Address family of the listen address or host.
+fn family(addr: &IpAddr) -> (&'static str, &'static str) {
+    match addr {
+        IpAddr::V4(_) => ("ip", "ipv4_addr"),
+        IpAddr::V6(_) => ("ip6", "ipv6_addr"),
+    }
+}
+
+/// Generate the ruleset of the relayd tables.
+///
+/// The ruleset replaces the existing tables in a single transaction.  Each
+/// redirect gets a map per address family with the hosts that are up and a
+/// chain that translates the destination of connections to its listen
+/// addresses to one of the hosts in the map.  Redirects that route to the hosts forward the
+/// packets from the ingress hook of their interfaces instead.
+fn ruleset(members: &[&Members]) -> String {
+    let inet = format!("table inet {}", crate::NFT_TABLE);
+    let netdev = format!("table netdev {}", crate::NFT_TABLE);
+    let mut out = format!("{}\ndelete {}\n{}\ndelete {}\n", inet, inet, netdev, netdev);
+
+    let (route, nat): (Vec<&Members>, _) = members
+        .iter()
+        .copied()
+        .partition(|members| members.forward.as_ref().is_some_and(|f| f.route));
+    if !nat.is_empty() {
+        let _ = write!(out, "{} {{\n{}}}\n", inet, nat_ruleset(&nat));
+    }
+    if !route.is_empty() {
+        let _ = write!(out, "{} {{\n{}}}\n", netdev, route_ruleset(&route));
+    }
+
+    out
+}
+
+/// Chains of the redirects that translate the destination.
+fn nat_ruleset(members: &[&Members]) -> String {
+    let mut out = String::new();
+    for Members {
+        redirect,
+        forward,
+        hosts,
+    } in members.iter().copied()
+    {
+        let _ = writeln!(out, "\t# redirect \"{}\"", redirect.name.escape_default());
+        if let Some(tag) = &redirect.tag {
+            let _ = writeln!(
+                out,
+                "\t# pftag \"{}\" mark {:#010x}",
+                tag.escape_default(),
+                mark(tag)
+            );
+        }
+        if let Some(timeout) = redirect.session_timeout {
+            let _ = writeln!(out, "\tct timeout rdr_{}_timeout {{", redirect.id);
+            let _ = writeln!(out, "\t\tprotocol tcp");
+            let _ = writeln!(out, "\t\tpolicy = {{ established: {} }}", timeout.as_secs());
+            let _ = writeln!(out, "\t}}");
+        }
+
+        let families = redirect
+            .listen
+            .iter()
+            .map(|listen| family(&listen.addr.ip()))
+            .collect::<BTreeSet<_>>();
+        for (family, typ) in families {
+            let elements = hosts
+                .iter()
+                .filter(|host| self::family(host).0 == family)
+                .enumerate()
+                .map(|(i, host)| match forward.as_ref().and_then(|f| f.port) {
+                    Some(port) => format!("{} : {} . {}", i, host, port),
+                    None => format!("{} : {}", i, host),
+                })
+                .collect::<Vec<_>>();
+            let _ = writeln!(out, "\tmap rdr_{}_{} {{", redirect.id, family);
+            match forward.as_ref().and_then(|f| f.port) {
+                Some(_) => {
+                    let _ = writeln!(out, "\t\ttype mark : {} . inet_service", typ);
+                }
+                None => {
+                    let _ = writeln!(out, "\t\ttype mark : {}", typ);
+                }
+            }
+            if !elements.is_empty() {
+                let _ = writeln!(out, "\t\telements = {{ {} }}", elements.join(", "));
+            }
+            let _ = writeln!(out, "\t}}");
+        }
+
+        let _ = writeln!(out, "\tchain rdr_{} {{", redirect.id);
+        for listen in &redirect.listen {
+            if let Some(rule) = dnat(redirect, listen, forward.as_ref(), hosts) {
+                let _ = writeln!(out, "\t\t{}", rule);
+            }
+        }
+        let _ = writeln!(out, "\t}}");
+    }
+
+    // Timeouts have to be attached before the connections are tracked.
+    let timeouts = members
+        .iter()
+        .filter(|members| members.redirect.session_timeout.is_some())
+        .collect::<Vec<_>>();
+    if !timeouts.is_empty() {
+        let _ = writeln!(out, "\tchain timeouts {{");
+        let _ = writeln!(
+            out,
+            "\t\ttype filter hook prerouting priority raw; policy accept;"
+        );
+        for Members { redirect, .. } in timeouts {
+            for listen in &redirect.listen {
+                let _ = writeln!(
+                    out,
+                    "\t\t{}ct timeout set \"rdr_{}_timeout\"",
+                    matches(listen),
+                    redirect.id
+                );
+            }
+        }
+        let _ = writeln!(out, "\t}}");
+    }
+
+    // The translation only sees the first packet of a connection, so the
+    // tag is kept in the connection mark and copied to all of its packets.
+    let tags = members
+        .iter()
+        .filter_map(|members| Some((&members.redirect, mark(members.redirect.tag.as_ref()?))))
+        .collect::<Vec<_>>();
+    if !tags.is_empty() {
+        let _ = writeln!(out, "\tchain tags {{");
+        let _ = writeln!(
+            out,
+            "\t\ttype filter hook prerouting priority mangle; policy accept;"
+        );
+        for (redirect, mark) in &tags {
+            for listen in &redirect.listen {
+                let _ = writeln!(out, "\t\t{}ct mark set {:#010x}", matches(listen), mark);
+            }
+        }
+        let mut marks = tags.iter().map(|(_, mark)| *mark).collect::<Vec<_>>();
+        marks.sort_unstable();
+        marks.dedup();
+        for mark in marks {
+            let _ = writeln!(out, "\t\tct mark {:#010x} meta mark set ct mark", mark);
+        }
+        let _ = writeln!(out, "\t}}");
+    }
+
+    let _ = writeln!(out, "\tchain prerouting {{");
+    let _ = writeln!(
+        out,
+        "\t\ttype nat hook prerouting priority dstnat; policy accept;"
+    );
+    for Members { redirect, .. } in members.iter().copied() {
+        let _ = writeln!(out, "\t\tjump rdr_{}", redirect.id);
+    }
+    let _ = writeln!(out, "\t}}");
+
+    out
+}
+
+/// Chains of the redirects that route to the hosts.
+///
+/// Each listen address gets a chain in the ingress hook of its interface
+/// that forwards the packets to a host without changing the destination.
+fn route_ruleset(members: &[&Members]) -> String {
+    let mut out = String::new();
+    for Members {
+        redirect,
+        forward,
+        hosts,
+    } in members.iter().copied()
+    {
+        let _ = writeln!(out, "\t# redirect \"{}\"", redirect.name.escape_default());
+        for (i, listen) in redirect.listen.iter().enumerate() {
+            let interface = match &listen.interface {
+                Some(interface) => interface.escape_default().to_string(),
+                None => continue,
+            };
+            let _ = writeln!(out, "\tchain route_{}_{} {{", redirect.id, i);
+            let _ = writeln!(
+                out,
+                "\t\ttype filter hook ingress device \"{}\" priority 0; policy accept;",
+                interface
+            );
+            if let Some(rule) = fwd(redirect, listen, forward.as_ref(), hosts) {
+                // The packets leave from the ingress hook, so the tag is
+                // only set on the packets that are forwarded.
+                if let Some(tag) = &redirect.tag {
+                    let _ = writeln!(
+                        out,
+                        "\t\t{} daddr {} tcp dport {} meta mark set {:#010x}",
+                        family(&listen.addr.ip()).0,
+                        listen.addr.ip(),
+                        listen.addr.port(),
+                        mark(tag)
+                    );
+                }
+                let _ = writeln!(out, "\t\t{} device \"{}\"", rule, interface);
+            }
+            let _ = writeln!(out, "\t}}");
+        }
+    }
+    out
+}
+
+/// Packet mark of a pftag, a 32-bit FNV-1a hash of its name.
+fn mark(tag: &str) -> u32 {
+    tag.bytes().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Match connections to a listen address.
+fn matches(listen: &Listen) -> String {
+    let mut rule = String::new();
+    if let Some(interface) = &listen.interface {
+        let _ = write!(rule, "iifname \"{}\" ", interface.escape_default());
+    }
+    let _ = write!(
+        rule,
+        "{} daddr {} tcp dport {} ",
+        family(&listen.addr.ip()).0,
+        listen.addr.ip(),
+        listen.addr.port()
+    );
+    rule
+}
+
+/// Translation rule of a listen address to the hosts in the map of its
+/// family, if hosts of the family are up.
+fn dnat(
+    redirect: &Redirect,
+    listen: &Listen,
+    forward: Option<&Forward>,
+    hosts: &[IpAddr],
+) -> Option<String> {
+    let forward = forward?;
+    let (family, _) = family(&listen.addr.ip());
+    let hosts = hosts
+        .iter()
+        .filter(|host| self::family(host).0 == family)
+        .collect::<Vec<_>>();
+    if hosts.is_empty() {
+        return None;
+    }
+
+    let mut rule = matches(listen);
+
+    // Sticky addresses hash the source to keep it on the same host.
+    let mode = if redirect.sticky {
+        Mode::SourceHash
+    } else {
+        forward.mode
+    };
+    let selector = match mode {
+        Mode::Hash | Mode::SourceHash => format!("jhash {} saddr mod {}", family, hosts.len()),
+        Mode::Random => format!("numgen random mod {}", hosts.len()),
+        Mode::Loadbalance | Mode::LeastStates | Mode::RoundRobin => {
+            format!("numgen inc mod {}", hosts.len())
+        }
+    };
+    let map = format!("@rdr_{}_{}", redirect.id, family);
+    match forward.port {
+        Some(_) => {
+            let _ = write!(
+                rule,
+                "dnat {} addr . port to {} map {}",
+                family, selector, map
+            );
+        }
+        None => {
+            let _ = write!(rule, "dnat {} to {} map {}", family, selector, map);
+        }
+    }
+
+    Some(rule)
+}
+
+/// Forwarding rule of a listen address, if hosts of its family are up.
+///
+/// The ingress hook does not track connections, so the host is selected
+/// by a hash of the source to send all packets of a connection to it.
+fn fwd(
+    redirect: &Redirect,
+    listen: &Listen,
+    forward: Option<&Forward>,
+    hosts: &[IpAddr],
+) -> Option<String> {
+    let forward = forward?;
+    let (family, _) = family(&listen.addr.ip());
+    let hosts = hosts
+        .iter()
+        .filter(|host| self::family(host).0 == family)
+        .collect::<Vec<_>>();
+    if hosts.is_empty() {
+        return None;
+    }
+
+    let source = match forward.mode {
+        _ if redirect.sticky => format!("{} saddr", family),
+        Mode::Hash | Mode::SourceHash => format!("{} saddr", family),
+        _ => format!("{} saddr . tcp sport", family),
+    };
+    let map = hosts
+        .iter()
+        .enumerate()
+        .map(|(i, host)| format!("{} : {}", i, host))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(format!(
+        "{} daddr {} tcp dport {} fwd {} to jhash {} mod {} map {{ {} }}",
+        family,
+        listen.addr.ip(),
+        listen.addr.port(),
+        family,
+        source,
+        hosts.len(),
+        map
+    ))
+}
+
+/// Apply a ruleset with `nft`.
+pub async fn apply(ruleset: &str) -> io::Result<()> {
+    run(crate::NFT_COMMAND, &["-f", "-"], ruleset).await
+}
+
+/// Delete the tracked connections to a host with `conntrack`.
+///
+/// After the translation, the host is the source of the replies.
+pub async fn flush(states: &States) -> io::Result<()> {
+    let host = states.host.to_string();
+    for listen in &states.listen {
+        let family = match listen.ip() {
+            IpAddr::V4(_) => "ipv4",
+            IpAddr::V6(_) => "ipv6",
+        };
+        let (addr, port) = (listen.ip().to_string(), listen.port().to_string());
+        #[rustfmt::skip]
+        let args = [
+            "-D", "-f", family, "-p", "tcp",
+            "--orig-dst", &addr, "--orig-port-dst", &port,
+            "--reply-src", &host,
+        ];
+        match run(crate::CONNTRACK_COMMAND, &args, "").await {
+            // conntrack fails if there was nothing to delete.
+            Err(err) if err.to_string().contains("0 flow entries") => (),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Reference, Target};
+    use std::time::Duration;
+
+    #[test]
+    fn test_nftables_ruleset() {
+        let listen = |addr: &str, interface: Option<&str>| Listen {
+            addr: addr.parse().unwrap(),
+            tls: false,
+            interface: interface.map(ToString::to_string),
+        };
+        let forward = |port, mode| Forward {
+            target: Target::Table(Reference::new("web")),
+            port,
+            mode,
+            check: None,
+            route: false,
+        };
+        let www = Redirect {
+            id: 1,
+            name: "www".to_string(),
+            listen: vec![
+                listen("10.1.0.1:80", Some("em0")),
+                listen("[fd00::1]:80", None),
+                listen("10.1.0.5:80", None),
+            ],
+            forward: vec![forward(Some(8080), Mode::RoundRobin)],
+            ..Default::default()
+        };
+        let dns = Redirect {
+            id: 2,
+            name: "dns".to_string(),
+            listen: vec![listen("10.1.0.2:53", None)],
+            forward: vec![forward(None, Mode::SourceHash)],
+            ..Default::default()
+        };
+        let mut filter = Nftables::default();
+        assert_eq!(
+            filter.rules(),
+            "table inet relayd\ndelete table inet relayd\ntable netdev relayd\ndelete table netdev relayd\n"
+        );
+
+        filter.install(&www);
+        filter.install(&dns);
+        filter.update(
+            www.id,
+            www.forward.first(),
+            &[
+                "10.0.0.1".parse().unwrap(),
+                "10.0.0.2".parse().unwrap(),
+                "fd00::2".parse().unwrap(),
+            ],
+        );
+        assert_eq!(
+            filter.rules(),
+            "table inet relayd
+delete table inet relayd
+table netdev relayd
+delete table netdev relayd
+table inet relayd {
+\t# redirect \"www\"
+\tmap rdr_1_ip {
+\t\ttype mark : ipv4_addr . inet_service
+\t\telements = { 0 : 10.0.0.1 . 8080, 1 : 10.0.0.2 . 8080 }
+\t}
+\tmap rdr_1_ip6 {
+\t\ttype mark : ipv6_addr . inet_service
+\t\telements = { 0 : fd00::2 . 8080 }
+\t}
+\tchain rdr_1 {
+\t\tiifname \"em0\" ip daddr 10.1.0.1 tcp dport 80 dnat ip addr . port to numgen inc mod 2 map @rdr_1_ip
+\t\tip6 daddr fd00::1 tcp dport 80 dnat ip6 addr . port to numgen inc mod 1 map @rdr_1_ip6
+\t\tip daddr 10.1.0.5 tcp dport 80 dnat ip addr . port to numgen inc mod 2 map @rdr_1_ip
+\t}
+\t# redirect \"dns\"
+\tmap rdr_2_ip {
+\t\ttype mark : ipv4_addr
+\t}
+\tchain rdr_2 {
+\t}
+\tchain prerouting {
+\t\ttype nat hook prerouting priority dstnat; policy accept;
+\t\tjump rdr_1
+\t\tjump rdr_2
+\t}
+}
+"
+        );
+        filter.remove(www.id);
+        filter.remove(dns.id);
+        assert_eq!(
+            filter.rules(),
+            "table inet relayd\ndelete table inet relayd\ntable netdev relayd\ndelete table netdev relayd\n"
+        );
+
+        let tagged = Redirect {
+            id: 3,
+            name: "tagged".to_string(),
+            listen: vec![listen("10.1.0.3:80", None)],
+            forward: vec![forward(None, Mode::RoundRobin)],
+            tag: Some("RELAYD".to_string()),
+            sticky: true,
+            session_timeout: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        filter.install(&tagged);
+        filter.update(
+            tagged.id,
+            tagged.forward.first(),
+            &["10.0.0.1".parse().unwrap()],
+        );
+        assert_eq!(
+            filter.rules(),
+            "table inet relayd
+delete table inet relayd
+table netdev relayd
+delete table netdev relayd
+table inet relayd {
+\t# redirect \"tagged\"
+\t# pftag \"RELAYD\" mark 0xed9c6312
+\tct timeout rdr_3_timeout {
+\t\tprotocol tcp
+\t\tpolicy = { established: 60 }
+\t}
+\tmap rdr_3_ip {
+\t\ttype mark : ipv4_addr
+\t\telements = { 0 : 10.0.0.1 }
+\t}
+\tchain rdr_3 {
+\t\tip daddr 10.1.0.3 tcp dport 80 dnat ip to jhash ip saddr mod 1 map @rdr_3_ip
+\t}
+\tchain timeouts {
+\t\ttype filter hook prerouting priority raw; policy accept;
+\t\tip daddr 10.1.0.3 tcp dport 80 ct timeout set \"rdr_3_timeout\"
+\t}
+\tchain tags {
+\t\ttype filter hook prerouting priority mangle; policy accept;
+\t\tip daddr 10.1.0.3 tcp dport 80 ct mark set 0xed9c6312
+\t\tct mark 0xed9c6312 meta mark set ct mark
+\t}
+\tchain prerouting {
+\t\ttype nat hook prerouting priority dstnat; policy accept;
+\t\tjump rdr_3
+\t}
+}
+"
+        );
+        filter.remove(tagged.id);
+
+        let dsr = Redirect {
+            id: 4,
+            name: "dsr".to_string(),
+            listen: vec![
+                listen("10.1.0.4:80", Some("em0")),
+                listen("[fd00::4]:80", Some("em1")),
+            ],
+            forward: vec![Forward {
+                route: true,
+                ..forward(Some(8080), Mode::RoundRobin)
+            }],
+            tag: Some("RELAYD".to_string()),
+            ..Default::default()
+        };
+        filter.install(&dsr);
+        filter.update(
+            dsr.id,
+            dsr.forward.first(),
+            &["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+        );
+        assert_eq!(
+            filter.rules(),
+            "table inet relayd
+delete table inet relayd
+table netdev relayd
+delete table netdev relayd
+table netdev relayd {
+\t# redirect \"dsr\"
+\tchain route_4_0 {
+\t\ttype filter hook ingress device \"em0\" priority 0; policy accept;
+\t\tip daddr 10.1.0.4 tcp dport 80 meta mark set 0xed9c6312
+\t\tip daddr 10.1.0.4 tcp dport 80 fwd ip to jhash ip saddr . tcp sport mod 2 \
+map { 0 : 10.0.0.1, 1 : 10.0.0.2 } device \"em0\"
+\t}
+\tchain route_4_1 {
+\t\ttype filter hook ingress device \"em1\" priority 0; policy accept;
+\t}
+}
+"
+        );
+    }
+}
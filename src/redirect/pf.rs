@@ -0,0 +1,276 @@
+use super::filter::{output, run, Filter, Members, Redirects, States};
+use crate::config::Mode;
+use std::{
+    fmt::Write as _,
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+/// OpenBSD pf backend.
+#[derive(Debug, Default)]
+pub struct Pf {
+    redirects: Redirects,
+}
+
+impl Filter for Pf {
+    fn redirects(&mut self) -> &mut Redirects {
+        &mut self.redirects
+    }
+
+    fn rules(&self) -> String {
+        self.redirects.iter().map(ruleset).collect()
+    }
+}
+
+/// Generate the rules of a redirect for the relayd anchor.
+///
+/// Each redirect gets a persistent table with the hosts that are up and a
+/// rule per listen address that redirects connections to the table.
+fn ruleset(members: &Members) -> String {
+    let Members {
+        redirect,
+        forward,
+        hosts,
+    } = members;
+    let mut out = format!("# redirect \"{}\"\n", redirect.name.escape_default());
+    let _ = write!(out, "table <rdr_{}> persist", redirect.id);
+    if !hosts.is_empty() {
+        let elements = hosts.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let _ = write!(out, " {{ {} }}", elements.join(" "));
+    }
+    out.push('\n');
+
+    let forward = match forward {
+        Some(forward) => forward,
+        None => return out,
+    };
+    let pool = match forward.mode {
+        Mode::Random => "random",
+        Mode::RoundRobin => "round-robin",
+        Mode::LeastStates => "least-states",
+        Mode::Hash | Mode::Loadbalance | Mode::SourceHash => "source-hash",
+    };
+    for listen in &redirect.listen {
+        let family = match listen.addr.ip() {
+            IpAddr::V4(_) => "inet",
+            IpAddr::V6(_) => "inet6",
+        };
+        // A match rule only tags the packets and leaves the decision to
+        // the following rules.
+        let action = if redirect.match_tag {
+            "match in"
+        } else {
+            "pass in quick"
+        };
+        let _ = write!(out, "{} ", action);
+        if let Some(interface) = &listen.interface {
+            let _ = write!(out, "on {} ", interface);
+        }
+        let _ = write!(
+            out,
+            "{} proto tcp to {} port {}",
+            family,
+            listen.addr.ip(),
+            listen.addr.port()
+        );
+        if let Some(tag) = &redirect.tag {
+            let _ = write!(out, " tag \"{}\"", tag.escape_default());
+        }
+        match redirect.session_timeout {
+            Some(timeout) if !redirect.match_tag => {
+                let _ = write!(out, " keep state (tcp.established {})", timeout.as_secs());
+            }
+            _ => (),
+        }
+        if forward.route {
+            let _ = write!(out, " route-to <rdr_{}>", redirect.id);
+        } else {
+            let _ = write!(out, " rdr-to <rdr_{}>", redirect.id);
+            if let Some(port) = forward.port {
+                let _ = write!(out, " port {}", port);
+            }
+        }
+        let _ = write!(out, " {}", pool);
+        if redirect.sticky {
+            let _ = write!(out, " sticky-address");
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Load the rules into the relayd anchor with `pfctl`.
+pub async fn apply(rules: &str) -> io::Result<()> {
+    run(
+        crate::PFCTL_COMMAND,
+        &["-a", crate::PF_RELAYD_ANCHOR, "-f", "-"],
+        rules,
+    )
+    .await
+}
+
+/// Kill the states of the connections to a host with `pfctl`.
+///
+/// pfctl can only kill the states by the addresses of one side, so the
+/// states that were redirected from a listen address to the host are
+/// looked up and killed by their Id.
+pub async fn flush(states: &States) -> io::Result<()> {
+    let list = output(crate::PFCTL_COMMAND, &["-vv", "-s", "states"]).await?;
+    for id in state_ids(&list, states) {
+        run(crate::PFCTL_COMMAND, &["-k", "id", "-k", &id], "").await?;
+    }
+    Ok(())
+}
+
+/// Find the Ids of the redirected states in the verbose state listing,
+/// an inbound state is printed as `host:port (listen:port) <- client:port`.
+fn state_ids(list: &str, states: &States) -> Vec<String> {
+    let mut ids = vec![];
+    let mut matched = false;
+    for line in list.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            matched = match fields.as_slice() {
+                [_, "tcp", host, listen, "<-", ..] => {
+                    let listen = listen
+                        .strip_prefix('(')
+                        .and_then(|listen| listen.strip_suffix(')'))
+                        .and_then(parse_host);
+                    parse_host(host).map(|host| host.ip()) == Some(states.host)
+                        && listen.is_some_and(|listen| states.listen.contains(&listen))
+                }
+                _ => false,
+            };
+        } else if let Some(id) = line.trim().strip_prefix("id: ") {
+            if matched {
+                let mut id = id.split_whitespace();
+                if let (Some(id), Some("creatorid:"), Some(creator)) =
+                    (id.next(), id.next(), id.next())
+                {
+                    ids.push(format!("{}/{}", id, creator));
+                }
+            }
+        }
+    }
+    ids
+}
+
+/// Parse an address of a state, IPv6 addresses are printed as `addr[port]`.
+fn parse_host(s: &str) -> Option<SocketAddr> {
+    let (addr, port) = match s.strip_suffix(']') {
+        Some(s) => s.split_once('[')?,
+        None => s.rsplit_once(':')?,
+    };
+    Some(SocketAddr::new(addr.parse().ok()?, port.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Forward, Listen, Redirect, Reference, Target};
+    use std::time::Duration;
+
+    #[test]
+    fn test_pf_ruleset() {
+        let www = Redirect {
+            id: 1,
+            name: "www".to_string(),
+            listen: vec![
+                Listen {
+                    addr: "10.1.0.1:80".parse().unwrap(),
+                    tls: false,
+                    interface: Some("em0".to_string()),
+                },
+                Listen {
+                    addr: "[fd00::1]:80".parse().unwrap(),
+                    tls: false,
+                    interface: None,
+                },
+            ],
+            forward: vec![Forward {
+                target: Target::Table(Reference::new("web")),
+                port: Some(8080),
+                mode: Mode::LeastStates,
+                check: None,
+                route: false,
+            }],
+            ..Default::default()
+        };
+
+        let mut filter = Pf::default();
+        filter.install(&www);
+        assert_eq!(
+            filter.rules(),
+            "# redirect \"www\"\ntable <rdr_1> persist\n"
+        );
+
+        filter.update(
+            www.id,
+            www.forward.first(),
+            &["10.0.0.1".parse().unwrap(), "fd00::2".parse().unwrap()],
+        );
+        assert_eq!(
+            filter.rules(),
+            "# redirect \"www\"
+table <rdr_1> persist { 10.0.0.1 fd00::2 }
+pass in quick on em0 inet proto tcp to 10.1.0.1 port 80 rdr-to <rdr_1> port 8080 least-states
+pass in quick inet6 proto tcp to fd00::1 port 80 rdr-to <rdr_1> port 8080 least-states
+"
+        );
+
+        filter.remove(www.id);
+        assert_eq!(filter.rules(), "");
+
+        let dsr = Redirect {
+            id: 2,
+            name: "dsr".to_string(),
+            listen: vec![www.listen[0].clone()],
+            forward: vec![Forward {
+                route: true,
+                ..www.forward[0].clone()
+            }],
+            tag: Some("RELAYD".to_string()),
+            match_tag: true,
+            sticky: true,
+            session_timeout: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        filter.install(&dsr);
+        filter.update(dsr.id, dsr.forward.first(), &["10.0.0.1".parse().unwrap()]);
+        assert_eq!(
+            filter.rules().lines().nth(2).unwrap(),
+            "match in on em0 inet proto tcp to 10.1.0.1 port 80 tag \"RELAYD\" \
+             route-to <rdr_2> least-states sticky-address"
+        );
+    }
+
+    #[test]
+    fn test_pf_state_ids() {
+        let list = "\
+all tcp 10.0.0.1:8080 (10.1.0.1:80) <- 192.0.2.1:51234       ESTABLISHED:ESTABLISHED
+   [3914377187 + 16384](+2918437064) wscale 6  [1285217262 + 16384](+1485224498) wscale 6
+   age 00:00:05, expires in 23:59:55, 6:4 pkts, 447:470 bytes, rule 3
+   id: 5f1a2b3c00000001 creatorid: 12345678
+all tcp 10.0.0.2:8080 (10.1.0.1:80) <- 192.0.2.1:51235       ESTABLISHED:ESTABLISHED
+   id: 5f1a2b3c00000002 creatorid: 12345678
+all tcp 10.0.0.1:8080 (10.1.0.2:80) <- 192.0.2.1:51236       ESTABLISHED:ESTABLISHED
+   id: 5f1a2b3c00000003 creatorid: 12345678
+all tcp 10.0.0.1:22 <- 192.0.2.1:51237       ESTABLISHED:ESTABLISHED
+   id: 5f1a2b3c00000004 creatorid: 12345678
+all tcp fd00::2[8080] (fd00::1[80]) <- fd00::3[51238]       ESTABLISHED:ESTABLISHED
+   id: 5f1a2b3c00000005 creatorid: 12345678
+";
+        let states = States {
+            listen: vec!["10.1.0.1:80".parse().unwrap()],
+            host: "10.0.0.1".parse().unwrap(),
+        };
+        assert_eq!(state_ids(list, &states), ["5f1a2b3c00000001/12345678"]);
+
+        let states = States {
+            listen: vec!["[fd00::1]:80".parse().unwrap()],
+            host: "fd00::2".parse().unwrap(),
+        };
+        assert_eq!(state_ids(list, &states), ["5f1a2b3c00000005/12345678"]);
+    }
+}
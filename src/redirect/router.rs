@@ -0,0 +1,283 @@
+use crate::config::{Config, DynamicHost, Id, Prefix, Target};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::IpAddr,
+};
+
+/// Route of a prefix to a gateway.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct Route {
+    pub prefix: Prefix,
+    pub gateway: IpAddr,
+    /// The priority of the gateway, lower values are preferred.
+    pub metric: u32,
+    /// Routing table, the main table by default.
+    pub rtable: Option<u32>,
+    pub label: Option<String>,
+}
+
+/// Returns the routes to all gateways that are up.
+///
+/// Every gateway gets a route with its priority as the metric, so the
+/// kernel prefers the gateway with the lowest priority and falls back to
+/// the others when its route is removed.
+pub fn routes(
+    config: &Config,
+    hosts: &HashSet<Id>,
+    dynamic_hosts: &HashMap<Id, Vec<DynamicHost>>,
+) -> Vec<Route> {
+    let mut routes = vec![];
+    for router in &config.routers {
+        let gateways = router
+            .forward
+            .iter()
+            .filter_map(|forward| match &forward.target {
+                Target::Table(table) => config.table_by_id(table.id),
+                _ => None,
+            })
+            .filter(|table| !table.disabled)
+            .flat_map(|table| table.hosts.iter())
+            .flat_map(|host| match dynamic_hosts.get(&host.id) {
+                Some(members) => members
+                    .iter()
+                    .filter(|member| hosts.contains(&member.id))
+                    .map(|member| (member.addr, host.priority.or(member.priority)))
+                    .collect(),
+                None if hosts.contains(&host.id) => host
+                    .name
+                    .parse()
+                    .map(|addr| (addr, host.priority))
+                    .into_iter()
+                    .collect(),
+                None => vec![],
+            });
+        for (gateway, priority) in gateways {
+            for prefix in &router.routes {
+                let route = Route {
+                    prefix: *prefix,
+                    gateway,
+                    metric: priority.unwrap_or_default().into(),
+                    rtable: router.rtable,
+                    label: router.rtlabel.clone(),
+                };
+                if prefix.addr.is_ipv4() == gateway.is_ipv4() && !routes.contains(&route) {
+                    routes.push(route);
+                }
+            }
+        }
+    }
+    routes
+}
+
+/// Backend that installs routes in the kernel.
+pub trait Routing: Send {
+    fn add(&mut self, route: &Route) -> io::Result<()>;
+
+    fn delete(&mut self, route: &Route) -> io::Result<()>;
+}
+
+/// Backend of platforms without routing support.
+#[allow(unused)]
+struct Unsupported;
+
+impl Routing for Unsupported {
+    fn add(&mut self, _route: &Route) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "routers are not supported",
+        ))
+    }
+
+    fn delete(&mut self, route: &Route) -> io::Result<()> {
+        self.add(route)
+    }
+}
+
+/// Returns the routing backend of the platform.
+pub fn backend() -> Box<dyn Routing> {
+    #[cfg(target_os = "linux")]
+    return Box::new(super::netlink::Netlink);
+    #[cfg(not(target_os = "linux"))]
+    return Box::new(Unsupported);
+}
+
+/// Routes that were installed by the Parent.
+pub struct RouteTable {
+    backend: Box<dyn Routing>,
+    installed: Vec<Route>,
+}
+
+impl RouteTable {
+    pub fn new(backend: Box<dyn Routing>) -> Self {
+        Self {
+            backend,
+            installed: vec![],
+        }
+    }
+
+    /// Install the routes and delete the ones that are no longer needed.
+    ///
+    /// New routes are added first, so a prefix keeps a route during the
+    /// failover to another gateway.  Failed routes are retried on the next
+    /// update, and a route that already exists is considered installed.
+    pub fn update(&mut self, routes: &[Route]) -> Vec<io::Error> {
+        let mut errors = vec![];
+        for route in routes {
+            if self.installed.contains(route) {
+                continue;
+            }
+            match self.backend.add(route) {
+                Ok(()) => self.installed.push(route.clone()),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    self.installed.push(route.clone())
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+
+        let backend = &mut self.backend;
+        self.installed.retain(|route| {
+            if routes.contains(route) {
+                return true;
+            }
+            match backend.delete(route) {
+                Ok(()) => false,
+                Err(err) => {
+                    errors.push(err);
+                    true
+                }
+            }
+        });
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Routing backend that keeps the routes in memory.
+    #[derive(Clone, Default)]
+    struct Mock {
+        routes: Arc<Mutex<Vec<Route>>>,
+    }
+
+    impl Routing for Mock {
+        fn add(&mut self, route: &Route) -> io::Result<()> {
+            let mut routes = self.routes.lock().unwrap();
+            if route.gateway.is_unspecified() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "unreachable"));
+            }
+            if routes.contains(route) {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+            }
+            routes.push(route.clone());
+            Ok(())
+        }
+
+        fn delete(&mut self, route: &Route) -> io::Result<()> {
+            self.routes.lock().unwrap().retain(|r| r != route);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_router_routes() {
+        let config = Config::parse(
+            "relayd.conf",
+            r#"
+table <gateways> { 192.0.2.1 priority 10, 198.51.100.1 priority 20, 2001:db8::1 }
+router "uplinks" {
+	route 0.0.0.0/0
+	route 2001:db8:1::/48
+	forward to <gateways> check icmp
+	rtable 1
+	rtlabel "uplink"
+}
+"#,
+            Default::default(),
+        )
+        .unwrap();
+        let gateways = &config.tables[0].hosts;
+        let route = |prefix: &str, gateway: &str, metric| {
+            let (addr, len) = prefix.split_once('/').unwrap();
+            Route {
+                prefix: Prefix {
+                    addr: addr.parse().unwrap(),
+                    len: len.parse().unwrap(),
+                },
+                gateway: gateway.parse().unwrap(),
+                metric,
+                rtable: Some(1),
+                label: Some("uplink".to_string()),
+            }
+        };
+
+        let mock = Mock::default();
+        let mut table = RouteTable::new(Box::new(mock.clone()));
+        let mut hosts = gateways.iter().map(|host| host.id).collect::<HashSet<_>>();
+        let expected = [
+            route("0.0.0.0/0", "192.0.2.1", 10),
+            route("0.0.0.0/0", "198.51.100.1", 20),
+            route("2001:db8:1::/48", "2001:db8::1", 0),
+        ];
+        assert_eq!(routes(&config, &hosts, &HashMap::new()), expected);
+        assert!(table.update(&expected).is_empty());
+        assert_eq!(*mock.routes.lock().unwrap(), expected);
+
+        // The preferred gateway is down.
+        hosts.remove(&gateways[0].id);
+        let routes = routes(&config, &hosts, &HashMap::new());
+        assert!(table.update(&routes).is_empty());
+        assert_eq!(*mock.routes.lock().unwrap(), expected[1..]);
+
+        // Failed routes are retried.
+        let mut failed = routes.clone();
+        failed.push(route("0.0.0.0/0", "0.0.0.0", 0));
+        assert_eq!(table.update(&failed).len(), 1);
+        assert_eq!(table.update(&failed).len(), 1);
+
+        assert!(table.update(&[]).is_empty());
+        assert!(mock.routes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_router_equal_priority() {
+        let config = Config::parse(
+            "relayd.conf",
+            r#"
+table <gateways> { 192.0.2.1, 192.0.2.2 }
+router "uplinks" {
+	route 0.0.0.0/0
+	forward to <gateways> check icmp
+}
+"#,
+            Default::default(),
+        )
+        .unwrap();
+        let gateways = &config.tables[0].hosts;
+
+        // The route of the first gateway is left over from a previous run.
+        let mock = Mock::default();
+        let mut hosts = gateways.iter().map(|host| host.id).collect::<HashSet<_>>();
+        let expected = routes(&config, &hosts, &HashMap::new());
+        assert_eq!(expected.len(), 2);
+        assert_eq!(expected[0].metric, expected[1].metric);
+        mock.routes.lock().unwrap().push(expected[0].clone());
+
+        let mut table = RouteTable::new(Box::new(mock.clone()));
+        assert!(table.update(&expected).is_empty());
+        assert_eq!(*mock.routes.lock().unwrap(), expected);
+
+        // The other gateway keeps its route.
+        hosts.remove(&gateways[1].id);
+        assert!(table
+            .update(&routes(&config, &hosts, &HashMap::new()))
+            .is_empty());
+        assert_eq!(*mock.routes.lock().unwrap(), expected[..1]);
+    }
+}
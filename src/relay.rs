@@ -1,49 +1,791 @@
+mod access;
+mod listener;
+mod tls;
+
 use crate::{
+    config::{Config, DynamicHost, Id, LogConnection, Mode, ProtocolType, Relay, Target},
     error::Error,
     message::{Data, Type},
-    parent::default_handler,
-    Child, Privsep,
+    metrics::{RelayStats, Stats},
+    parent::{default_handler, send_to_peer},
+    Child, Context, Privsep,
+};
+use access::{AccessLog, Head, Session, Tap};
+use arc_swap::ArcSwap;
+use privsep::net::Fd;
+use privsep_log::{debug, info, trace, warn};
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashMap, HashSet, VecDeque,
+    },
+    hash::{BuildHasher, Hash, Hasher},
+    io,
+    net::{self, SocketAddr},
+    os::unix::io::{FromRawFd, IntoRawFd},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
-use privsep::imsg::Message;
-use privsep_log::{info, trace};
-use std::sync::Arc;
+use tls::Keypairs;
+use tokio::{
+    fs::File,
+    io::{copy_bidirectional, AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time,
+};
+
+pub use listener::Listeners;
+
+/// Runtime state of the relays.
+#[derive(Debug, Default)]
+struct State {
+    /// Hosts that are currently up.
+    hosts: Mutex<HashSet<Id>>,
+    /// Resolved addresses by the Id of the configured host.
+    dynamic_hosts: Mutex<HashMap<Id, Vec<DynamicHost>>>,
+    /// Round-robin counter to select hosts.
+    next: AtomicUsize,
+    /// Number of active sessions.
+    active: AtomicUsize,
+    /// Number of active sessions by table host for the least-states mode.
+    host_sessions: Mutex<HashMap<Id, usize>>,
+    /// Statistics by relay Id.
+    stats: Mutex<HashMap<Id, RelayStats>>,
+    /// TLS certificates of the listeners.
+    keypairs: Keypairs,
+}
+
+impl State {
+    /// Update the statistics of a relay.
+    fn count(&self, relay: &Relay, f: impl FnOnce(&mut RelayStats)) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(relay.id).or_insert_with(|| RelayStats {
+            id: relay.id,
+            name: relay.name.clone(),
+            ..Default::default()
+        });
+        f(stats)
+    }
+}
+
+/// Active session to a table host that is counted until it is dropped.
+struct HostSession<'a> {
+    state: &'a State,
+    id: Id,
+}
+
+impl<'a> HostSession<'a> {
+    fn new(state: &'a State, id: Id) -> Self {
+        *state.host_sessions.lock().unwrap().entry(id).or_default() += 1;
+        Self { state, id }
+    }
+}
+
+impl Drop for HostSession<'_> {
+    fn drop(&mut self) {
+        let mut sessions = self.state.host_sessions.lock().unwrap();
+        if let Some(count) = sessions.get_mut(&self.id) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.remove(&self.id);
+            }
+        }
+    }
+}
+
+/// Listener sockets of the Parent and the tasks that accept connections.
+#[derive(Debug, Default)]
+struct Listening {
+    sockets: HashMap<SocketAddr, Arc<TcpListener>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Listening {
+    /// Add a socket that was created by the Parent.
+    fn insert(&mut self, addr: SocketAddr, fd: Fd) -> io::Result<()> {
+        let listener = unsafe { net::TcpListener::from_raw_fd(fd.into_raw_fd()) };
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        self.sockets.insert(addr, Arc::new(listener));
+        Ok(())
+    }
+
+    /// Stop accepting connections.
+    fn abort(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
 
 pub async fn main<const N: usize>(
     child: Child<N>,
-    config: privsep::Config,
+    privsep_config: privsep::Config,
 ) -> Result<(), privsep::Error> {
-    let _guard = privsep_log::async_logger(&child.to_string(), &config)
+    let _guard = privsep_log::async_logger(&child.to_string(), &privsep_config)
         .await
         .map_err(|err| privsep::Error::GeneralError(Box::new(err)))?;
 
-    let child = Arc::new(child);
+    let context = Context {
+        child: Arc::new(child),
+        config: Default::default(),
+    };
+    let state = Arc::new(State::default());
+
+    // Every session holds a sender; the receiver returns once all are gone.
+    let (sessions, drained) = mpsc::channel::<()>(1);
+    let (abort, aborted) = watch::channel(false);
+    let (access_log, writer) = AccessLog::new();
+    let mut listening = Listening::default();
+    let mut reporter = None;
+    let signer = sign(context.clone(), state.clone());
+    // Received file descriptors in the order they were sent.
+    let mut fds = VecDeque::new();
 
     info!("Started");
 
     loop {
-        tokio::select! {
-            message = default_handler::<Data<'_>>(&child[Privsep::PARENT_ID]) => {
-                match message? {
-                    (Message { id: Type::CONFIG, .. }, _, Data::Config(config)) => {
-                        trace!("received config: {:?}", config);
-                    }
-                    (Message { id: Type::START, .. }, ..) => {
-                        trace!("received start command");
+        let (message, fd, data) =
+            default_handler::<Data<'_>>(&context.child[Privsep::PARENT_ID]).await?;
+        let typ = Type::from(message.id);
+
+        // A file descriptor is returned with the first message of a read,
+        // which may precede the message that it was sent with.
+        fds.extend(fd);
+        let fd = match typ {
+            Type::LogFile | Type::Listener => fds.pop_front(),
+            _ => None,
+        };
+
+        match (typ, data) {
+            (Type::Config, Data::Config(new_config)) => {
+                trace!("received config: {:?}", new_config);
+                context.config.store(Arc::new(new_config.into_owned()));
+                state.keypairs.clear();
+                if reporter.is_some() {
+                    listen(
+                        &context,
+                        &state,
+                        &mut listening,
+                        &access_log,
+                        &sessions,
+                        &aborted,
+                    );
+                }
+            }
+            (Type::LogFile, _) => {
+                trace!("received log file");
+                let fd = fd.ok_or(Error::InvalidMessage)?;
+                let file = unsafe { std::fs::File::from_raw_fd(fd.into_raw_fd()) };
+                access_log.reopen(File::from_std(file)).await;
+            }
+            (Type::Listener, Data::Listener(addr)) => {
+                trace!("received listener {}", addr);
+                let fd = fd.ok_or(Error::InvalidMessage)?;
+                if let Err(err) = listening.insert(addr, fd) {
+                    warn!("invalid listener {}: {}", addr, err);
+                }
+                if reporter.is_some() {
+                    listen(
+                        &context,
+                        &state,
+                        &mut listening,
+                        &access_log,
+                        &sessions,
+                        &aborted,
+                    );
+                }
+            }
+            (Type::Keypair, Data::Keypair(keypair)) => {
+                trace!("received keypair {}", keypair.name);
+                let (name, listen) = (keypair.name.clone(), keypair.listen);
+                if let Err(err) = state.keypairs.insert(keypair) {
+                    warn!("invalid keypair {} of {}: {}", name, listen, err);
+                }
+            }
+            (Type::Signature, Data::Signature(signature)) => {
+                trace!("received signature {}", signature.id);
+                state.keypairs.ca().signature(signature);
+            }
+            (Type::Start, _) => {
+                trace!("received start command");
+                reporter = Some(report(context.clone(), state.clone()));
+                listen(
+                    &context,
+                    &state,
+                    &mut listening,
+                    &access_log,
+                    &sessions,
+                    &aborted,
+                );
+            }
+            (Type::HostUp, Data::Host(id)) => {
+                trace!("received host UP: {}", id);
+                state.hosts.lock().unwrap().insert(id);
+            }
+            (Type::HostDown, Data::Host(id)) => {
+                trace!("received host DOWN: {}", id);
+                state.hosts.lock().unwrap().remove(&id);
+            }
+            (Type::HostAdd, Data::DynamicHost(host)) => {
+                trace!("received host ADD: {} {}", host.id, host.addr);
+                let mut dynamic_hosts = state.dynamic_hosts.lock().unwrap();
+                dynamic_hosts.entry(host.parent).or_default().push(host);
+            }
+            (Type::HostRemove, Data::Host(id)) => {
+                trace!("received host REMOVE: {}", id);
+                state.hosts.lock().unwrap().remove(&id);
+                let mut dynamic_hosts = state.dynamic_hosts.lock().unwrap();
+                for members in dynamic_hosts.values_mut() {
+                    members.retain(|member| member.id != id);
+                }
+                dynamic_hosts.retain(|_, members| !members.is_empty());
+            }
+            (Type::Shutdown, _) => {
+                trace!("received shutdown command");
+                break;
+            }
+            _ => return Err(Error::InvalidMessage.into()),
+        }
+    }
+
+    // Stop accepting new connections and wait for the active sessions.
+    listening.abort();
+    listening.sockets.clear();
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+    drop(sessions);
+    drain(&state, drained, abort, context.config.load().drain_timeout).await;
+
+    // Write the remaining log lines.
+    drop(access_log);
+    let _ = time::timeout(Duration::from_secs(1), writer).await;
+    signer.abort();
+
+    info!("Terminated");
+
+    Ok(())
+}
+
+/// Wait until all sessions are closed or abort them after the timeout.
+///
+/// Returns false if the sessions had to be aborted.
+async fn drain(
+    state: &State,
+    mut drained: mpsc::Receiver<()>,
+    abort: watch::Sender<bool>,
+    timeout: Duration,
+) -> bool {
+    info!(
+        "Draining {} sessions",
+        state.active.load(Ordering::SeqCst);
+        "timeout" => timeout.as_secs(),
+    );
+    if time::timeout(timeout, drained.recv()).await.is_ok() {
+        return true;
+    }
+
+    warn!(
+        "Drain timeout expired, aborting {} sessions",
+        state.active.load(Ordering::SeqCst)
+    );
+    let _ = abort.send(true);
+    drained.recv().await;
+    false
+}
+
+/// Accept connections on all configured relays.
+///
+/// The relays cannot bind privileged ports, they use the listener sockets
+/// of the Parent.  The tasks are restarted after a reload or when the
+/// Parent sent a new listener; sockets that are no longer configured are
+/// closed.
+fn listen<const N: usize>(
+    context: &Context<N>,
+    state: &Arc<State>,
+    listening: &mut Listening,
+    access_log: &AccessLog,
+    sessions: &mpsc::Sender<()>,
+    aborted: &watch::Receiver<bool>,
+) {
+    trace!("Listening");
+
+    let config = context.config.load_full();
+    listening.abort();
+    listening.sockets.retain(|addr, _| {
+        config
+            .relays
+            .iter()
+            .any(|relay| relay.listen.iter().any(|listen| listen.addr == *addr))
+    });
+
+    for relay in &config.relays {
+        for listen in &relay.listen {
+            let listener = match listening.sockets.get(&listen.addr) {
+                Some(listener) => listener.clone(),
+                None => {
+                    debug!("relay {}: no listener on {}", relay.name, listen.addr);
+                    continue;
+                }
+            };
+            debug!("relay {}: listening on {}", relay.name, listen.addr);
+
+            listening.tasks.push(tokio::spawn(accept(
+                listener,
+                relay.clone(),
+                context.config.clone(),
+                state.clone(),
+                access_log.clone(),
+                sessions.clone(),
+                aborted.clone(),
+            )));
+        }
+    }
+}
+
+/// Send the relay statistics to the parent for the control socket and
+/// the metrics process.
+///
+/// Each report finishes an interval of the rolling session counters.
+fn report<const N: usize>(context: Context<N>, state: Arc<State>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(crate::STATS_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let stats = state
+                .stats
+                .lock()
+                .unwrap()
+                .values_mut()
+                .map(|stats| {
+                    stats.tick();
+                    stats.clone()
+                })
+                .collect();
+            let data = Data::Stats(Stats::Relays(stats));
+            let peer = &context.child[Privsep::PARENT_ID];
+            if let Err(err) = send_to_peer(peer, Type::Stats, None, &data).await {
+                warn!("Failed to send relay statistics: {}", err);
+            }
+        }
+    })
+}
+
+/// Send the signing requests of the TLS handshakes to the CA process.
+fn sign<const N: usize>(context: Context<N>, state: Arc<State>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            for request in state.keypairs.ca().requests().await {
+                let data = Data::Sign(request);
+                let peer = &context.child[Privsep::PARENT_ID];
+                if let Err(err) = send_to_peer(peer, Type::Sign, None, &data).await {
+                    warn!("Failed to send signing request: {}", err);
+                }
+            }
+        }
+    })
+}
+
+/// Accept connections; sessions use the tables of the latest configuration.
+async fn accept(
+    listener: Arc<TcpListener>,
+    relay: Relay,
+    config: Arc<ArcSwap<Config>>,
+    state: Arc<State>,
+    access_log: AccessLog,
+    sessions: mpsc::Sender<()>,
+    aborted: watch::Receiver<bool>,
+) {
+    let relay = Arc::new(relay);
+    let bound = match listener.local_addr() {
+        Ok(bound) => bound,
+        Err(err) => {
+            warn!("relay {}: invalid listener: {}", relay.name, err);
+            return;
+        }
+    };
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("relay {}: accept failed: {}", relay.name, err);
+                time::sleep(crate::ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let local = match stream.local_addr() {
+            Ok(local) => (local, bound),
+            Err(_) => continue,
+        };
+
+        let relay = relay.clone();
+        let config = config.load_full();
+        let state = state.clone();
+        let access_log = access_log.clone();
+        let session = sessions.clone();
+        let mut aborted = aborted.clone();
+
+        tokio::spawn(async move {
+            let _session = session;
+            state.active.fetch_add(1, Ordering::SeqCst);
+            state.count(&relay, |stats| {
+                stats.sessions.count();
+                stats.active += 1;
+            });
+            debug!("relay {}: session from {}", relay.name, peer);
+
+            let started = Instant::now();
+            let mut session = Session {
+                start: SystemTime::now(),
+                duration: Duration::ZERO,
+                relay: &relay.name,
+                client: peer,
+                backend: None,
+                bytes_in: 0,
+                bytes_out: 0,
+                request: None,
+                response: None,
+                headers: vec![],
+                error: None,
+            };
+
+            tokio::select! {
+                result = relay_session(stream, local, &relay, &config, &state, &mut session) => {
+                    if let Err(err) = result {
+                        debug!("relay {}: session from {} failed: {}", relay.name, peer, err);
+                        state.count(&relay, |stats| stats.errors += 1);
+                        session.error = Some(err.to_string());
                     }
-                    _ => return Err(Error::InvalidMessage.into()),
                 }
+                _ = aborted.changed() => session.error = Some("aborted".to_string()),
+            }
+            session.duration = started.elapsed();
+
+            state.active.fetch_sub(1, Ordering::SeqCst);
+            state.count(&relay, |stats| {
+                stats.active -= 1;
+                stats.bytes_in += session.bytes_in;
+                stats.bytes_out += session.bytes_out;
+            });
+
+            match config.log.connection {
+                Some(LogConnection::All) => {}
+                Some(LogConnection::Errors) if session.error.is_some() => {}
+                _ => return,
             }
-            message = default_handler::<Data<'_>>(&child[Privsep::HEALTH_ID]) => {
-                match message? {
-                    (Message { id: Type::HOST_UP, .. }, _, Data::Host(id)) => {
-                        trace!("received host UP: {}", id);
+            access_log.log(session.format(config.log.format));
+        });
+    }
+}
+
+/// Relay a session and record it for the access log.
+///
+/// The local addresses are the ones of the connection and its listener.
+/// TLS connections are accepted before connecting to the backend.
+async fn relay_session(
+    inbound: TcpStream,
+    local: (SocketAddr, SocketAddr),
+    relay: &Relay,
+    config: &Config,
+    state: &State,
+    session: &mut Session<'_>,
+) -> io::Result<()> {
+    let tls = relay
+        .listen
+        .iter()
+        .any(|listen| listen.addr == local.1 && listen.tls);
+    if tls {
+        let inbound = state.keypairs.acceptor(&local.1)?.accept(inbound).await?;
+        let (outbound, _host) =
+            open(inbound.get_ref().0, local, relay, config, state, session).await?;
+        forward(inbound, outbound, relay, config, session).await
+    } else {
+        let (outbound, _host) = open(&inbound, local, relay, config, state, session).await?;
+        forward(inbound, outbound, relay, config, session).await
+    }
+}
+
+/// Connect to the backend of a session.
+///
+/// Returns the connection and the session to the selected table host.
+async fn open<'a>(
+    inbound: &TcpStream,
+    local: (SocketAddr, SocketAddr),
+    relay: &Relay,
+    config: &Config,
+    state: &'a State,
+    session: &mut Session<'_>,
+) -> io::Result<(TcpStream, Option<HostSession<'a>>)> {
+    let (outbound, backend) = connect(inbound, local, relay, config, state).await?;
+    let host = backend.map(|(id, name)| {
+        state.count(relay, |stats| stats.host(id, &name).count());
+        HostSession::new(state, id)
+    });
+    session.backend = outbound.peer_addr().ok().map(|addr| addr.to_string());
+    Ok((outbound, host))
+}
+
+/// Copy the data of a session in both directions.
+async fn forward<S: AsyncRead + AsyncWrite + Unpin>(
+    inbound: S,
+    outbound: TcpStream,
+    relay: &Relay,
+    config: &Config,
+    session: &mut Session<'_>,
+) -> io::Result<()> {
+    let (mut inbound, mut outbound) = (Tap::new(inbound), Tap::new(outbound));
+    let result = copy_bidirectional(&mut inbound, &mut outbound).await;
+
+    if let Some(protocol) = relay
+        .protocol
+        .as_ref()
+        .and_then(|protocol| config.protocol_by_id(protocol.id))
+        .filter(|protocol| protocol.typ == ProtocolType::Http)
+    {
+        session.request = Head::parse(inbound.head());
+        session.response = Head::parse(outbound.head());
+        session.headers = access::logged_headers(
+            protocol,
+            session.request.as_ref(),
+            session.response.as_ref(),
+        );
+    }
+
+    (session.bytes_in, session.bytes_out) = result?;
+    Ok(())
+}
+
+/// Available host of a table.
+#[derive(Debug)]
+struct Candidate {
+    id: Id,
+    name: String,
+    port: u16,
+    /// Priority of an SRV record, lower values are preferred.
+    priority: Option<u8>,
+    /// Weight of an SRV record.
+    weight: u16,
+}
+
+/// Select a host with the lowest priority.
+///
+/// The hosts are weighted by their SRV records and `key` is the counter,
+/// random number or hash of the scheduling mode.  The least-states mode
+/// only selects from the hosts with the fewest active sessions.
+fn select<'a>(
+    hosts: &'a [Candidate],
+    mode: Mode,
+    key: usize,
+    sessions: &HashMap<Id, usize>,
+) -> Option<&'a Candidate> {
+    let priority = hosts.iter().map(|host| host.priority).min()?;
+    let mut hosts = hosts
+        .iter()
+        .filter(|host| host.priority == priority)
+        .collect::<Vec<_>>();
+    if mode == Mode::LeastStates {
+        let active = |host: &Candidate| sessions.get(&host.id).copied().unwrap_or_default();
+        let least = hosts.iter().map(|host| active(host)).min()?;
+        hosts.retain(|host| active(host) == least);
+    }
+
+    // Records with weight 0 are still selected, but rarely.
+    let weight = |host: &Candidate| usize::from(host.weight).max(1);
+    let mut n = key % hosts.iter().map(|host| weight(host)).sum::<usize>();
+    hosts.into_iter().find(|host| {
+        let found = n < weight(host);
+        n = n.saturating_sub(weight(host));
+        found
+    })
+}
+
+/// Hash the key of a scheduling mode the same way in all relay processes.
+fn hash<T: Hash>(key: T) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+/// Connect to the first available forwarding target.
+///
+/// Hosts are selected by the scheduling mode of the target:
+///
+/// - `roundrobin` rotates over the hosts.
+/// - `least-states` prefers the hosts with the fewest active sessions of
+///   this relay process.
+/// - `random` picks a random host.
+/// - `hash` picks the same host for the table.
+/// - `source-hash` picks the same host for the client address.
+/// - `loadbalance` picks the same host for the client address and the
+///   local port.
+///
+/// SRV records are selected by their priority and weight.  Returns the Id
+/// and address of the selected table host.
+async fn connect(
+    inbound: &TcpStream,
+    (local, bound): (SocketAddr, SocketAddr),
+    relay: &Relay,
+    config: &Config,
+    state: &State,
+) -> io::Result<(TcpStream, Option<(Id, String)>)> {
+    for forward in &relay.forward {
+        let port = forward.port.unwrap_or_else(|| local.port());
+
+        match &forward.target {
+            Target::Table(table) => {
+                let hosts = {
+                    let up = state.hosts.lock().unwrap();
+                    let dynamic_hosts = state.dynamic_hosts.lock().unwrap();
+                    let mut hosts = vec![];
+                    for host in config
+                        .table_by_id(table.id)
+                        .filter(|table| !table.disabled)
+                        .iter()
+                        .flat_map(|table| table.hosts.iter())
+                    {
+                        // Host names are replaced by their resolved addresses.
+                        match dynamic_hosts.get(&host.id) {
+                            Some(members) => hosts.extend(
+                                members.iter().filter(|member| up.contains(&member.id)).map(
+                                    |member| Candidate {
+                                        id: member.id,
+                                        name: member.addr.to_string(),
+                                        port: forward.port.or(member.port).unwrap_or(port),
+                                        priority: member.priority,
+                                        weight: member.weight,
+                                    },
+                                ),
+                            ),
+                            None if up.contains(&host.id) => hosts.push(Candidate {
+                                id: host.id,
+                                name: host.name.clone(),
+                                port,
+                                priority: None,
+                                weight: 0,
+                            }),
+                            None => {}
+                        }
                     }
-                    (Message { id: Type::HOST_DOWN, .. }, _, Data::Host(id)) => {
-                        trace!("received host DOWN: {}", id);
+                    hosts
+                };
+                let key = match forward.mode {
+                    Mode::RoundRobin | Mode::LeastStates => {
+                        state.next.fetch_add(1, Ordering::Relaxed)
                     }
-                    _ => return Err(Error::InvalidMessage.into()),
+                    Mode::Random => RandomState::new().build_hasher().finish() as usize,
+                    Mode::Hash => hash(&table.name),
+                    Mode::SourceHash => hash(inbound.peer_addr()?.ip()),
+                    Mode::Loadbalance => hash((inbound.peer_addr()?.ip(), local.port())),
+                };
+                let host = {
+                    let sessions = state.host_sessions.lock().unwrap();
+                    select(&hosts, forward.mode, key, &sessions)
+                };
+                let host = match host {
+                    Some(host) => host,
+                    None => continue,
+                };
+                let stream = TcpStream::connect((host.name.as_str(), host.port)).await?;
+                let name = format!("{}:{}", host.name, host.port);
+                return Ok((stream, Some((host.id, name))));
+            }
+            Target::Host(name) => {
+                return Ok((TcpStream::connect((name.as_str(), port)).await?, None))
+            }
+            Target::Destination => {
+                let mut addr = listener::destination(inbound, local, bound)?;
+                if let Some(port) = forward.port {
+                    addr.set_port(port);
                 }
+                return Ok((TcpStream::connect(addr).await?, None));
             }
         }
     }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotConnected,
+        "no host available",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay_select() {
+        let candidate = |id, priority, weight| Candidate {
+            id,
+            name: format!("10.0.0.{}", id),
+            port: 80,
+            priority,
+            weight,
+        };
+        let selected = |hosts, mode, keys: std::ops::Range<usize>, sessions| {
+            keys.map(|key| select(hosts, mode, key, sessions).unwrap().id)
+                .collect::<Vec<_>>()
+        };
+        let none = HashMap::new();
+
+        let hosts = [candidate(1, None, 0), candidate(2, None, 0)];
+        assert_eq!(
+            selected(&hosts, Mode::RoundRobin, 0..4, &none),
+            [1, 2, 1, 2]
+        );
+        assert!(select(&[], Mode::RoundRobin, 0, &none).is_none());
+
+        // Hosts with the fewest sessions are preferred.
+        let sessions = [(1, 2), (2, 1)].into_iter().collect();
+        assert_eq!(selected(&hosts, Mode::LeastStates, 0..2, &sessions), [2, 2]);
+        assert_eq!(selected(&hosts, Mode::RoundRobin, 0..2, &sessions), [1, 2]);
+
+        // SRV records with the lowest priority, weighted.
+        let hosts = [
+            candidate(1, Some(20), 1),
+            candidate(2, Some(10), 3),
+            candidate(3, Some(10), 1),
+        ];
+        assert_eq!(selected(&hosts, Mode::Random, 0..4, &none), [2, 2, 2, 3]);
+        let sessions = [(2, 1)].into_iter().collect();
+        assert_eq!(selected(&hosts, Mode::LeastStates, 0..2, &sessions), [3, 3]);
+
+        // The hash modes select the same host for the same key.
+        assert_eq!(hash("web"), hash("web"));
+        assert_ne!(hash(("10.1.0.1", 80)), hash(("10.1.0.1", 81)));
+    }
+
+    #[tokio::test]
+    async fn test_relay_drain() {
+        let state = State::default();
+
+        // Sessions that close within the timeout are not aborted.
+        let (sessions, drained) = mpsc::channel::<()>(1);
+        let (abort, aborted) = watch::channel(false);
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(10)).await;
+            drop(sessions);
+        });
+        assert!(drain(&state, drained, abort, Duration::from_secs(5)).await);
+        assert!(!*aborted.borrow());
+
+        // Remaining sessions are aborted once the timeout expired.
+        let (sessions, drained) = mpsc::channel::<()>(1);
+        let (abort, mut aborted) = watch::channel(false);
+        let session = tokio::spawn(async move {
+            let _ = aborted.changed().await;
+            let aborted = *aborted.borrow();
+            drop(sessions);
+            aborted
+        });
+        assert!(!drain(&state, drained, abort, Duration::from_millis(10)).await);
+        assert!(session.await.unwrap());
+    }
 }
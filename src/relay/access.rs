@@ -0,0 +1,484 @@
+use crate::{
+    config::{Direction, FilterType, LogFormat, Operation, Protocol},
+    Timestamp,
+};
+use privsep_log::{info, warn};
+use std::{
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// Maximum size of a request or response head that is inspected.
+const MAX_HEAD: usize = 8192;
+/// Maximum number of queued log lines; further lines are dropped.
+const LOG_QUEUE: usize = 1024;
+
+/// Stream wrapper that keeps the first bytes that were read.
+#[derive(Debug)]
+pub struct Tap<S> {
+    inner: S,
+    head: Vec<u8>,
+}
+
+impl<S> Tap<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            head: vec![],
+        }
+    }
+
+    /// Returns the first bytes that were read from the stream.
+    pub fn head(&self) -> &[u8] {
+        &self.head
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tap<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let data = &buf.filled()[filled..];
+            let len = data.len().min(MAX_HEAD.saturating_sub(this.head.len()));
+            this.head.extend_from_slice(&data[..len]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tap<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Start line and headers of an HTTP request or response.
+#[derive(Debug, Default, PartialEq)]
+pub struct Head {
+    pub line: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Head {
+    /// Parse the head of an HTTP message, it might be truncated.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let end = data
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap_or(data.len());
+        let text = String::from_utf8_lossy(&data[..end]);
+        let mut lines = text.lines();
+
+        let line = lines.next()?.to_string();
+        let mut words = line.split(' ');
+        let http = match (words.next(), words.next(), words.next()) {
+            (Some(version), Some(_), _) if version.starts_with("HTTP/") => true,
+            (Some(_), Some(_), Some(version)) => version.starts_with("HTTP/"),
+            _ => false,
+        };
+        if !http {
+            return None;
+        }
+
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        Some(Self { line, headers })
+    }
+
+    /// Returns the first value of a header.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the status code of a response.
+    pub fn status(&self) -> Option<u16> {
+        self.line.split(' ').nth(1)?.parse().ok()
+    }
+}
+
+/// Returns the headers that are logged by the rules of the protocol.
+pub fn logged_headers(
+    protocol: &Protocol,
+    request: Option<&Head>,
+    response: Option<&Head>,
+) -> Vec<(String, String)> {
+    let mut headers = vec![];
+    for rule in &protocol.rules {
+        let heads = match rule.direction {
+            Some(Direction::Request) => [request, None],
+            Some(Direction::Response) => [None, response],
+            None => [request, response],
+        };
+        for filter in rule.filters.iter().filter(|filter| {
+            filter.typ == FilterType::Header && filter.operation == Some(Operation::Log)
+        }) {
+            for head in heads.iter().flatten() {
+                if let Some(value) = head.header(&filter.key) {
+                    headers.push((filter.key.clone(), value.to_string()));
+                }
+            }
+        }
+    }
+    headers
+}
+
+/// Log record of a relay session.
+#[derive(Debug)]
+pub struct Session<'a> {
+    pub start: SystemTime,
+    pub duration: Duration,
+    pub relay: &'a str,
+    pub client: SocketAddr,
+    /// Address of the backend host, if connected.
+    pub backend: Option<String>,
+    /// Bytes received from the client.
+    pub bytes_in: u64,
+    /// Bytes sent to the client.
+    pub bytes_out: u64,
+    pub request: Option<Head>,
+    pub response: Option<Head>,
+    /// Headers that are logged by the protocol rules.
+    pub headers: Vec<(String, String)>,
+    pub error: Option<String>,
+}
+
+impl Session<'_> {
+    /// Format the session as a single log line.
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common | LogFormat::Combined => self.format_common(format),
+            LogFormat::Json => self.format_json(),
+        }
+    }
+
+    /// Common or combined log format, followed by the relay fields.
+    fn format_common(&self, format: LogFormat) -> String {
+        let request = self.request.as_ref();
+        let status = self.response.as_ref().and_then(Head::status);
+
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            self.client.ip(),
+            Timestamp(self.start, false),
+            request
+                .map(|head| head.line.as_str())
+                .unwrap_or("-")
+                .escape_default(),
+            status
+                .map(|code| code.to_string())
+                .as_deref()
+                .unwrap_or("-"),
+            match self.bytes_out {
+                0 => "-".to_string(),
+                bytes => bytes.to_string(),
+            }
+        );
+        if format == LogFormat::Combined {
+            for name in ["Referer", "User-Agent"] {
+                let value = request.and_then(|head| head.header(name)).unwrap_or("-");
+                let _ = write!(line, " \"{}\"", value.escape_default());
+            }
+        }
+        let _ = write!(
+            line,
+            " relay=\"{}\" backend={} in={} duration={:.3}",
+            self.relay.escape_default(),
+            self.backend.as_deref().unwrap_or("-"),
+            self.bytes_in,
+            self.duration.as_secs_f64()
+        );
+        for (key, value) in &self.headers {
+            let _ = write!(line, " \"{}: {}\"", key, value.escape_default());
+        }
+        if let Some(error) = &self.error {
+            let _ = write!(line, " error=\"{}\"", error.escape_default());
+        }
+        line
+    }
+
+    fn format_json(&self) -> String {
+        let mut fields = vec![
+            (
+                "time",
+                Json::String(Timestamp(self.start, true).to_string()),
+            ),
+            ("relay", Json::String(self.relay.to_string())),
+            ("client", Json::String(self.client.to_string())),
+            ("backend", self.backend.clone().into()),
+            ("bytes_in", Json::Number(self.bytes_in as f64)),
+            ("bytes_out", Json::Number(self.bytes_out as f64)),
+            ("duration", Json::Number(self.duration.as_secs_f64())),
+        ];
+        if let Some(request) = &self.request {
+            fields.push(("request", Json::String(request.line.clone())));
+        }
+        if let Some(status) = self.response.as_ref().and_then(Head::status) {
+            fields.push(("status", Json::Number(status.into())));
+        }
+        let headers = self
+            .headers
+            .iter()
+            .map(|(key, value)| format!("{}:{}", Json::from(key), Json::from(value)))
+            .collect::<Vec<_>>();
+        if !headers.is_empty() {
+            fields.push(("headers", Json::Raw(format!("{{{}}}", headers.join(",")))));
+        }
+        if let Some(error) = &self.error {
+            fields.push(("error", Json::String(error.clone())));
+        }
+
+        let fields = fields
+            .iter()
+            .map(|(key, value)| format!("\"{}\":{}", key, value))
+            .collect::<Vec<_>>();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// Minimal JSON value.
+enum Json {
+    Null,
+    Number(f64),
+    String(String),
+    /// Already encoded JSON.
+    Raw(String),
+}
+
+impl From<Option<String>> for Json {
+    fn from(value: Option<String>) -> Self {
+        value.map(Self::String).unwrap_or(Self::Null)
+    }
+}
+
+impl From<&String> for Json {
+    fn from(value: &String) -> Self {
+        Self::String(value.clone())
+    }
+}
+
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Number(number) => write!(f, "{}", number),
+            Self::String(string) => {
+                f.write_char('"')?;
+                for ch in string.chars() {
+                    match ch {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        ch if ch.is_control() => write!(f, "\\u{:04x}", ch as u32)?,
+                        ch => f.write_char(ch)?,
+                    }
+                }
+                f.write_char('"')
+            }
+            Self::Raw(raw) => f.write_str(raw),
+        }
+    }
+}
+
+enum Record {
+    Line(String),
+    File(File),
+}
+
+/// Queue of log lines that are written by a dedicated task.
+///
+/// Sessions never wait for the log; lines are dropped if the writer
+/// cannot keep up.
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+    sender: mpsc::Sender<Record>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl std::fmt::Debug for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Line(line) => f.debug_tuple("Line").field(line).finish(),
+            Self::File(_) => f.write_str("File"),
+        }
+    }
+}
+
+impl AccessLog {
+    /// Start the writer, logging to the daemon log until a file is set.
+    pub fn new() -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel(LOG_QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer = tokio::spawn(write(receiver, dropped.clone()));
+        (Self { sender, dropped }, writer)
+    }
+
+    /// Queue a log line.
+    pub fn log(&self, line: String) {
+        if self.sender.try_send(Record::Line(line)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Write the following lines to a new file.
+    pub async fn reopen(&self, file: File) {
+        let _ = self.sender.send(Record::File(file)).await;
+    }
+}
+
+async fn write(mut receiver: mpsc::Receiver<Record>, dropped: Arc<AtomicU64>) {
+    let mut file = None;
+
+    while let Some(record) = receiver.recv().await {
+        // Write the queued records as a batch and flush it to the file.
+        let mut next = Some(record);
+        while let Some(record) = next {
+            match (record, &mut file) {
+                (Record::File(new_file), old_file) => {
+                    if let Some(old_file) = old_file {
+                        flush(old_file).await;
+                    }
+                    file = Some(new_file);
+                }
+                (Record::Line(line), Some(output)) => {
+                    let line = line + "\n";
+                    if let Err(err) = output.write_all(line.as_bytes()).await {
+                        warn!("access log: write failed: {}", err);
+                    }
+                }
+                (Record::Line(line), None) => info!("{}", line),
+            }
+            next = receiver.try_recv().ok();
+        }
+        if let Some(output) = &mut file {
+            flush(output).await;
+        }
+
+        let dropped = dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("access log: dropped {} lines", dropped);
+        }
+    }
+}
+
+async fn flush(file: &mut File) {
+    if let Err(err) = file.flush().await {
+        warn!("access log: flush failed: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_access_log_format() {
+        let request =
+            b"GET /index.html HTTP/1.1\r\nHost: www.example.com\r\nUser-Agent: curl/7.79\r\n\r\n";
+        let response = b"HTTP/1.1 404 Not Found\r\nServer: test\r\n\r\nbody";
+        let session = Session {
+            start: UNIX_EPOCH + Duration::from_secs(971185536),
+            duration: Duration::from_millis(12),
+            relay: "www",
+            client: "192.0.2.1:51234".parse().unwrap(),
+            backend: Some("10.0.0.1:80".to_string()),
+            bytes_in: 77,
+            bytes_out: 2326,
+            request: Head::parse(request),
+            response: Head::parse(response),
+            headers: vec![("Host".to_string(), "www.example.com".to_string())],
+            error: None,
+        };
+
+        assert_eq!(session.response.as_ref().unwrap().status(), Some(404));
+        assert_eq!(Head::parse(b"SSH-2.0-OpenSSH\r\n"), None);
+        assert_eq!(
+            session.format(LogFormat::Common),
+            "192.0.2.1 - - [10/Oct/2000:13:45:36 +0000] \"GET /index.html HTTP/1.1\" 404 2326 \
+             relay=\"www\" backend=10.0.0.1:80 in=77 duration=0.012 \"Host: www.example.com\""
+        );
+        assert_eq!(
+            session.format(LogFormat::Combined),
+            "192.0.2.1 - - [10/Oct/2000:13:45:36 +0000] \"GET /index.html HTTP/1.1\" 404 2326 \
+             \"-\" \"curl/7.79\" relay=\"www\" backend=10.0.0.1:80 in=77 duration=0.012 \
+             \"Host: www.example.com\""
+        );
+        assert_eq!(
+            session.format(LogFormat::Json),
+            "{\"time\":\"2000-10-10T13:45:36Z\",\"relay\":\"www\",\"client\":\"192.0.2.1:51234\",\
+             \"backend\":\"10.0.0.1:80\",\"bytes_in\":77,\"bytes_out\":2326,\"duration\":0.012,\
+             \"request\":\"GET /index.html HTTP/1.1\",\"status\":404,\
+             \"headers\":{\"Host\":\"www.example.com\"}}"
+        );
+
+        // A quote in the request line doesn't end the quoted field.
+        let request = b"GET /\"x\" HTTP/1.0\r\n\r\n";
+        let session = Session {
+            request: Head::parse(request),
+            headers: vec![],
+            ..session
+        };
+        assert!(session
+            .format(LogFormat::Common)
+            .contains(" \"GET /\\\"x\\\" HTTP/1.0\" 404 "));
+    }
+
+    #[tokio::test]
+    async fn test_access_log_reopen() {
+        let path =
+            |n| std::env::temp_dir().join(format!("relayd-access-{}.{}", std::process::id(), n));
+        let (log, writer) = AccessLog::new();
+
+        log.reopen(File::create(path(0)).await.unwrap()).await;
+        log.log("first".to_string());
+        log.log("second".to_string());
+        log.reopen(File::create(path(1)).await.unwrap()).await;
+        log.log("third".to_string());
+
+        // The writer stops when the queue is closed.
+        drop(log);
+        writer.await.unwrap();
+
+        for (n, lines) in [(0, "first\nsecond\n"), (1, "third\n")] {
+            assert_eq!(std::fs::read_to_string(path(n)).unwrap(), lines);
+            std::fs::remove_file(path(n)).unwrap();
+        }
+    }
+}
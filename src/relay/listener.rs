@@ -0,0 +1,358 @@
+use crate::config::Config;
+use nix::{
+    libc,
+    sys::socket::{
+        bind as bind_socket, getsockname, listen, setsockopt, socket, sockopt, AddressFamily,
+        InetAddr, SockAddr, SockFlag, SockType,
+    },
+};
+use privsep::net::Fd;
+use privsep_log::{info, warn};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    os::unix::io::{AsRawFd, RawFd},
+};
+use tokio::net::TcpStream;
+
+/// Maximum number of pending connections of a listener.
+const BACKLOG: usize = 128;
+
+/// Listener socket that was created by the Parent.
+#[derive(Debug)]
+struct Listener {
+    fd: Fd,
+    transparent: bool,
+    /// Name of the socket if it was passed by the service manager.
+    activated: Option<String>,
+}
+
+/// Listener sockets of all relays.
+///
+/// The Relay process runs unprivileged and cannot bind ports below 1024,
+/// so the Parent creates the sockets and keeps them open: a restarted
+/// Relay process gets the same listeners again and a reload only creates
+/// the sockets of new listen addresses.
+#[derive(Debug, Default)]
+pub struct Listeners {
+    sockets: HashMap<SocketAddr, Listener>,
+    /// Unused sockets of the service manager by name and address.
+    activated: HashMap<(String, SocketAddr), Fd>,
+}
+
+impl Listeners {
+    /// Add a socket that was passed by the service manager.
+    ///
+    /// It is used instead of binding the listen address of the relay with
+    /// the same name; returns the address of the socket.
+    pub fn activate(&mut self, name: String, fd: Fd) -> io::Result<SocketAddr> {
+        let addr = match getsockname(fd.as_raw_fd())? {
+            SockAddr::Inet(addr) => addr.to_std(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not an internet socket",
+                ))
+            }
+        };
+        self.activated.insert((name, addr), fd);
+        Ok(addr)
+    }
+
+    /// Create the sockets of new listen addresses and close the ones that
+    /// are no longer configured.
+    ///
+    /// Returns the addresses of the new sockets.
+    pub fn update(&mut self, config: &Config) -> Vec<SocketAddr> {
+        let mut addrs = vec![];
+        let mut new = vec![];
+        for relay in &config.relays {
+            let transparent = relay.transparent();
+            for listen in &relay.listen {
+                addrs.push(listen.addr);
+                if let Some(listener) = self.sockets.get_mut(&listen.addr) {
+                    // The socket is shared with the Relay process.
+                    if listener.transparent != transparent {
+                        match set_transparent(listener.fd.as_raw_fd(), &listen.addr, transparent) {
+                            Ok(()) => listener.transparent = transparent,
+                            Err(err) => warn!(
+                                "relay {}: failed to update listener {}: {}",
+                                relay.name, listen.addr, err
+                            ),
+                        }
+                    }
+                    continue;
+                }
+                let key = (relay.name.clone(), listen.addr);
+                let (result, activated) = match self.activated.remove(&key) {
+                    Some(fd) if transparent => {
+                        let result = set_transparent(fd.as_raw_fd(), &listen.addr, true);
+                        (result.map(|_| fd), Some(key.0))
+                    }
+                    Some(fd) => (Ok(fd), Some(key.0)),
+                    None => (bind(listen.addr, transparent), None),
+                };
+                match result {
+                    Ok(fd) => {
+                        info!("relay {}: listening on {}", relay.name, listen.addr);
+                        let listener = Listener {
+                            fd,
+                            transparent,
+                            activated,
+                        };
+                        self.sockets.insert(listen.addr, listener);
+                        new.push(listen.addr);
+                    }
+                    Err(err) => warn!(
+                        "relay {}: failed to listen on {}: {}",
+                        relay.name, listen.addr, err
+                    ),
+                }
+            }
+        }
+
+        // Keep the sockets of the service manager for the next reload.
+        let unused = self
+            .sockets
+            .keys()
+            .filter(|addr| !addrs.contains(addr))
+            .copied()
+            .collect::<Vec<_>>();
+        for addr in unused {
+            if let Some(Listener {
+                fd,
+                activated: Some(name),
+                ..
+            }) = self.sockets.remove(&addr)
+            {
+                self.activated.insert((name, addr), fd);
+            }
+        }
+        new
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &Fd)> {
+        self.sockets
+            .iter()
+            .map(|(addr, listener)| (addr, &listener.fd))
+    }
+}
+
+/// Create a listener socket.
+///
+/// A transparent listener accepts connections to any address that were
+/// diverted to it with TPROXY; this requires privileges on Linux.
+pub fn bind(addr: SocketAddr, transparent: bool) -> io::Result<Fd> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = Fd::from(socket(
+        family,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?);
+
+    setsockopt(fd.as_raw_fd(), sockopt::ReuseAddr, &true)?;
+    if transparent {
+        set_transparent(fd.as_raw_fd(), &addr, true)?;
+    }
+    bind_socket(
+        fd.as_raw_fd(),
+        &SockAddr::new_inet(InetAddr::from_std(&addr)),
+    )?;
+    listen(fd.as_raw_fd(), BACKLOG)?;
+
+    Ok(fd)
+}
+
+#[cfg(target_os = "linux")]
+fn set_transparent(fd: RawFd, addr: &SocketAddr, transparent: bool) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => setsockopt(fd, sockopt::IpTransparent, &transparent)?,
+        SocketAddr::V6(_) => {
+            let value = libc::c_int::from(transparent);
+            let ret = unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::SOL_IPV6,
+                    libc::IPV6_TRANSPARENT,
+                    &value as *const _ as *const libc::c_void,
+                    std::mem::size_of_val(&value) as libc::socklen_t,
+                )
+            };
+            if ret == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Other platforms divert connections to regular listeners.
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(_fd: RawFd, _addr: &SocketAddr, _transparent: bool) -> io::Result<()> {
+    Ok(())
+}
+
+/// Returns the original destination of a diverted connection.
+///
+/// Connections that were redirected with REDIRECT or DNAT keep their
+/// original destination in the connection tracking of Linux.  TPROXY and
+/// pf `divert-to` keep the original destination as the local address.
+pub fn destination(
+    stream: &TcpStream,
+    local: SocketAddr,
+    listener: SocketAddr,
+) -> io::Result<SocketAddr> {
+    let destination = original_dst(stream.as_raw_fd(), &local).unwrap_or(local);
+    if !is_diverted(destination, listener) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "connection was not diverted",
+        ));
+    }
+    Ok(destination)
+}
+
+/// Whether a destination differs from the address of the listener,
+/// otherwise connecting to it would loop.
+fn is_diverted(destination: SocketAddr, listener: SocketAddr) -> bool {
+    destination.port() != listener.port()
+        || (!listener.ip().is_unspecified() && destination.ip() != listener.ip())
+}
+
+#[cfg(target_os = "linux")]
+fn original_dst(fd: RawFd, local: &SocketAddr) -> io::Result<SocketAddr> {
+    match local {
+        SocketAddr::V4(_) => {
+            let addr = nix::sys::socket::getsockopt(fd, sockopt::OriginalDst)?;
+            Ok(InetAddr::V4(addr).to_std())
+        }
+        SocketAddr::V6(_) => {
+            let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of_val(&addr) as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IPV6,
+                    libc::IP6T_SO_ORIGINAL_DST,
+                    &mut addr as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if ret == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(InetAddr::V6(addr).to_std())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_dst(_fd: RawFd, _local: &SocketAddr) -> io::Result<SocketAddr> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_listener_update() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let relays = format!(
+            r#"
+relay "www" {{
+	listen on 127.0.0.1 port {}
+	forward to 127.0.0.2 port 80
+}}
+"#,
+            port
+        );
+        let config = Config::parse("relayd.conf", &relays, Default::default()).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+        let mut listeners = Listeners::default();
+        assert_eq!(listeners.update(&config), [addr]);
+        assert_eq!(listeners.iter().count(), 1);
+
+        // A reload only creates new sockets.
+        assert!(listeners.update(&config).is_empty());
+        assert_eq!(listeners.iter().count(), 1);
+
+        listeners.update(&Config::default());
+        assert_eq!(listeners.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_listener_activate() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let raw_fd = socket.into_raw_fd();
+        let relays = format!(
+            r#"
+relay "www" {{
+	listen on 127.0.0.1 port {}
+	forward to 127.0.0.2 port 80
+}}
+"#,
+            addr.port()
+        );
+        let config = Config::parse("relayd.conf", &relays, Default::default()).unwrap();
+
+        // The socket of the service manager is used instead of binding.
+        let mut listeners = Listeners::default();
+        assert_eq!(
+            listeners
+                .activate("www".to_string(), Fd::from(raw_fd))
+                .unwrap(),
+            addr
+        );
+        for _ in 0..2 {
+            assert_eq!(listeners.update(&config), [addr]);
+            let (_, fd) = listeners.iter().next().unwrap();
+            assert_eq!(fd.as_raw_fd(), raw_fd);
+
+            // It is kept open when the relay is removed.
+            listeners.update(&Config::default());
+            assert_eq!(listeners.iter().count(), 0);
+        }
+
+        let (left, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let fd = Fd::from(left.into_raw_fd());
+        assert!(listeners.activate("www".to_string(), fd).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_listener_destination() {
+        let fd = bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd.into_raw_fd()) };
+        listener.set_nonblocking(true).unwrap();
+        let listener = TcpListener::from_std(listener).unwrap();
+        let bound = listener.local_addr().unwrap();
+
+        let _client = TcpStream::connect(bound).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let local = stream.local_addr().unwrap();
+        assert_eq!(
+            destination(&stream, local, bound).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let any = "0.0.0.0:8080".parse().unwrap();
+        assert!(is_diverted("192.0.2.1:80".parse().unwrap(), any));
+        assert!(!is_diverted("127.0.0.1:8080".parse().unwrap(), any));
+        assert!(is_diverted(
+            "192.0.2.1:8080".parse().unwrap(),
+            "127.0.0.1:8080".parse().unwrap()
+        ));
+    }
+}
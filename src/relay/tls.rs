@@ -0,0 +1,299 @@
+use crate::ca::{Keypair, Sign, Signature};
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::CertificateDer,
+    sign::{CertifiedKey, Signer, SigningKey, SingleCertAndKey},
+    Error as TlsError, ServerConfig, SignatureAlgorithm, SignatureScheme,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+};
+use tokio::{sync::Notify, task};
+use tokio_rustls::TlsAcceptor;
+
+/// Result of a signing request, a signature or the error of the CA process.
+type SignResult = Result<Vec<u8>, String>;
+
+/// Pending signing requests that are answered by the CA process.
+#[derive(Debug, Default)]
+pub struct Ca {
+    next: AtomicU64,
+    /// Requests that were not sent yet.
+    requests: Mutex<Vec<Sign>>,
+    notify: Notify,
+    /// Signers that wait for a signature by the request Id.
+    pending: Mutex<HashMap<u64, mpsc::Sender<SignResult>>>,
+}
+
+impl Ca {
+    /// Queue a request and wait for the signature.
+    ///
+    /// rustls calls the signer synchronously during the handshake, so the
+    /// task blocks its worker thread until the CA process answered.
+    fn sign(&self, name: &str, scheme: SignatureScheme, message: &[u8]) -> SignResult {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        self.requests.lock().unwrap().push(Sign {
+            id,
+            name: name.to_string(),
+            scheme: scheme.into(),
+            message: message.to_vec(),
+        });
+        self.notify.notify_one();
+
+        let result = task::block_in_place(|| rx.recv_timeout(crate::SIGN_TIMEOUT));
+        self.pending.lock().unwrap().remove(&id);
+        result.unwrap_or_else(|_| Err("signing timed out".to_string()))
+    }
+
+    /// Returns the requests that have to be sent to the CA process.
+    pub async fn requests(&self) -> Vec<Sign> {
+        loop {
+            let requests = std::mem::take(&mut *self.requests.lock().unwrap());
+            if !requests.is_empty() {
+                return requests;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Pass the signature of the CA process to the waiting signer.
+    pub fn signature(&self, signature: Signature) {
+        if let Some(tx) = self.pending.lock().unwrap().remove(&signature.id) {
+            let _ = tx.send(signature.signature);
+        }
+    }
+}
+
+/// Private key that is held by the CA process.
+#[derive(Debug)]
+struct RemoteKey {
+    name: String,
+    algorithm: SignatureAlgorithm,
+    schemes: Vec<SignatureScheme>,
+    ca: Arc<Ca>,
+}
+
+impl SigningKey for RemoteKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        let scheme = self
+            .schemes
+            .iter()
+            .find(|scheme| offered.contains(scheme))?;
+        Some(Box::new(RemoteSigner {
+            name: self.name.clone(),
+            scheme: *scheme,
+            ca: self.ca.clone(),
+        }))
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.algorithm
+    }
+}
+
+#[derive(Debug)]
+struct RemoteSigner {
+    name: String,
+    scheme: SignatureScheme,
+    ca: Arc<Ca>,
+}
+
+impl Signer for RemoteSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, TlsError> {
+        self.ca
+            .sign(&self.name, self.scheme, message)
+            .map_err(TlsError::General)
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+/// TLS configurations of the relay listeners.
+#[derive(Debug, Default)]
+pub struct Keypairs {
+    configs: Mutex<HashMap<SocketAddr, Arc<ServerConfig>>>,
+    ca: Arc<Ca>,
+}
+
+impl Keypairs {
+    pub fn ca(&self) -> &Ca {
+        &self.ca
+    }
+
+    /// Remove the keypairs of the previous configuration.
+    pub fn clear(&self) {
+        self.configs.lock().unwrap().clear();
+    }
+
+    /// Add the certificates of a listener; the private key stays with the
+    /// CA process.
+    pub fn insert(&self, keypair: Keypair) -> Result<(), TlsError> {
+        let certs = keypair
+            .certs
+            .into_iter()
+            .map(CertificateDer::from)
+            .collect();
+        let key = RemoteKey {
+            name: keypair.name,
+            algorithm: keypair.algorithm.into(),
+            schemes: keypair.schemes.into_iter().map(Into::into).collect(),
+            ca: self.ca.clone(),
+        };
+        let certified = CertifiedKey::new(certs, Arc::new(key));
+
+        let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SingleCertAndKey::from(certified)));
+        self.configs
+            .lock()
+            .unwrap()
+            .insert(keypair.listen, Arc::new(config));
+        Ok(())
+    }
+
+    /// Returns the TLS acceptor of a listener.
+    pub fn acceptor(&self, listen: &SocketAddr) -> io::Result<TlsAcceptor> {
+        self.configs
+            .lock()
+            .unwrap()
+            .get(listen)
+            .cloned()
+            .map(TlsAcceptor::from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no keypair"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ca::{self, Keys},
+        config::Listen,
+    };
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+        pki_types::{ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct,
+    };
+    use std::path::Path;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::TlsConnector;
+
+    /// Verifier that accepts the self-signed test certificates but checks
+    /// the handshake signatures.
+    #[derive(Debug)]
+    struct Verifier(WebPkiSupportedAlgorithms);
+
+    impl ServerCertVerifier for Verifier {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, TlsError> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, TlsError> {
+            verify_tls12_signature(message, cert, dss, &self.0)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, TlsError> {
+            verify_tls13_signature(message, cert, dss, &self.0)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.supported_schemes()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_tls_remote_key() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        let provider = default_provider();
+        let client = ClientConfig::builder_with_provider(Arc::new(provider.clone()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(Verifier(
+                provider.signature_verification_algorithms,
+            )))
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client));
+
+        for name in ["rsa", "ecdsa"] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let listen = Listen {
+                addr: listener.local_addr().unwrap(),
+                tls: true,
+                interface: None,
+            };
+            let keypair = ca::keypair(name, &listen, &dir, &dir).unwrap();
+
+            // The relay only gets the certificate.
+            let keypairs = Arc::new(Keypairs::default());
+            keypairs.insert(keypair.public()).unwrap();
+            let mut keys = Keys::default();
+            keys.insert(&keypair).unwrap();
+
+            // Answer the signing requests like the CA process.
+            let ca = {
+                let keypairs = keypairs.clone();
+                tokio::spawn(async move {
+                    loop {
+                        for request in keypairs.ca().requests().await {
+                            keypairs.ca().signature(keys.sign(&request));
+                        }
+                    }
+                })
+            };
+            let server = {
+                let keypairs = keypairs.clone();
+                tokio::spawn(async move {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let acceptor = keypairs.acceptor(&listen.addr).unwrap();
+                    let mut stream = acceptor.accept(stream).await.unwrap();
+                    stream.write_all(b"hello").await.unwrap();
+                    stream.shutdown().await.unwrap();
+                })
+            };
+
+            let stream = TcpStream::connect(listen.addr).await.unwrap();
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let mut stream = connector.connect(server_name, stream).await.unwrap();
+            let mut data = vec![];
+            stream.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data, b"hello");
+
+            server.await.unwrap();
+            ca.abort();
+        }
+    }
+}
@@ -0,0 +1,44 @@
+use std::process::{Command, Output};
+
+/// Run relayd in configtest mode.
+fn configtest(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_relayd"))
+        .arg("-n")
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_configtest() {
+    let output = configtest(&["-f", "examples/relayd.conf"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(output.stderr, b"configuration OK\n");
+
+    // The verbose mode prints the expanded configuration.
+    let output = configtest(&[
+        "-v",
+        "-f",
+        "examples/relayd.conf",
+        "-D",
+        "ext_addr=10.1.2.3",
+    ]);
+    assert!(output.status.success());
+    let printed = String::from_utf8(output.stdout).unwrap();
+    assert!(printed.contains("relay \"wwwtls\" {\n\tlisten on 10.1.2.3 port 443 tls\n"));
+}
+
+#[test]
+fn test_configtest_errors() {
+    let output = configtest(&["-f", "examples/relayd.conf", "-D", "invalid"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("invalid macro definition: invalid"));
+    assert!(!stderr.contains("configuration OK"));
+
+    let output = configtest(&["-f", "examples/missing.conf"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}